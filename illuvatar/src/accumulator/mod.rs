@@ -1,2 +1,361 @@
 // Accumulators collect data worker threads and perform some action when they've
 // acquired enough data, or when they are told to do so.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use fxhash::FxHashMap;
+
+use crate::{assemble::DropReason, bcl::reader::SkippedTile, IlluvatarError};
+
+/// Guard against sample sheet disasters that leave most reads undetermined
+///
+/// `max` of `None` disables the check.
+pub fn check_undetermined_fraction(
+    undetermined: u64,
+    total: u64,
+    max: Option<f64>,
+) -> Result<(), IlluvatarError> {
+    match max {
+        Some(max) if total > 0 && undetermined as f64 / total as f64 > max => {
+            Err(IlluvatarError::TooManyUndetermined {
+                undetermined,
+                total,
+                max,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Width of each read-length bucket, so memory stays bounded for long reads
+pub const READ_LENGTH_BUCKET: usize = 10;
+
+/// Histogram of output read lengths, keyed by destination (sample and read)
+///
+/// Lengths are bucketed by [READ_LENGTH_BUCKET], bucket `i` counting reads
+/// with lengths in `[i * READ_LENGTH_BUCKET, (i + 1) * READ_LENGTH_BUCKET)`.
+/// Reads are recorded after trimming and masking, so this surfaces
+/// over-aggressive adapter trimming.
+#[derive(Debug, Default)]
+pub struct ReadLengthStats {
+    histograms: FxHashMap<String, Vec<u64>>,
+}
+
+impl ReadLengthStats {
+    pub fn record(&mut self, destination: &str, len: usize) {
+        let bucket = len / READ_LENGTH_BUCKET;
+        let histogram = match self.histograms.get_mut(destination) {
+            Some(h) => h,
+            None => self.histograms.entry(destination.to_string()).or_default(),
+        };
+        if histogram.len() <= bucket {
+            histogram.resize(bucket + 1, 0);
+        }
+        histogram[bucket] += 1;
+    }
+
+    pub fn histogram(&self, destination: &str) -> Option<&[u64]> {
+        self.histograms.get(destination).map(|h| h.as_slice())
+    }
+
+    pub fn histograms(&self) -> &FxHashMap<String, Vec<u64>> {
+        &self.histograms
+    }
+
+    /// Render as `{"bucket_width": 10, "destinations": {"<destination>": [...], ...}}`
+    fn write_json(&self, out: &mut String) {
+        let _ = write!(
+            out,
+            "{{\"bucket_width\":{READ_LENGTH_BUCKET},\"destinations\":{{"
+        );
+        // sorted so reports are stable across runs
+        let mut destinations = self.histograms.iter().collect::<Vec<_>>();
+        destinations.sort_by(|a, b| a.0.cmp(b.0));
+        for (i, (destination, histogram)) in destinations.into_iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "\"{}\":{:?}", escape_json(destination), histogram);
+        }
+        out.push_str("}}");
+    }
+
+    /// Add the histograms of `other`, e.g. from another worker
+    pub fn merge(&mut self, other: ReadLengthStats) {
+        for (destination, theirs) in other.histograms {
+            let ours = self.histograms.entry(destination).or_default();
+            if ours.len() < theirs.len() {
+                ours.resize(theirs.len(), 0);
+            }
+            ours.iter_mut().zip(theirs).for_each(|(a, b)| *a += b);
+        }
+    }
+}
+
+/// Reads removed by the quality post-filter, by reason
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DroppedReads {
+    pub low_mean_quality: u64,
+    pub high_n_content: u64,
+}
+
+impl DroppedReads {
+    pub fn record(&mut self, reason: DropReason) {
+        match reason {
+            DropReason::LowMeanQuality => self.low_mean_quality += 1,
+            DropReason::HighNContent => self.high_n_content += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.low_mean_quality + self.high_n_content
+    }
+
+    pub fn merge(&mut self, other: DroppedReads) {
+        self.low_mean_quality += other.low_mean_quality;
+        self.high_n_content += other.high_n_content;
+    }
+}
+
+/// Bases removed from the end of reads because their cycles were dark
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DarkCycleTrims {
+    pub reads: u64,
+    pub bases: u64,
+}
+
+impl DarkCycleTrims {
+    pub fn record(&mut self, trimmed: usize) {
+        if trimmed > 0 {
+            self.reads += 1;
+            self.bases += trimmed as u64;
+        }
+    }
+
+    pub fn merge(&mut self, other: DarkCycleTrims) {
+        self.reads += other.reads;
+        self.bases += other.bases;
+    }
+}
+
+/// Destinations whose name starts with this hold reads that matched no sample
+pub const UNDETERMINED_PREFIX: &str = "Undetermined";
+
+/// Records written per destination
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DemuxCounts {
+    /// Ordered so reports are stable across runs
+    pub per_destination: BTreeMap<String, u64>,
+}
+
+impl DemuxCounts {
+    pub fn total(&self) -> u64 {
+        self.per_destination.values().sum()
+    }
+
+    pub fn undetermined(&self) -> u64 {
+        self.per_destination
+            .iter()
+            .filter(|(d, _)| d.starts_with(UNDETERMINED_PREFIX))
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    fn record(&mut self, destination: &str) {
+        match self.per_destination.get_mut(destination) {
+            Some(n) => *n += 1,
+            None => {
+                self.per_destination.insert(destination.to_string(), 1);
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: DemuxCounts) {
+        for (destination, n) in other.per_destination {
+            *self.per_destination.entry(destination).or_default() += n;
+        }
+    }
+
+    fn write_json(&self, out: &mut String) {
+        let _ = write!(
+            out,
+            "{{\"total\":{},\"undetermined\":{},\"destinations\":{{",
+            self.total(),
+            self.undetermined()
+        );
+        for (i, (destination, n)) in self.per_destination.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "\"{}\":{n}", escape_json(destination));
+        }
+        out.push_str("}}");
+    }
+}
+
+/// Demux counts for the whole run and broken down by lane
+///
+/// Imbalance or a spike in undetermined reads confined to one lane usually
+/// points at a physical problem with that lane rather than the sample sheet.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DemuxStats {
+    run: DemuxCounts,
+    lanes: BTreeMap<u8, DemuxCounts>,
+}
+
+impl DemuxStats {
+    pub fn record(&mut self, lane: u8, destination: &str) {
+        self.run.record(destination);
+        self.lanes.entry(lane).or_default().record(destination);
+    }
+
+    /// Add the counts of `other`
+    ///
+    /// Workers can each accumulate their own stats without contention and
+    /// fold them together once they finish.
+    pub fn merge(&mut self, other: DemuxStats) {
+        self.run.merge(other.run);
+        for (lane, counts) in other.lanes {
+            self.lanes.entry(lane).or_default().merge(counts);
+        }
+    }
+
+    pub fn run(&self) -> &DemuxCounts {
+        &self.run
+    }
+
+    pub fn lane(&self, lane: u8) -> Option<&DemuxCounts> {
+        self.lanes.get(&lane)
+    }
+
+    pub fn lanes(&self) -> &BTreeMap<u8, DemuxCounts> {
+        &self.lanes
+    }
+
+    /// Render as `{"run": {...}, "lanes": {"1": {...}, ...}}`
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"run\":");
+        self.run.write_json(&mut out);
+        out.push_str(",\"lanes\":{");
+        for (i, (lane, counts)) in self.lanes.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            // writing to a String cannot fail
+            let _ = write!(out, "\"{lane}\":");
+            counts.write_json(&mut out);
+        }
+        out.push_str("}}");
+        out
+    }
+}
+
+/// Everything a demux run reports, written to `Reports/demux_stats.json`
+#[derive(Debug)]
+pub struct DemuxReport<'a> {
+    pub stats: &'a DemuxStats,
+    pub read_lengths: &'a ReadLengthStats,
+    /// Tiles left out because they failed to decode
+    pub skipped_tiles: &'a [SkippedTile],
+    /// Clusters dropped by the quality post-filter
+    pub dropped: DroppedReads,
+    /// Bases trimmed from reads ending in dark cycles
+    pub dark_cycles: DarkCycleTrims,
+}
+
+impl DemuxReport<'_> {
+    /// Render as `{"stats": {...}, "read_lengths": {...}, "skipped_tiles": [...],
+    /// "dropped_reads": {...}, "dark_cycle_trims": {...}}`
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"stats\":");
+        out.push_str(&self.stats.to_json());
+        out.push_str(",\"read_lengths\":");
+        self.read_lengths.write_json(&mut out);
+        out.push_str(",\"skipped_tiles\":[");
+        for (i, skipped) in self.skipped_tiles.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"path\":\"{}\",\"tile\":{},\"error\":\"{}\"}}",
+                escape_json(&skipped.path.display().to_string()),
+                skipped.tile_num,
+                escape_json(&skipped.error.to_string())
+            );
+        }
+        out.push(']');
+        let _ = write!(
+            out,
+            ",\"dropped_reads\":{{\"low_mean_quality\":{},\"high_n_content\":{}}}",
+            self.dropped.low_mean_quality, self.dropped.high_n_content
+        );
+        let _ = write!(
+            out,
+            ",\"dark_cycle_trims\":{{\"reads\":{},\"bases\":{}}}",
+            self.dark_cycles.reads, self.dark_cycles.bases
+        );
+        out.push('}');
+        out
+    }
+}
+
+fn escape_json(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_includes_read_length_histograms() {
+        let mut stats = DemuxStats::default();
+        stats.record(1, "S1_R1");
+        let mut read_lengths = ReadLengthStats::default();
+        read_lengths.record("S1_R1", 151);
+        read_lengths.record("S1_R1", 35);
+
+        let report = DemuxReport {
+            stats: &stats,
+            read_lengths: &read_lengths,
+            skipped_tiles: &[],
+            dropped: DroppedReads::default(),
+            dark_cycles: DarkCycleTrims::default(),
+        };
+        assert!(report.to_json().ends_with(
+            ",\"read_lengths\":{\"bucket_width\":10,\"destinations\":\
+             {\"S1_R1\":[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]}},\
+             \"skipped_tiles\":[],\
+             \"dropped_reads\":{\"low_mean_quality\":0,\"high_n_content\":0},\
+             \"dark_cycle_trims\":{\"reads\":0,\"bases\":0}}"
+        ));
+    }
+
+    #[test]
+    fn merged_partial_stats_sum_to_the_whole() {
+        let records = [
+            (1, "S1_R1"),
+            (1, "S2_R1"),
+            (1, "Undetermined_R1"),
+            (2, "S1_R1"),
+            (2, "S1_R1"),
+            (2, "Undetermined_R1"),
+        ];
+        let mut whole = DemuxStats::default();
+        let mut partials = vec![DemuxStats::default(); 3];
+        for (i, (lane, destination)) in records.iter().enumerate() {
+            whole.record(*lane, destination);
+            partials[i % 3].record(*lane, destination);
+        }
+
+        let mut merged = DemuxStats::default();
+        for partial in partials {
+            merged.merge(partial);
+        }
+        assert_eq!(merged, whole);
+        assert_eq!(merged.run().total(), 6);
+        assert_eq!(merged.run().undetermined(), 2);
+        assert_eq!(merged.lane(2).unwrap().per_destination["S1_R1"], 2);
+    }
+}
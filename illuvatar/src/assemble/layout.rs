@@ -0,0 +1,614 @@
+// The read structure of a run, reconciled from everything that describes it:
+// RunInfo.xml's reads, the sample sheet's [Reads] section, and OverrideCycles.
+// Everything downstream of the readers should take cycle positions from here.
+
+use std::{ops::Range, str::FromStr};
+
+use samplesheet::OverrideCycle;
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum LayoutError {
+    #[error("RunInfo declares no reads")]
+    NoReads,
+    #[error("{source_name} describes {got} reads, but RunInfo declares {expected}")]
+    ReadCountMismatch {
+        source_name: &'static str,
+        expected: usize,
+        got: usize,
+    },
+    #[error("Read {read} has {run_info} cycles in RunInfo, but the [Reads] section asks for {requested}")]
+    TooManyCycles {
+        read: usize,
+        run_info: u16,
+        requested: u16,
+    },
+    #[error("Read {read} is {index} in RunInfo but not in the [Reads] section")]
+    IndexMismatch { read: usize, index: &'static str },
+    #[error("R{0} was asked to be reverse-complemented, but the run has no such template read")]
+    NoSuchTemplateRead(u8),
+    #[error("OverrideCycles for read {read} cover {got} cycles, expected {expected}")]
+    OverrideLengthMismatch {
+        read: usize,
+        expected: u16,
+        got: u16,
+    },
+    #[error("Cycles {range} go past the run's {total} cycles")]
+    CycleRangeOutOfBounds { range: CycleRange, total: u16 },
+}
+
+/// One read as RunInfo.xml or the [Reads] section describes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunRead {
+    pub cycles: u16,
+    pub is_index: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    Template,
+    Index,
+    Umi,
+    /// Sequenced but not emitted, N in OverrideCycles
+    Skip,
+}
+
+/// A contiguous run of cycles with the same role
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    /// Zero-based cycle positions within the whole run, not within the read
+    pub cycles: Range<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadSpec {
+    /// One-based read number, as in RunInfo
+    pub number: usize,
+    pub is_index: bool,
+    /// Zero-based cycle positions within the whole run
+    pub cycles: Range<u16>,
+    pub segments: Vec<Segment>,
+    /// Reverse-complement the assembled read before writing it
+    pub reverse_complement: bool,
+}
+
+impl ReadSpec {
+    /// Run cycles of every segment of `kind`, in order
+    pub fn cycles_of(&self, kind: SegmentKind) -> impl Iterator<Item = u16> + '_ {
+        self.segments
+            .iter()
+            .filter(move |s| s.kind == kind)
+            .flat_map(|s| s.cycles.clone())
+    }
+
+    /// Whether each cycle of the read is template, as [assemble_read](super::assemble_read) takes it
+    pub fn template_mask(&self) -> Vec<bool> {
+        let mut mask = vec![false; self.cycles.len()];
+        for cycle in self.cycles_of(SegmentKind::Template) {
+            mask[usize::from(cycle - self.cycles.start)] = true;
+        }
+        mask
+    }
+
+    /// Whether this read produces its own FASTQ
+    pub fn is_written(&self, create_fastq_for_index_reads: bool) -> bool {
+        let has_template = self
+            .segments
+            .iter()
+            .any(|s| s.kind == SegmentKind::Template);
+        has_template || (create_fastq_for_index_reads && self.is_index)
+    }
+}
+
+/// Per-read structure shared by the reader, assembler, demultiplexer, and writer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadLayout {
+    reads: Vec<ReadSpec>,
+}
+
+impl ReadLayout {
+    /// Reconcile the three sources describing a run's reads
+    ///
+    /// RunInfo is authoritative for what was sequenced. The [Reads] section may
+    /// ask for fewer cycles of a read, in which case trailing cycles are skipped.
+    /// OverrideCycles, if given, must cover exactly the cycles each read has left.
+    /// Without OverrideCycles, reads are entirely template or index.
+    pub fn new(
+        run_info: &[RunRead],
+        reads_section: Option<&[RunRead]>,
+        override_cycles: Option<&[Vec<OverrideCycle>]>,
+    ) -> Result<ReadLayout, LayoutError> {
+        if run_info.is_empty() {
+            return Err(LayoutError::NoReads);
+        }
+        if let Some(requested) = reads_section {
+            check_read_count("[Reads] section", run_info.len(), requested.len())?;
+        }
+        if let Some(overrides) = override_cycles {
+            check_read_count("OverrideCycles", run_info.len(), overrides.len())?;
+        }
+
+        let mut reads = Vec::with_capacity(run_info.len());
+        let mut start = 0u16;
+        for (i, read) in run_info.iter().enumerate() {
+            let number = i + 1;
+            let used = match reads_section.map(|r| r[i]) {
+                Some(requested) if requested.cycles > read.cycles => {
+                    return Err(LayoutError::TooManyCycles {
+                        read: number,
+                        run_info: read.cycles,
+                        requested: requested.cycles,
+                    })
+                }
+                Some(requested) if requested.is_index != read.is_index => {
+                    return Err(LayoutError::IndexMismatch {
+                        read: number,
+                        index: if read.is_index {
+                            "an index"
+                        } else {
+                            "not an index"
+                        },
+                    })
+                }
+                Some(requested) => requested.cycles,
+                None => read.cycles,
+            };
+
+            let mut segments = match override_cycles {
+                Some(overrides) => {
+                    let segments = segments_from_overrides(&overrides[i], start);
+                    let got = segments.iter().map(|s| s.cycles.len() as u16).sum::<u16>();
+                    if got != used {
+                        return Err(LayoutError::OverrideLengthMismatch {
+                            read: number,
+                            expected: used,
+                            got,
+                        });
+                    }
+                    segments
+                }
+                None => vec![Segment {
+                    kind: if read.is_index {
+                        SegmentKind::Index
+                    } else {
+                        SegmentKind::Template
+                    },
+                    cycles: start..start + used,
+                }],
+            };
+            if used < read.cycles {
+                segments.push(Segment {
+                    kind: SegmentKind::Skip,
+                    cycles: start + used..start + read.cycles,
+                });
+            }
+            segments.retain(|s| !s.cycles.is_empty());
+
+            reads.push(ReadSpec {
+                number,
+                is_index: read.is_index,
+                cycles: start..start + read.cycles,
+                segments,
+                reverse_complement: false,
+            });
+            start += read.cycles;
+        }
+        Ok(ReadLayout { reads })
+    }
+
+    pub fn reads(&self) -> &[ReadSpec] {
+        &self.reads
+    }
+
+    /// Reads with template cycles, written to their own FASTQs as R1, R2, ...
+    pub fn template_reads(&self) -> impl Iterator<Item = &ReadSpec> {
+        self.reads
+            .iter()
+            .filter(|r| r.cycles_of(SegmentKind::Template).next().is_some())
+    }
+
+    /// Reverse-complement output read `R<template_read>`, e.g. 2 for R2
+    ///
+    /// Template reads are numbered in run order skipping index reads, as in
+    /// FASTQ file names, so on a 4-read run with two index reads R2 is read 4.
+    pub fn set_reverse_complement(&mut self, template_read: u8) -> Result<(), LayoutError> {
+        let read = self
+            .reads
+            .iter_mut()
+            .filter(|r| r.cycles_of(SegmentKind::Template).next().is_some())
+            .nth(usize::from(template_read).wrapping_sub(1))
+            .ok_or(LayoutError::NoSuchTemplateRead(template_read))?;
+        read.reverse_complement = true;
+        Ok(())
+    }
+
+    pub fn total_cycles(&self) -> u16 {
+        self.reads.last().map_or(0, |r| r.cycles.end)
+    }
+
+    /// Run cycles holding index bases, in read order, i.e. i7 then i5
+    pub fn index_cycles(&self) -> Vec<u16> {
+        self.reads
+            .iter()
+            .flat_map(|r| r.cycles_of(SegmentKind::Index))
+            .collect()
+    }
+
+    pub fn umi_cycles(&self) -> Vec<u16> {
+        self.reads
+            .iter()
+            .flat_map(|r| r.cycles_of(SegmentKind::Umi))
+            .collect()
+    }
+}
+
+/// An inclusive range of one-based run cycles to decode, e.g. `1-10`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct CycleRange {
+    start: u16,
+    end: u16,
+}
+
+impl CycleRange {
+    /// Cut run `reads` down to the cycles in range, leaving out reads with none
+    pub fn restrict(&self, reads: &[RunRead]) -> Result<Vec<RunRead>, LayoutError> {
+        let total = reads.iter().map(|r| r.cycles).sum::<u16>();
+        if self.end > total {
+            return Err(LayoutError::CycleRangeOutOfBounds {
+                range: *self,
+                total,
+            });
+        }
+        let mut start = 1;
+        Ok(reads
+            .iter()
+            .filter_map(|read| {
+                let end = start + read.cycles - 1;
+                let kept = end.min(self.end).saturating_sub(start.max(self.start)) + 1;
+                let overlaps = start <= self.end && end >= self.start;
+                start = end + 1;
+                overlaps.then_some(RunRead {
+                    cycles: kept,
+                    is_index: read.is_index,
+                })
+            })
+            .collect())
+    }
+
+    /// Positions of the range in a lane's cycles, which start at cycle 1
+    pub fn indices(&self) -> Range<usize> {
+        usize::from(self.start - 1)..usize::from(self.end)
+    }
+}
+
+impl FromStr for CycleRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{s} is not a cycle range such as 1-10");
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let start = start.trim().parse::<u16>().map_err(|_| invalid())?;
+        let end = end.trim().parse::<u16>().map_err(|_| invalid())?;
+        if start == 0 || end < start {
+            return Err(invalid());
+        }
+        Ok(CycleRange { start, end })
+    }
+}
+
+impl TryFrom<String> for CycleRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for CycleRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// A file describing the read structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadSource {
+    RunInfo,
+    RunParameters,
+    SampleSheet,
+}
+
+impl std::fmt::Display for ReadSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadSource::RunInfo => write!(f, "RunInfo.xml"),
+            ReadSource::RunParameters => write!(f, "RunParameters.xml"),
+            ReadSource::SampleSheet => write!(f, "the sample sheet"),
+        }
+    }
+}
+
+/// The cycle count each source gives a read, `None` where a source lacks the read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleDisagreement {
+    /// One-based read number
+    pub read: usize,
+    pub cycles: Vec<(ReadSource, Option<u16>)>,
+}
+
+impl std::fmt::Display for CycleDisagreement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "read {} has", self.read)?;
+        for (i, (source, cycles)) in self.cycles.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            match cycles {
+                Some(cycles) => write!(f, "{sep} {cycles} cycles in {source}")?,
+                None => write!(f, "{sep} no cycles in {source}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Cross-check planned cycle counts across every source that has them
+///
+/// RunInfo.xml and RunParameters.xml must agree exactly, since both describe
+/// what the instrument was set up to sequence. The sample sheet's [Reads]
+/// section may ask for fewer cycles than were sequenced, as [ReadLayout::new]
+/// allows, but not more. A read missing from any given source is always a
+/// disagreement. Sources passed as `None` are not checked.
+pub fn verify_read_structure_consistency(
+    run_info: &[RunRead],
+    run_parameters: Option<&[u16]>,
+    sample_sheet: Option<&[RunRead]>,
+) -> Vec<CycleDisagreement> {
+    let n_reads = run_info
+        .len()
+        .max(run_parameters.map_or(0, |r| r.len()))
+        .max(sample_sheet.map_or(0, |r| r.len()));
+    (0..n_reads)
+        .filter_map(|i| {
+            let info = run_info.get(i).map(|r| r.cycles);
+            let mut cycles = vec![(ReadSource::RunInfo, info)];
+            let mut consistent = info.is_some();
+            if let Some(params) = run_parameters {
+                let planned = params.get(i).copied();
+                consistent &= planned == info;
+                cycles.push((ReadSource::RunParameters, planned));
+            }
+            if let Some(sheet) = sample_sheet {
+                let requested = sheet.get(i).map(|r| r.cycles);
+                consistent &= matches!((requested, info), (Some(r), Some(i)) if r <= i);
+                cycles.push((ReadSource::SampleSheet, requested));
+            }
+            (!consistent).then_some(CycleDisagreement {
+                read: i + 1,
+                cycles,
+            })
+        })
+        .collect()
+}
+
+fn check_read_count(
+    source_name: &'static str,
+    expected: usize,
+    got: usize,
+) -> Result<(), LayoutError> {
+    if expected == got {
+        Ok(())
+    } else {
+        Err(LayoutError::ReadCountMismatch {
+            source_name,
+            expected,
+            got,
+        })
+    }
+}
+
+fn segments_from_overrides(overrides: &[OverrideCycle], mut start: u16) -> Vec<Segment> {
+    overrides
+        .iter()
+        .map(|segment| {
+            let (kind, n) = match segment {
+                OverrideCycle::Y(n) => (SegmentKind::Template, n),
+                OverrideCycle::I(n) => (SegmentKind::Index, n),
+                OverrideCycle::U(n) => (SegmentKind::Umi, n),
+                OverrideCycle::N(n) => (SegmentKind::Skip, n),
+            };
+            let cycles = start..start + u16::from(*n);
+            start = cycles.end;
+            Segment { kind, cycles }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble::assemble_read, bcl::testutil::bcl_tile};
+
+    const RUN: [RunRead; 2] = [
+        RunRead {
+            cycles: 6,
+            is_index: false,
+        },
+        RunRead {
+            cycles: 4,
+            is_index: true,
+        },
+    ];
+
+    #[test]
+    fn n_segments_are_left_out_of_reads() {
+        let overrides = vec![
+            vec![
+                OverrideCycle::Y(2),
+                OverrideCycle::N(1),
+                OverrideCycle::Y(3),
+            ],
+            vec![OverrideCycle::I(4)],
+        ];
+        let layout = ReadLayout::new(&RUN, None, Some(overrides.as_slice())).unwrap();
+        let r1 = &layout.reads()[0];
+        assert_eq!(
+            r1.cycles_of(SegmentKind::Template).collect::<Vec<u16>>(),
+            vec![0, 1, 3, 4, 5]
+        );
+        assert_eq!(
+            r1.cycles_of(SegmentKind::Skip).collect::<Vec<u16>>(),
+            vec![2]
+        );
+        assert_eq!(layout.index_cycles(), vec![6, 7, 8, 9]);
+
+        // one cluster, the N cycle holding a call that must not be emitted
+        let tiles = b"ACGTAC"
+            .iter()
+            .zip(30..)
+            .map(|(base, qual)| bcl_tile(&[*base], &[qual]))
+            .collect::<Vec<_>>();
+        let (mut seq, mut qual) = (Vec::new(), Vec::new());
+        assemble_read(&tiles, &r1.template_mask(), 0, &mut seq, &mut qual);
+        assert_eq!(seq, b"ACTAC");
+        assert_eq!(qual, vec![30, 31, 33, 34, 35]);
+    }
+
+    #[test]
+    fn overrides_must_cover_every_cycle() {
+        let overrides = vec![
+            vec![
+                OverrideCycle::Y(2),
+                OverrideCycle::N(1),
+                OverrideCycle::Y(2),
+            ],
+            vec![OverrideCycle::I(4)],
+        ];
+        assert_eq!(
+            ReadLayout::new(&RUN, None, Some(overrides.as_slice())),
+            Err(LayoutError::OverrideLengthMismatch {
+                read: 1,
+                expected: 6,
+                got: 5,
+            })
+        );
+    }
+
+    #[test]
+    fn trimmed_reads_skip_trailing_cycles() {
+        let requested = [
+            RunRead {
+                cycles: 4,
+                is_index: false,
+            },
+            RUN[1],
+        ];
+        let layout = ReadLayout::new(&RUN, Some(&requested), None).unwrap();
+        assert_eq!(
+            layout.reads()[0].template_mask(),
+            vec![true, true, true, true, false, false]
+        );
+        assert_eq!(layout.total_cycles(), 10);
+    }
+
+    #[test]
+    fn run_info_and_run_parameters_must_agree() {
+        let disagreements = verify_read_structure_consistency(&RUN, Some(&[6, 8]), None);
+        assert_eq!(
+            disagreements,
+            vec![CycleDisagreement {
+                read: 2,
+                cycles: vec![
+                    (ReadSource::RunInfo, Some(4)),
+                    (ReadSource::RunParameters, Some(8)),
+                ],
+            }]
+        );
+        assert_eq!(
+            disagreements[0].to_string(),
+            "read 2 has 4 cycles in RunInfo.xml, 8 cycles in RunParameters.xml"
+        );
+        assert!(verify_read_structure_consistency(&RUN, Some(&[6, 4]), None).is_empty());
+    }
+
+    #[test]
+    fn every_source_is_reported_when_all_three_disagree() {
+        let sheet = [
+            RunRead {
+                cycles: 7,
+                is_index: false,
+            },
+            RUN[1],
+        ];
+        assert_eq!(
+            verify_read_structure_consistency(&RUN, Some(&[5]), Some(&sheet)),
+            vec![
+                CycleDisagreement {
+                    read: 1,
+                    cycles: vec![
+                        (ReadSource::RunInfo, Some(6)),
+                        (ReadSource::RunParameters, Some(5)),
+                        (ReadSource::SampleSheet, Some(7)),
+                    ],
+                },
+                CycleDisagreement {
+                    read: 2,
+                    cycles: vec![
+                        (ReadSource::RunInfo, Some(4)),
+                        (ReadSource::RunParameters, None),
+                        (ReadSource::SampleSheet, Some(4)),
+                    ],
+                },
+            ]
+        );
+
+        // trimming a read in the sheet is not a disagreement
+        let trimmed = [
+            RunRead {
+                cycles: 4,
+                is_index: false,
+            },
+            RUN[1],
+        ];
+        assert!(verify_read_structure_consistency(&RUN, Some(&[6, 4]), Some(&trimmed)).is_empty());
+    }
+
+    #[test]
+    fn cycle_ranges_cut_reads() {
+        // R1 is cycles 1-6, the i7 7-10
+        let range = "5-8".parse::<CycleRange>().unwrap();
+        assert_eq!(
+            range.restrict(&RUN).unwrap(),
+            vec![
+                RunRead {
+                    cycles: 2,
+                    is_index: false,
+                },
+                RunRead {
+                    cycles: 2,
+                    is_index: true,
+                },
+            ]
+        );
+        assert_eq!(range.indices(), 4..8);
+        assert_eq!(
+            "7-10"
+                .parse::<CycleRange>()
+                .unwrap()
+                .restrict(&RUN)
+                .unwrap(),
+            vec![RUN[1]]
+        );
+        assert_eq!(
+            "1-11".parse::<CycleRange>().unwrap().restrict(&RUN),
+            Err(LayoutError::CycleRangeOutOfBounds {
+                range: "1-11".parse().unwrap(),
+                total: 10,
+            })
+        );
+        for invalid in ["0-4", "4-2", "5", "a-b"] {
+            assert!(invalid.parse::<CycleRange>().is_err());
+        }
+    }
+}
@@ -0,0 +1,752 @@
+// Reading every cycle of a surface in lockstep, one tile at a time.
+//
+// Assembling a read needs one call from every cycle. Rather than reopening
+// each cycle's cbcl per tile, we hold a reader open per cycle and advance them
+// together, so every cbcl is read once, front to back.
+//
+// This relies on an ordering assumption that is a silent corruption risk if it
+// ever breaks: cluster N of a tile is the same cluster in every cycle's cbcl.
+// Clusters are stored in the instrument's fixed order within a tile and are
+// never reordered here, so the only thing to get right is the tile order.
+// Tiles are taken in cbcl header order, which is how the blocks are laid out
+// on disk, and every cycle's header must list the same tiles in the same
+// order. That is checked up front in LockstepReader::new, and again per tile
+// as a backstop.
+
+use std::{fs::File, io::BufReader, path::PathBuf, sync::PoisonError};
+
+use log::warn;
+
+use crate::bcl::{
+    pool::TilePool,
+    reader::{filter_reads, BclReader, CBclReader, ReaderOptions, SharedFilters, SkippedTile},
+    BclError, BclTile, TileData,
+};
+
+/// Holds one open [CBclReader] per cycle and advances them tile by tile
+///
+/// The cbcls must all belong to the same lane and surface, in cycle order, so
+/// that they list the same tiles in the same order.
+///
+/// Memory cost is one decoded tile per cycle, i.e. roughly
+/// `2 * cycles * clusters per tile` bytes for bases and quals, plus each
+/// reader's compressed block buffer of `reader_capacity` bytes. Tiles are
+/// recycled through a [TilePool] as the readers advance.
+pub struct LockstepReader {
+    readers: Vec<CBclReader<BufReader<File>>>,
+    tiles: Vec<BclTile>,
+    pool: TilePool,
+    filters: Option<SharedFilters>,
+    skip_errors: bool,
+}
+
+impl LockstepReader {
+    pub fn new(cbcls: &[PathBuf], reader_capacity: usize) -> Result<Self, BclError> {
+        LockstepReader::with_pool(cbcls, reader_capacity, TilePool::new(cbcls.len()))
+    }
+
+    /// Decode into tiles taken from `pool`, e.g. one shared with the demux workers
+    /// that tiles taken by [next_unit](LockstepReader::next_unit) are returned to
+    pub fn with_pool(
+        cbcls: &[PathBuf],
+        reader_capacity: usize,
+        pool: TilePool,
+    ) -> Result<Self, BclError> {
+        LockstepReader::with_options(cbcls, reader_capacity, pool, &ReaderOptions::default())
+    }
+
+    /// Like [with_pool](LockstepReader::with_pool), applying `options` to the reader of every cycle
+    ///
+    /// With `skip_errors`, a tile that fails to decode in any cycle is skipped
+    /// in every cycle, since a cycle skipping it alone would fall out of step.
+    pub fn with_options(
+        cbcls: &[PathBuf],
+        reader_capacity: usize,
+        pool: TilePool,
+        options: &ReaderOptions,
+    ) -> Result<Self, BclError> {
+        if cbcls.is_empty() {
+            return Err(BclError::NoTiles);
+        }
+        // errors are skipped here, for every cycle at once
+        let reader_options = ReaderOptions {
+            skip_errors: false,
+            ..*options
+        };
+        let mut readers = Vec::with_capacity(cbcls.len());
+        for cbcl in cbcls {
+            let mut reader = CBclReader::with_capacity(cbcl, reader_capacity)?;
+            reader.set_options(&reader_options);
+            reader.read_header_only()?;
+            reader.set_pool(pool.clone());
+            readers.push(reader);
+        }
+        check_tile_order(&readers)?;
+        Ok(LockstepReader {
+            readers,
+            tiles: Vec::with_capacity(cbcls.len()),
+            pool,
+            filters: None,
+            skip_errors: options.skip_errors,
+        })
+    }
+
+    /// Remove clusters that did not pass filter from every cycle
+    ///
+    /// All cycles share `filters`, so each tile's filter is read once rather
+    /// than once per cycle, and dropped again once every cycle has used it.
+    pub fn set_filters(&mut self, filters: SharedFilters) {
+        for reader in self.readers.iter_mut() {
+            reader.share_filters(filters.clone());
+        }
+        self.filters = Some(filters);
+    }
+
+    pub fn n_cycles(&self) -> usize {
+        self.readers.len()
+    }
+
+    /// Drain the tiles skipped so far because they failed to decode in some cycle
+    pub fn take_skipped(&mut self) -> Vec<SkippedTile> {
+        self.readers
+            .iter_mut()
+            .flat_map(|reader| reader.take_skipped())
+            .collect()
+    }
+
+    /// Decode the next tile of every cycle
+    ///
+    /// Returns the tile's metadata and one [BclTile] per cycle, in cycle order,
+    /// ready for [assemble_read](super::assemble_read). The previous tiles are
+    /// recycled, so they must not be held across calls.
+    pub fn next_tile(&mut self) -> Option<Result<(TileData, &[BclTile]), BclError>> {
+        match self.advance()? {
+            Ok(tile_data) => Some(Ok((tile_data, self.tiles.as_slice()))),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// Like [next_tile](LockstepReader::next_tile), but hands over the tiles
+    ///
+    /// Give them back to the reader's pool once done with them.
+    pub fn next_unit(&mut self) -> Option<Result<(TileData, Vec<BclTile>), BclError>> {
+        let result = self.advance()?;
+        Some(result.map(|tile_data| (tile_data, std::mem::take(&mut self.tiles))))
+    }
+
+    /// [next_tile](LockstepReader::next_tile) without borrowing the tiles,
+    /// which are left in `self.tiles`
+    fn advance(&mut self) -> Option<Result<TileData, BclError>> {
+        loop {
+            match self.read_cycles()? {
+                Ok(tile_data) => return Some(Ok(tile_data)),
+                Err((Some(cycle), e)) if self.skip_errors => {
+                    if let Err(e) = self.skip_tile(cycle, e) {
+                        return Some(Err(e));
+                    }
+                }
+                Err((_, e)) => return Some(Err(e)),
+            }
+        }
+    }
+
+    /// Read the next tile of every cycle into `self.tiles`
+    ///
+    /// Errors decoding a cycle's tile come with the index of that cycle.
+    fn read_cycles(&mut self) -> Option<Result<TileData, (Option<usize>, BclError)>> {
+        for tile in self.tiles.drain(..) {
+            self.pool.give(tile);
+        }
+
+        let mut tile_data: Option<TileData> = None;
+        for (cycle, reader) in self.readers.iter_mut().enumerate() {
+            let tile = match reader.read_tile() {
+                Some(Ok(tile)) => tile,
+                Some(Err(e)) => return Some(Err((Some(cycle), e))),
+                // every cycle ran out together
+                None if tile_data.is_none() => return None,
+                // an earlier cycle still had a tile, so this cbcl is short
+                None => return Some(Err((None, BclError::EofError))),
+            };
+            let current = reader
+                .last_tile()
+                .cloned()
+                .expect("reader returned a tile without reading its header");
+            match &tile_data {
+                Some(expected) if expected.tile_num() != current.tile_num() => {
+                    return Some(Err((
+                        None,
+                        BclError::TileOutOfStep {
+                            path: reader.path().to_path_buf(),
+                            expected: expected.tile_num(),
+                            got: current.tile_num(),
+                        },
+                    )))
+                }
+                Some(_) => {}
+                None => tile_data = Some(current),
+            }
+            self.tiles.push(tile);
+        }
+        if let Some(tile_data) = &tile_data {
+            self.evict(tile_data.tile_num());
+        }
+        tile_data.map(Ok)
+    }
+
+    /// Skip the tile that `cycle` failed to decode in every cycle
+    ///
+    /// Earlier cycles already decoded it and later ones have yet to, so their
+    /// tiles are recycled and their blocks discarded respectively.
+    fn skip_tile(&mut self, cycle: usize, error: BclError) -> Result<(), BclError> {
+        for tile in self.tiles.drain(..) {
+            self.pool.give(tile);
+        }
+        let tile_num = self.readers[cycle].skip_failed(error);
+        for reader in self.readers[cycle + 1..].iter_mut() {
+            match reader.discard_tile()? {
+                Some(got) if got == tile_num => {}
+                Some(got) => {
+                    return Err(BclError::TileOutOfStep {
+                        path: reader.path().to_path_buf(),
+                        expected: tile_num,
+                        got,
+                    })
+                }
+                None => return Err(BclError::EofError),
+            }
+        }
+        self.evict(tile_num);
+        Ok(())
+    }
+
+    /// Every cycle is done with `tile_num`, so drop its cached filter
+    fn evict(&self, tile_num: u32) {
+        if let Some(filters) = &self.filters {
+            filters
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .evict(tile_num);
+        }
+    }
+}
+
+/// Every reader's header must list the first reader's tiles, in the same order
+fn check_tile_order(readers: &[CBclReader<BufReader<File>>]) -> Result<(), BclError> {
+    let Some((first, rest)) = readers.split_first() else {
+        return Ok(());
+    };
+    for reader in rest {
+        let mismatch = first
+            .tiles()
+            .iter()
+            .map(|t| t.tile_num())
+            .zip(reader.tiles().iter().map(|t| t.tile_num()))
+            .find(|(expected, got)| expected != got);
+        if let Some((expected, got)) = mismatch {
+            return Err(BclError::TileOutOfStep {
+                path: reader.path().to_path_buf(),
+                expected,
+                got,
+            });
+        }
+        if reader.tiles().len() != first.tiles().len() {
+            return Err(BclError::TileCountMismatch {
+                path: reader.path().to_path_buf(),
+                expected: first.tiles().len(),
+                got: reader.tiles().len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Reads every surface of a lane in turn, each surface in lockstep across cycles
+///
+/// Two-surface flowcells write one cbcl per surface into each cycle directory,
+/// e.g. `C1.1/L001_1.cbcl` and `C1.1/L001_2.cbcl`, each holding that surface's
+/// tiles. Surfaces are read in file name order, and within a surface tiles come
+/// in header order, which is also the order per-lane filters list them in.
+/// Only one surface's readers are open at a time.
+pub struct LaneLockstepReader {
+    /// cbcls of each surface, in cycle order
+    surfaces: Vec<Vec<PathBuf>>,
+    next_surface: usize,
+    current: Option<LockstepReader>,
+    reader_capacity: usize,
+    pool: Option<TilePool>,
+    filters: Option<SharedFilters>,
+    options: ReaderOptions,
+    /// Tiles skipped on surfaces that have been closed
+    skipped: Vec<SkippedTile>,
+}
+
+impl LaneLockstepReader {
+    /// `cycles` holds the cbcls of each cycle, in cycle order
+    ///
+    /// Every cycle must have a cbcl with the same file name for every surface.
+    pub fn new(cycles: &[Vec<PathBuf>], reader_capacity: usize) -> Result<Self, BclError> {
+        let Some(first) = cycles.first() else {
+            return Err(BclError::NoTiles);
+        };
+        let mut names = first
+            .iter()
+            .filter_map(|p| p.file_name().map(|n| (n, p)))
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        let mut surfaces = vec![Vec::with_capacity(cycles.len()); names.len()];
+        for (cycle, cbcls) in cycles.iter().enumerate() {
+            if cbcls.len() != names.len() {
+                return Err(BclError::SurfaceMismatch {
+                    path: first[0].clone(),
+                    cycle: cycle + 1,
+                });
+            }
+            for (surface, (name, reference)) in names.iter().enumerate() {
+                let cbcl = cbcls
+                    .iter()
+                    .find(|p| p.file_name() == Some(name))
+                    .ok_or_else(|| BclError::SurfaceMismatch {
+                        path: reference.to_path_buf(),
+                        cycle: cycle + 1,
+                    })?;
+                surfaces[surface].push(cbcl.clone());
+            }
+        }
+        Ok(LaneLockstepReader {
+            surfaces,
+            next_surface: 0,
+            current: None,
+            reader_capacity,
+            pool: None,
+            filters: None,
+            options: ReaderOptions::default(),
+            skipped: Vec::new(),
+        })
+    }
+
+    pub fn n_surfaces(&self) -> usize {
+        self.surfaces.len()
+    }
+
+    /// Decode every surface into tiles taken from `pool`, see [LockstepReader::with_pool]
+    pub fn set_pool(&mut self, pool: TilePool) {
+        self.pool = Some(pool);
+    }
+
+    /// Filter every surface with the lane's `filters`, see [LockstepReader::set_filters]
+    pub fn set_filters(&mut self, filters: SharedFilters) {
+        self.filters = Some(filters);
+    }
+
+    /// Apply `options` to the reader of every cycle of every surface
+    pub fn set_options(&mut self, options: ReaderOptions) {
+        self.options = options;
+    }
+
+    /// Drain the tiles skipped so far, on every surface, see [LockstepReader::take_skipped]
+    pub fn take_skipped(&mut self) -> Vec<SkippedTile> {
+        let mut skipped = std::mem::take(&mut self.skipped);
+        if let Some(reader) = self.current.as_mut() {
+            skipped.extend(reader.take_skipped());
+        }
+        skipped
+    }
+
+    /// Decode the next tile of every cycle, moving on to the next surface as each runs out
+    ///
+    /// See [LockstepReader::next_tile].
+    pub fn next_tile(&mut self) -> Option<Result<(TileData, &[BclTile]), BclError>> {
+        let result = self.advance()?;
+        let reader = self.current.as_ref().expect("the surface is still open");
+        Some(result.map(|tile_data| (tile_data, reader.tiles.as_slice())))
+    }
+
+    /// Like [next_tile](LaneLockstepReader::next_tile), but hands over the tiles
+    ///
+    /// See [LockstepReader::next_unit].
+    pub fn next_unit(&mut self) -> Option<Result<(TileData, Vec<BclTile>), BclError>> {
+        let result = self.advance()?;
+        let reader = self.current.as_mut().expect("the surface is still open");
+        Some(result.map(|tile_data| (tile_data, std::mem::take(&mut reader.tiles))))
+    }
+
+    fn advance(&mut self) -> Option<Result<TileData, BclError>> {
+        loop {
+            if self.current.is_none() {
+                let cbcls = self.surfaces.get(self.next_surface)?;
+                self.next_surface += 1;
+                let pool = match &self.pool {
+                    Some(pool) => pool.clone(),
+                    None => TilePool::new(cbcls.len()),
+                };
+                match LockstepReader::with_options(cbcls, self.reader_capacity, pool, &self.options)
+                {
+                    Ok(mut reader) => {
+                        if let Some(filters) = &self.filters {
+                            reader.set_filters(filters.clone());
+                        }
+                        self.current = Some(reader)
+                    }
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            let reader = self.current.as_mut().expect("a surface was just opened");
+            match reader.advance() {
+                Some(result) => return Some(result),
+                None => {
+                    self.skipped.extend(reader.take_skipped());
+                    self.current = None
+                }
+            }
+        }
+    }
+}
+
+/// Reads a lane of per-tile bcls, e.g. `C1.1/s_1_1101.bcl.gz`, one tile of every cycle at a time
+///
+/// Older instruments write a bcl per tile per cycle rather than a cbcl per
+/// cycle, so there are no headers to keep in step. Tiles are read in the order
+/// the first cycle's file names sort in, and every cycle must have a bcl for
+/// each of them. Filters are per tile, as those instruments write them.
+pub struct BclLaneReader {
+    /// Each tile's number and bcls, in cycle order
+    tiles: Vec<(u32, Vec<PathBuf>)>,
+    next_tile: usize,
+    reader: BclReader,
+    pool: Option<TilePool>,
+    filters: Option<SharedFilters>,
+    options: ReaderOptions,
+    skipped: Vec<SkippedTile>,
+}
+
+impl BclLaneReader {
+    /// `cycles` holds the bcls of each cycle, in cycle order
+    pub fn new(cycles: &[Vec<PathBuf>]) -> Result<Self, BclError> {
+        let Some(first) = cycles.first().filter(|first| !first.is_empty()) else {
+            return Err(BclError::NoTiles);
+        };
+        let mut tiles = first
+            .iter()
+            .map(|bcl| match BclReader::new(bcl).tile_num() {
+                Some(tile_num) => Ok((tile_num, Vec::with_capacity(cycles.len()))),
+                None => Err(BclError::UnnamedBcl { path: bcl.clone() }),
+            })
+            .collect::<Result<Vec<(u32, Vec<PathBuf>)>, BclError>>()?;
+        for (cycle, bcls) in cycles.iter().enumerate() {
+            if bcls.len() != first.len() {
+                return Err(BclError::TileBclMismatch {
+                    path: first[0].clone(),
+                    cycle: cycle + 1,
+                });
+            }
+            for ((tile_num, tile_bcls), reference) in tiles.iter_mut().zip(first) {
+                let bcl = bcls
+                    .iter()
+                    .find(|bcl| BclReader::new(bcl).tile_num() == Some(*tile_num))
+                    .ok_or_else(|| BclError::TileBclMismatch {
+                        path: reference.clone(),
+                        cycle: cycle + 1,
+                    })?;
+                tile_bcls.push(bcl.clone());
+            }
+        }
+        Ok(BclLaneReader {
+            tiles,
+            next_tile: 0,
+            reader: BclReader::new(&first[0]),
+            pool: None,
+            filters: None,
+            options: ReaderOptions::default(),
+            skipped: Vec::new(),
+        })
+    }
+
+    /// Decode into tiles taken from `pool`, see [LockstepReader::with_pool]
+    pub fn set_pool(&mut self, pool: TilePool) {
+        self.reader.set_pool(pool.clone());
+        self.pool = Some(pool);
+    }
+
+    /// Remove clusters that did not pass filter from every cycle, see [LockstepReader::set_filters]
+    pub fn set_filters(&mut self, filters: SharedFilters) {
+        self.filters = Some(filters);
+    }
+
+    /// Apply `options` to every cycle
+    ///
+    /// bcls have no record of whether non-PF clusters were removed, so they
+    /// count as not PF excluded.
+    pub fn set_options(&mut self, options: ReaderOptions) {
+        self.options = options;
+    }
+
+    /// Drain the tiles skipped so far because they failed to decode in some cycle
+    pub fn take_skipped(&mut self) -> Vec<SkippedTile> {
+        std::mem::take(&mut self.skipped)
+    }
+
+    /// Decode the next tile of every cycle, see [LockstepReader::next_unit]
+    ///
+    /// The tile's metadata gives its clusters before filtering, as a cbcl header would.
+    pub fn next_unit(&mut self) -> Option<Result<(TileData, Vec<BclTile>), BclError>> {
+        loop {
+            let (tile_num, bcls) = self.tiles.get(self.next_tile)?.clone();
+            self.next_tile += 1;
+            if !self
+                .options
+                .pf_filter
+                .keep(&TileData::new(tile_num, 0, false))
+            {
+                continue;
+            }
+            let mut tiles = Vec::with_capacity(bcls.len());
+            let result = self.read_cycles(tile_num, &bcls, &mut tiles);
+            self.evict(tile_num);
+            match result {
+                Ok(tile_data) => return Some(Ok((tile_data, tiles))),
+                Err((path, error)) => {
+                    if let Some(pool) = &self.pool {
+                        tiles.into_iter().for_each(|tile| pool.give(tile));
+                    }
+                    if !self.options.skip_errors {
+                        return Some(Err(error));
+                    }
+                    warn!("skipping tile {tile_num} of {}: {error}", path.display());
+                    self.skipped.push(SkippedTile {
+                        path,
+                        tile_num,
+                        error,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Decode, filter and downsample `tile_num` of every cycle into `tiles`
+    ///
+    /// Errors come with the bcl that failed.
+    fn read_cycles(
+        &mut self,
+        tile_num: u32,
+        bcls: &[PathBuf],
+        tiles: &mut Vec<BclTile>,
+    ) -> Result<TileData, (PathBuf, BclError)> {
+        let mut tile_data = None;
+        for bcl in bcls {
+            self.reader.reset_with(bcl);
+            let mut tile = self.reader.read_tile().map_err(|e| (bcl.clone(), e))?;
+            let cycle_data = TileData::new(tile_num, tile.get_bases().len() as u32, false);
+            if let Some(filters) = &self.filters {
+                let mut filters = filters.lock().unwrap_or_else(PoisonError::into_inner);
+                filters
+                    .filter(tile_num, std::slice::from_ref(&cycle_data))
+                    .and_then(|filter| filter_reads(&mut tile, &cycle_data, filter))
+                    .map_err(|e| (bcl.clone(), e))?;
+            }
+            if let Some(downsample) = self.options.downsample.as_ref() {
+                downsample.apply(&mut tile, tile_num);
+            }
+            tiles.push(tile);
+            tile_data.get_or_insert(cycle_data);
+        }
+        Ok(tile_data.expect("every tile has at least one cycle"))
+    }
+
+    fn evict(&self, tile_num: u32) {
+        if let Some(filters) = &self.filters {
+            filters
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .evict(tile_num);
+        }
+    }
+}
+
+/// Reads a lane of cbcls or of per-tile bcls, whichever its cycles hold
+pub enum LaneReader {
+    Cbcl(LaneLockstepReader),
+    Bcl(BclLaneReader),
+}
+
+impl LaneReader {
+    /// `cycles` holds the base call files of each cycle, as
+    /// [lane_cycle_bcls](crate::bcl::integrity::lane_cycle_bcls) returns them
+    pub fn new(cycles: &[Vec<PathBuf>], reader_capacity: usize) -> Result<Self, BclError> {
+        let is_cbcl = cycles
+            .first()
+            .and_then(|cycle| cycle.first())
+            .map_or(true, |path| path.extension().is_some_and(|e| e == "cbcl"));
+        if is_cbcl {
+            Ok(LaneReader::Cbcl(LaneLockstepReader::new(
+                cycles,
+                reader_capacity,
+            )?))
+        } else {
+            Ok(LaneReader::Bcl(BclLaneReader::new(cycles)?))
+        }
+    }
+
+    pub fn set_pool(&mut self, pool: TilePool) {
+        match self {
+            LaneReader::Cbcl(reader) => reader.set_pool(pool),
+            LaneReader::Bcl(reader) => reader.set_pool(pool),
+        }
+    }
+
+    pub fn set_filters(&mut self, filters: SharedFilters) {
+        match self {
+            LaneReader::Cbcl(reader) => reader.set_filters(filters),
+            LaneReader::Bcl(reader) => reader.set_filters(filters),
+        }
+    }
+
+    pub fn set_options(&mut self, options: ReaderOptions) {
+        match self {
+            LaneReader::Cbcl(reader) => reader.set_options(options),
+            LaneReader::Bcl(reader) => reader.set_options(options),
+        }
+    }
+
+    pub fn take_skipped(&mut self) -> Vec<SkippedTile> {
+        match self {
+            LaneReader::Cbcl(reader) => reader.take_skipped(),
+            LaneReader::Bcl(reader) => reader.take_skipped(),
+        }
+    }
+
+    pub fn next_unit(&mut self) -> Option<Result<(TileData, Vec<BclTile>), BclError>> {
+        match self {
+            LaneReader::Cbcl(reader) => reader.next_unit(),
+            LaneReader::Bcl(reader) => reader.next_unit(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use crate::bcl::{
+        integrity::lane_cycle_bcls,
+        reader::FilterProvider,
+        testutil::{bcl_bytes, filter_bytes, gzip, scratch_dir, CBclBuilder},
+    };
+
+    /// A cycle's cbcl of tiles 1101 to 1103, with 1102 failing to decompress if `corrupt`
+    fn cycle_cbcl(path: &std::path::Path, corrupt: bool) {
+        let mut cbcl = CBclBuilder::new()
+            .tile(1101, b"AC", &[1, 1])
+            .tile(1102, b"GT", &[1, 1])
+            .tile(1103, b"TA", &[1, 1])
+            .build();
+        if corrupt {
+            // claim 1102 decompresses to more bytes than it does
+            cbcl[40..44].copy_from_slice(&64u32.to_le_bytes());
+        }
+        std::fs::write(path, cbcl).unwrap();
+    }
+
+    fn lockstep_cbcls(name: &str) -> Vec<PathBuf> {
+        let dir = scratch_dir(name);
+        let cbcls = vec![dir.join("C1_L001_1.cbcl"), dir.join("C2_L001_1.cbcl")];
+        cycle_cbcl(&cbcls[0], false);
+        cycle_cbcl(&cbcls[1], true);
+        cbcls
+    }
+
+    #[test]
+    fn corrupt_tile_is_an_error_by_default() {
+        let cbcls = lockstep_cbcls("lockstep-strict");
+        let mut reader = LockstepReader::new(&cbcls, 1024).unwrap();
+        assert!(reader.next_tile().unwrap().is_ok());
+        assert!(matches!(
+            reader.next_tile(),
+            Some(Err(BclError::DecompSizeMismatch))
+        ));
+    }
+
+    #[test]
+    fn corrupt_tile_is_skipped_in_every_cycle() {
+        let cbcls = lockstep_cbcls("lockstep-skip");
+        let options = ReaderOptions {
+            skip_errors: true,
+            ..Default::default()
+        };
+        let mut reader =
+            LockstepReader::with_options(&cbcls, 1024, TilePool::new(2), &options).unwrap();
+
+        let mut tile_nums = Vec::new();
+        while let Some(tile) = reader.next_tile() {
+            let (tile_data, tiles) = tile.unwrap();
+            assert_eq!(tiles.len(), 2);
+            tile_nums.push(tile_data.tile_num());
+        }
+        assert_eq!(tile_nums, vec![1101, 1103]);
+
+        let skipped = reader.take_skipped();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].path, cbcls[1]);
+        assert_eq!(skipped[0].tile_num, 1102);
+    }
+
+    #[test]
+    fn bcl_lanes_are_read_a_tile_at_a_time() {
+        let lane_dir = scratch_dir("bcl-lane").join("L001");
+        let cycles = [(b"ACG", [30, 31, 32]), (b"TGA", [20, 21, 22])];
+        for (cycle, (bases, quals)) in cycles.iter().enumerate() {
+            let cycle_dir = lane_dir.join(format!("C{}.1", cycle + 1));
+            std::fs::create_dir_all(&cycle_dir).unwrap();
+            // tile 1102 is gzipped, as MiSeq writes them
+            std::fs::write(
+                cycle_dir.join("s_1_1101.bcl"),
+                bcl_bytes(&bases[..2], &quals[..2]),
+            )
+            .unwrap();
+            std::fs::write(
+                cycle_dir.join("s_1_1102.bcl.gz"),
+                gzip(&bcl_bytes(&bases[2..], &quals[2..])),
+            )
+            .unwrap();
+        }
+        std::fs::write(
+            lane_dir.join("s_1_1101.filter"),
+            filter_bytes(&[false, true]),
+        )
+        .unwrap();
+        std::fs::write(lane_dir.join("s_1_1102.filter"), filter_bytes(&[true])).unwrap();
+
+        let cycles = lane_cycle_bcls(&lane_dir).unwrap();
+        assert_eq!(cycles.len(), 2);
+        let mut reader = LaneReader::new(&cycles, 1024).unwrap();
+        assert!(matches!(reader, LaneReader::Bcl(_)));
+        reader.set_filters(Arc::new(Mutex::new(FilterProvider::detect(&lane_dir, 1))));
+
+        let (tile_data, tiles) = reader.next_unit().unwrap().unwrap();
+        assert_eq!((tile_data.tile_num(), tile_data.num_clusters()), (1101, 2));
+        assert_eq!(tiles.len(), 2);
+        assert_eq!(tiles[0].get_bases(), b"C");
+        assert_eq!(tiles[1].get_bases(), b"G");
+        assert_eq!(tiles[1].get_quals(), &[21]);
+
+        let (tile_data, tiles) = reader.next_unit().unwrap().unwrap();
+        assert_eq!(tile_data.tile_num(), 1102);
+        assert_eq!(tiles[0].get_bases(), b"G");
+        assert_eq!(tiles[1].get_bases(), b"A");
+        assert!(reader.next_unit().is_none());
+    }
+
+    #[test]
+    fn bcl_lanes_need_every_tile_in_every_cycle() {
+        let dir = scratch_dir("bcl-lane-missing");
+        let cycles = vec![
+            vec![dir.join("s_1_1101.bcl"), dir.join("s_1_1102.bcl")],
+            vec![dir.join("s_1_1101.bcl"), dir.join("s_1_1103.bcl")],
+        ];
+        assert!(matches!(
+            BclLaneReader::new(&cycles),
+            Err(BclError::TileBclMismatch { cycle: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,208 @@
+// Assemblers stitch per-cycle BclTiles back together into per-cluster reads.
+// Each BclTile holds one cycle's worth of calls for every cluster in a tile,
+// so building a read means walking the same cluster index across cycles.
+
+pub mod layout;
+pub mod lockstep;
+
+use thiserror::Error;
+
+use crate::bcl::BclTile;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AssembleError {
+    #[error("Cluster {cluster} is out of range for the tile at index cycle {cycle}, which has {n_clusters} clusters")]
+    ClusterOutOfRange {
+        cluster: usize,
+        cycle: usize,
+        n_clusters: usize,
+    },
+}
+
+/// Assemble the read for a single cluster
+///
+/// `tiles` must contain one tile per cycle of the read, in cycle order,
+/// including N cycles. Their cbcls are still read, but calls at positions
+/// masked out, e.g. by [ReadSpec::template_mask](layout::ReadSpec::template_mask),
+/// are dropped from `seq` and `qual`.
+pub fn assemble_read(
+    tiles: &[BclTile],
+    mask: &[bool],
+    cluster: usize,
+    seq: &mut Vec<u8>,
+    qual: &mut Vec<u8>,
+) {
+    seq.clear();
+    qual.clear();
+    tiles
+        .iter()
+        .zip(mask.iter())
+        .filter(|(_, keep)| **keep)
+        .for_each(|(tile, _)| {
+            seq.push(tile.get_bases()[cluster]);
+            qual.push(tile.get_quals()[cluster]);
+        });
+}
+
+/// Reverse-complement an assembled read in place, reversing its qualities to match
+///
+/// Anything other than `ACGT` (i.e. `N`) is left as is.
+pub fn reverse_complement(seq: &mut [u8], qual: &mut [u8]) {
+    seq.reverse();
+    qual.reverse();
+    seq.iter_mut().for_each(|b| {
+        *b = match *b {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            other => other,
+        }
+    });
+}
+
+/// Cycles where more than this fraction of clusters are N are considered dark
+pub const DEFAULT_DARK_CYCLE_THRESHOLD: f64 = 0.95;
+
+/// Whether a cycle failed chemically, leaving (nearly) every cluster a no-call
+pub fn is_dark_cycle(tile: &BclTile, threshold: f64) -> bool {
+    let bases = tile.get_bases();
+    if bases.is_empty() {
+        return false;
+    }
+    let n = bases.iter().filter(|b| **b == b'N').count();
+    n as f64 / bases.len() as f64 > threshold
+}
+
+/// Number of trailing emitted cycles of a read that are dark
+///
+/// Takes the same `tiles` and `mask` as [assemble_read], and is computed once
+/// per tile rather than per read. Truncate each assembled read by this many
+/// bases to drop the dark tail. Masked cycles are ignored, so a dark cycle
+/// followed only by N segments still counts as trailing.
+pub fn trailing_dark_cycles(tiles: &[BclTile], mask: &[bool], threshold: f64) -> usize {
+    tiles
+        .iter()
+        .zip(mask.iter())
+        .filter(|(_, keep)| **keep)
+        .rev()
+        .take_while(|(tile, _)| is_dark_cycle(tile, threshold))
+        .count()
+}
+
+/// Gather the index sequence of a single cluster
+///
+/// `index_tiles` holds one tile per index cycle, in cycle order, e.g. the
+/// tiles at [ReadLayout::index_cycles](layout::ReadLayout::index_cycles).
+/// Tiles of the same tile number should all have the same cluster count, but a
+/// truncated or mismatched cbcl is reported rather than indexed out of bounds.
+pub fn extract_index(
+    index_tiles: &[&BclTile],
+    cluster: usize,
+    index: &mut Vec<u8>,
+) -> Result<(), AssembleError> {
+    index.clear();
+    for (cycle, tile) in index_tiles.iter().enumerate() {
+        match tile.get_bases().get(cluster) {
+            Some(base) => index.push(*base),
+            None => {
+                return Err(AssembleError::ClusterOutOfRange {
+                    cluster,
+                    cycle,
+                    n_clusters: tile.get_bases().len(),
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Why [ReadFilter] rejected a read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    LowMeanQuality,
+    HighNContent,
+}
+
+/// Optional quality filters applied to assembled reads, on top of pass-filter
+///
+/// Both thresholds default to `None`, which keeps every read.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReadFilter {
+    /// Drop reads whose mean Phred score is below this
+    pub min_mean_quality: Option<f64>,
+    /// Drop reads where more than this fraction of calls are N
+    pub max_n_fraction: Option<f64>,
+}
+
+impl ReadFilter {
+    pub fn is_enabled(&self) -> bool {
+        self.min_mean_quality.is_some() || self.max_n_fraction.is_some()
+    }
+
+    /// Check an assembled read, `qual` holding numeric (not ASCII) scores
+    pub fn check(&self, seq: &[u8], qual: &[u8]) -> Result<(), DropReason> {
+        if seq.is_empty() {
+            return Ok(());
+        }
+        if let Some(min) = self.min_mean_quality {
+            let total = qual.iter().map(|q| u64::from(*q)).sum::<u64>();
+            if (total as f64 / qual.len() as f64) < min {
+                return Err(DropReason::LowMeanQuality);
+            }
+        }
+        if let Some(max) = self.max_n_fraction {
+            let n = seq.iter().filter(|b| **b == b'N').count();
+            if n as f64 / seq.len() as f64 > max {
+                return Err(DropReason::HighNContent);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcl::testutil::bcl_tile;
+
+    #[test]
+    fn reverse_complement_reverses_qualities() {
+        // one cluster over four cycles, the third masked out
+        let tiles = [
+            bcl_tile(b"A", &[2]),
+            bcl_tile(b"C", &[12]),
+            bcl_tile(b"G", &[23]),
+            bcl_tile(b"N", &[0]),
+        ];
+        let (mut seq, mut qual) = (Vec::new(), Vec::new());
+        assemble_read(&tiles, &[true, true, false, true], 0, &mut seq, &mut qual);
+        reverse_complement(&mut seq, &mut qual);
+
+        assert_eq!(seq, b"NGT");
+        assert_eq!(qual, vec![0, 12, 2]);
+    }
+
+    #[test]
+    fn dark_cycles_are_counted_from_the_last_emitted_cycle() {
+        let tiles = [
+            bcl_tile(b"AC", &[30, 30]),
+            bcl_tile(b"NN", &[0, 0]),
+            bcl_tile(b"NA", &[0, 30]),
+            bcl_tile(b"GT", &[30, 30]),
+        ];
+        // the last cycle is masked out, so the dark second cycle trails
+        assert_eq!(
+            trailing_dark_cycles(&tiles, &[true, true, false, false], 0.95),
+            1
+        );
+        assert_eq!(
+            trailing_dark_cycles(&tiles, &[true, true, true, false], 0.95),
+            0
+        );
+        assert_eq!(
+            trailing_dark_cycles(&tiles, &[true, true, true, false], 0.4),
+            2
+        );
+    }
+}
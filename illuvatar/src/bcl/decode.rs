@@ -0,0 +1,45 @@
+// Debugging helpers that run the cbcl decoder without a run directory.
+
+use std::{io::Write, path::Path};
+
+use super::{phred_to_ascii, reader::CBclReader, BclError};
+
+/// Decode a single cbcl and write each tile as a FASTQ record
+///
+/// A cbcl holds one cycle, so each record's sequence is that cycle's call for
+/// every cluster in the tile, named `@<file name>:<tile>`. These are not reads,
+/// but they exercise decompression, parsing, and quality encoding end to end.
+/// Returns the number of records written.
+pub fn cbcl_to_fastq<P: AsRef<Path>, W: Write>(cbcl: P, mut output: W) -> Result<usize, BclError> {
+    let name = cbcl
+        .as_ref()
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut reader = CBclReader::new(&cbcl)?;
+    reader.read_header_only()?;
+    let tile_nums = reader
+        .tiles()
+        .iter()
+        .map(|t| t.tile_num())
+        .collect::<Vec<u32>>();
+
+    let mut quals = Vec::new();
+    let mut n_records = 0;
+    for tile_num in tile_nums {
+        let tile = match reader.read_tile() {
+            Some(tile) => tile?,
+            None => break,
+        };
+        quals.clear();
+        quals.extend(tile.get_quals().iter().map(|q| phred_to_ascii(*q)));
+        output.write_all(format!("@{name}:{tile_num}\n").as_bytes())?;
+        output.write_all(tile.get_bases())?;
+        output.write_all(b"\n+\n")?;
+        output.write_all(&quals)?;
+        output.write_all(b"\n")?;
+        n_records += 1;
+    }
+    output.flush()?;
+    Ok(n_records)
+}
@@ -0,0 +1,439 @@
+// Integrity checks over cbcl files, from header-only comparisons to full decode scans.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use rayon::prelude::*;
+
+use super::{
+    reader::{BclReader, CBclReader},
+    BclError, TileData,
+};
+
+/// Where cbcls live relative to the sequencing directory
+pub const BASECALLS_DIR: &str = "Data/Intensities/BaseCalls";
+
+/// Tiles that differ between what a run declares and what a cbcl contains
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TileSetDiff {
+    /// Declared but not present in the cbcl
+    pub missing: Vec<u32>,
+    /// Present in the cbcl but never declared
+    pub extra: Vec<u32>,
+}
+
+impl TileSetDiff {
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+/// Compare declared tile numbers (e.g. from RunInfo.xml's TileSet) against
+/// the tiles listed in a lane directory's cbcl headers, e.g. `BaseCalls/L001`
+///
+/// Each surface has its own cbcl, so the tiles of every surface of the lowest
+/// cycle are combined first, see [lane_tile_numbers]. Only headers are read.
+/// Declared tiles must be bare tile numbers, i.e. `1101` rather than RunInfo's
+/// `1_1101` lane-prefixed form.
+pub fn compare_tile_sets<P: AsRef<Path>>(
+    declared: &[u32],
+    lane_dir: P,
+) -> Result<TileSetDiff, BclError> {
+    let present = lane_tile_numbers(lane_dir)?;
+    Ok(diff_tile_sets(declared, &present))
+}
+
+/// Every tile listed by the headers of one cycle's cbcls, in the order given
+///
+/// Pass a cycle's cbcls sorted by surface, as [lane_cycle_bcls] returns them,
+/// to get a lane's tiles in the order per-lane filters list them.
+pub fn cycle_tiles<P: AsRef<Path>>(cbcls: &[P]) -> Result<Vec<TileData>, BclError> {
+    let mut tiles = Vec::new();
+    for cbcl in cbcls {
+        let mut reader = CBclReader::new(cbcl)?;
+        reader.read_header_only()?;
+        tiles.extend_from_slice(reader.tiles());
+    }
+    Ok(tiles)
+}
+
+/// Tile numbers listed in a single cbcl header
+pub fn header_tile_numbers<P: AsRef<Path>>(cbcl: P) -> Result<Vec<u32>, BclError> {
+    let mut reader = CBclReader::new(cbcl)?;
+    reader.read_header_only()?;
+    Ok(reader.tiles().iter().map(|t| t.tile_num()).collect())
+}
+
+/// Every tile number present in a lane directory, e.g. `BaseCalls/L001`, sorted
+///
+/// Only headers are read, and only from the lowest cycle, since every cycle
+/// holds the same tiles. Each surface has its own cbcl, so all of that cycle's
+/// cbcls are read, or all of its per-tile bcls on older instruments.
+pub fn lane_tile_numbers<P: AsRef<Path>>(lane_dir: P) -> Result<Vec<u32>, BclError> {
+    Ok(lane_tile_clusters(lane_dir.as_ref())?.into_keys().collect())
+}
+
+/// Total clusters declared by the headers of a lane directory's lowest cycle
+///
+/// Like [lane_tile_numbers], only the first cycle's headers are read. Tiles that
+/// are not pf-excluded still count their non-PF clusters.
+pub fn lane_cluster_count<P: AsRef<Path>>(lane_dir: P) -> Result<u64, BclError> {
+    Ok(lane_tile_clusters(lane_dir.as_ref())?.into_values().sum())
+}
+
+/// A lane's cbcl headers checked against the tiles RunInfo declares for it
+///
+/// RunInfo has no cluster counts, so `expected` is what the declared tiles hold
+/// and `present` is what every listed tile holds. They differ when the headers
+/// list undeclared tiles.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LaneClusterReport {
+    pub tiles: TileSetDiff,
+    /// Declared tiles listed with no clusters, e.g. a cbcl truncated on copy
+    pub empty: Vec<u32>,
+    /// Clusters in the declared tiles
+    pub expected: u64,
+    /// Clusters in every tile the headers list
+    pub present: u64,
+}
+
+impl LaneClusterReport {
+    pub fn is_consistent(&self) -> bool {
+        self.tiles.is_consistent() && self.empty.is_empty()
+    }
+}
+
+/// Confirm every declared tile of a lane directory has cluster data
+///
+/// Declared tiles are bare tile numbers, as for [compare_tile_sets]. Only the
+/// lowest cycle's headers are read, like [lane_cluster_count].
+pub fn lane_cluster_report<P: AsRef<Path>>(
+    declared: &[u32],
+    lane_dir: P,
+) -> Result<LaneClusterReport, BclError> {
+    let clusters = lane_tile_clusters(lane_dir.as_ref())?;
+    let present = clusters.keys().copied().collect::<Vec<u32>>();
+    let mut report = LaneClusterReport {
+        tiles: diff_tile_sets(declared, &present),
+        present: clusters.values().sum(),
+        ..Default::default()
+    };
+    for tile in declared.iter() {
+        match clusters.get(tile) {
+            Some(0) => report.empty.push(*tile),
+            Some(n) => report.expected += n,
+            None => {}
+        }
+    }
+    report.empty.sort_unstable();
+    report.empty.dedup();
+    Ok(report)
+}
+
+/// Clusters per tile, summed over the surfaces of a lane directory's lowest cycle
+fn lane_tile_clusters(lane_dir: &Path) -> Result<BTreeMap<u32, u64>, BclError> {
+    let mut clusters = BTreeMap::new();
+    let Some(cycle_dir) = first_cycle_dir(lane_dir)? else {
+        return Ok(clusters);
+    };
+    for entry in fs::read_dir(cycle_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "cbcl") {
+            let mut reader = CBclReader::new(path)?;
+            reader.read_header_only()?;
+            for tile in reader.tiles().iter() {
+                *clusters.entry(tile.tile_num()).or_default() += u64::from(tile.num_clusters());
+            }
+        } else if is_tile_bcl(&path) {
+            let reader = BclReader::new(&path);
+            let tile_num = reader
+                .tile_num()
+                .ok_or_else(|| BclError::UnnamedBcl { path: path.clone() })?;
+            *clusters.entry(tile_num).or_default() += u64::from(reader.num_clusters()?);
+        }
+    }
+    Ok(clusters)
+}
+
+fn first_cycle_dir(lane_dir: &Path) -> Result<Option<PathBuf>, io::Error> {
+    Ok(dir_entries(lane_dir.to_path_buf(), "C")?
+        .into_iter()
+        .filter_map(|dir| cycle_number(&dir).map(|n| (n, dir)))
+        .min_by_key(|(n, _)| *n)
+        .map(|(_, dir)| dir))
+}
+
+/// `C12.1` is cycle 12
+fn cycle_number(dir: &Path) -> Option<u16> {
+    let name = dir.file_name()?.to_str()?;
+    name.strip_prefix('C')?.split('.').next()?.parse().ok()
+}
+
+pub fn diff_tile_sets(declared: &[u32], present: &[u32]) -> TileSetDiff {
+    let mut diff = TileSetDiff {
+        missing: declared
+            .iter()
+            .filter(|t| !present.contains(t))
+            .copied()
+            .collect(),
+        extra: present
+            .iter()
+            .filter(|t| !declared.contains(t))
+            .copied()
+            .collect(),
+    };
+    diff.missing.sort_unstable();
+    diff.extra.sort_unstable();
+    diff
+}
+
+/// A tile, or a whole file when `tile_num` is `None`, that failed to decode
+#[derive(Debug)]
+pub struct ScanFailure {
+    pub path: PathBuf,
+    pub tile_num: Option<u32>,
+    pub error: BclError,
+}
+
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    pub n_files: usize,
+    /// Tiles that decoded successfully
+    pub n_tiles: usize,
+    pub failures: Vec<ScanFailure>,
+}
+
+impl ScanReport {
+    pub fn is_clean(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Every cbcl under `<seq_dir>/Data/Intensities/BaseCalls/L*/C*`, sorted by path
+pub fn find_cbcls<P: AsRef<Path>>(seq_dir: P) -> Result<Vec<PathBuf>, io::Error> {
+    let mut cbcls = Vec::new();
+    for lane in dir_entries(seq_dir.as_ref().join(BASECALLS_DIR), "L")? {
+        for cycle in dir_entries(lane, "C")? {
+            for entry in fs::read_dir(cycle)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|e| e == "cbcl") {
+                    cbcls.push(path);
+                }
+            }
+        }
+    }
+    cbcls.sort_unstable();
+    Ok(cbcls)
+}
+
+/// Every `L<lane>` directory under `<seq_dir>/Data/Intensities/BaseCalls`, by lane
+pub fn lane_dirs<P: AsRef<Path>>(seq_dir: P) -> Result<BTreeMap<u8, PathBuf>, io::Error> {
+    Ok(dir_entries(seq_dir.as_ref().join(BASECALLS_DIR), "L")?
+        .into_iter()
+        .filter_map(|dir| {
+            let lane = dir.file_name()?.to_str()?.strip_prefix('L')?.parse().ok()?;
+            Some((lane, dir))
+        })
+        .collect())
+}
+
+/// Every per-tile bcl under `<seq_dir>/Data/Intensities/BaseCalls/L*/C*`, sorted by path
+///
+/// Older instruments, e.g. HiSeq and MiSeq, write an `s_<lane>_<tile>.bcl` or
+/// `.bcl.gz` per tile per cycle instead of a cbcl per cycle.
+pub fn find_bcls<P: AsRef<Path>>(seq_dir: P) -> Result<Vec<PathBuf>, io::Error> {
+    let mut bcls = Vec::new();
+    for lane in dir_entries(seq_dir.as_ref().join(BASECALLS_DIR), "L")? {
+        for cycle in dir_entries(lane, "C")? {
+            for entry in fs::read_dir(cycle)? {
+                let path = entry?.path();
+                if is_tile_bcl(&path) {
+                    bcls.push(path);
+                }
+            }
+        }
+    }
+    bcls.sort_unstable();
+    Ok(bcls)
+}
+
+/// [lane_dirs], checked against the `lane_count` RunInfo.xml declares
+///
+/// A lane directory missing, e.g. after a partial transfer, or one beyond the
+/// flowcell's lanes is an error, rather than a lane silently left out.
+pub fn declared_lane_dirs<P: AsRef<Path>>(
+    seq_dir: P,
+    lane_count: u8,
+) -> Result<BTreeMap<u8, PathBuf>, BclError> {
+    let lanes = lane_dirs(seq_dir)?;
+    let found = (1..=lane_count).filter(|l| lanes.contains_key(l)).count();
+    if found < usize::from(lane_count) {
+        return Err(BclError::MissingLanes {
+            declared: lane_count,
+            found,
+        });
+    }
+    if lanes.len() > found {
+        return Err(BclError::UndeclaredLanes {
+            declared: lane_count,
+            lanes: lanes.into_keys().collect(),
+        });
+    }
+    Ok(lanes)
+}
+
+/// The base call files of each cycle of a lane directory, in cycle order
+///
+/// This is the layout [LaneReader](crate::assemble::lockstep::LaneReader)
+/// reads. A cycle holds either cbcls, sorted by file name, i.e. by surface,
+/// or per-tile bcls, sorted by file name. Should a cycle have both, its cbcls
+/// win, since they are what current instruments write.
+pub fn lane_cycle_bcls<P: AsRef<Path>>(lane_dir: P) -> Result<Vec<Vec<PathBuf>>, io::Error> {
+    let mut cycles = dir_entries(lane_dir.as_ref().to_path_buf(), "C")?
+        .into_iter()
+        .filter_map(|dir| cycle_number(&dir).map(|n| (n, dir)))
+        .collect::<Vec<(u16, PathBuf)>>();
+    cycles.sort_unstable();
+    cycles
+        .into_iter()
+        .map(|(_, dir)| {
+            let (mut cbcls, mut bcls) = (Vec::new(), Vec::new());
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|e| e == "cbcl") {
+                    cbcls.push(path);
+                } else if is_tile_bcl(&path) {
+                    bcls.push(path);
+                }
+            }
+            let mut files = if cbcls.is_empty() { bcls } else { cbcls };
+            files.sort_unstable();
+            Ok(files)
+        })
+        .collect()
+}
+
+/// Whether `path` is a per-tile `.bcl` or `.bcl.gz`
+pub fn is_tile_bcl(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.ends_with(".bcl") || n.ends_with(".bcl.gz"))
+}
+
+fn dir_entries(dir: PathBuf, prefix: &str) -> Result<Vec<PathBuf>, io::Error> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let matches = path
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().starts_with(prefix));
+        if matches && path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    Ok(dirs)
+}
+
+/// Fully decode every tile of every cbcl, discarding the base calls
+///
+/// Files are scanned in parallel on the global rayon pool. A bad tile does not
+/// stop the scan of its file, but a bad header does.
+pub fn scan_cbcls(cbcls: &[PathBuf]) -> ScanReport {
+    cbcls
+        .par_iter()
+        .map(|path| scan_cbcl(path))
+        .reduce(ScanReport::default, |mut a, b| {
+            a.n_files += b.n_files;
+            a.n_tiles += b.n_tiles;
+            a.failures.extend(b.failures);
+            a
+        })
+}
+
+fn scan_cbcl(path: &Path) -> ScanReport {
+    let mut report = ScanReport {
+        n_files: 1,
+        ..Default::default()
+    };
+    let header = CBclReader::new(path).and_then(|mut reader| {
+        reader.read_header_only()?;
+        Ok(reader)
+    });
+    let mut reader = match header {
+        Ok(reader) => reader,
+        Err(error) => {
+            report.failures.push(ScanFailure {
+                path: path.to_path_buf(),
+                tile_num: None,
+                error,
+            });
+            return report;
+        }
+    };
+    reader.set_skip_errors(true);
+    while let Some(tile) = reader.read_tile() {
+        match tile {
+            Ok(_) => report.n_tiles += 1,
+            // only errors outside of a tile, e.g. reading past a short block, get here
+            Err(error) => {
+                report.failures.push(ScanFailure {
+                    path: path.to_path_buf(),
+                    tile_num: None,
+                    error,
+                });
+                break;
+            }
+        }
+    }
+    report.failures.extend(
+        reader
+            .take_skipped()
+            .into_iter()
+            .map(|skipped| ScanFailure {
+                path: skipped.path,
+                tile_num: Some(skipped.tile_num),
+                error: skipped.error,
+            }),
+    );
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcl::testutil::scratch_dir;
+
+    fn run_dir(name: &str, lanes: &[u8]) -> PathBuf {
+        let dir = scratch_dir(name);
+        for lane in lanes {
+            fs::create_dir_all(dir.join(BASECALLS_DIR).join(format!("L{lane:03}"))).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn lanes_are_checked_against_the_declared_count() {
+        let dir = run_dir("declared-lanes", &[1, 2, 3, 4]);
+        assert_eq!(declared_lane_dirs(&dir, 4).unwrap().len(), 4);
+
+        let err = declared_lane_dirs(&dir, 8).unwrap_err();
+        assert!(matches!(
+            err,
+            BclError::MissingLanes {
+                declared: 8,
+                found: 4
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "RunInfo declares 8 lanes but only 4 directories found"
+        );
+        assert!(matches!(
+            declared_lane_dirs(&dir, 2),
+            Err(BclError::UndeclaredLanes { declared: 2, .. })
+        ));
+    }
+}
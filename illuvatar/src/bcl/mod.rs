@@ -1,12 +1,42 @@
+pub mod decode;
+pub mod integrity;
 pub mod parser;
+pub mod pool;
+pub mod prefetch;
 pub mod reader;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testutil;
 
 use std::path::{Path, PathBuf};
 
+use clap::ValueEnum;
 use libdeflater::DecompressionError;
 use parser::cbcl::ILLUMINA_MIN_QUAL;
+use serde::Deserialize;
 use thiserror::Error;
 
+/// Bits per base call the cbcl decoder understands
+pub const CBCL_BITS_PER_BC: u8 = 2;
+/// Bits per quality score the cbcl decoder understands
+pub const CBCL_BITS_PER_QS: u8 = 2;
+
+/// Offset added to numeric Phred scores to encode them as FASTQ ASCII
+pub const PHRED_OFFSET: u8 = 33;
+/// Highest score that still encodes to a printable character (`~`)
+pub const MAX_PHRED: u8 = b'~' - PHRED_OFFSET;
+
+/// Encode a numeric Phred score as Phred+33 ASCII, clamping to [MAX_PHRED]
+pub fn phred_to_ascii(q: u8) -> u8 {
+    q.min(MAX_PHRED) + PHRED_OFFSET
+}
+
+/// Decode a Phred+33 ASCII character to a numeric Phred score
+///
+/// Characters below `!` are not valid Phred+33 and decode to 0.
+pub fn ascii_to_phred(c: u8) -> u8 {
+    c.saturating_sub(PHRED_OFFSET)
+}
+
 #[derive(Error, Debug)]
 pub enum BclError {
     #[error("Error parsing BCL")]
@@ -24,6 +54,62 @@ pub enum BclError {
     DecompSizeMismatch,
     #[error("Compressed block size {got} did not match expected size {expected}")]
     CompSizeMismatch { expected: u32, got: usize },
+    #[error("CBCL header declares no tiles. File is most likely truncated or corrupt.")]
+    NoTiles,
+    #[error("Filter for tile {tile_num} has {got} clusters, expected {expected}")]
+    FilterSizeMismatch {
+        tile_num: u32,
+        expected: usize,
+        got: usize,
+    },
+    #[error("Filter header of {path} declares {expected} clusters, but the file holds {got}")]
+    FilterClusterCount {
+        path: PathBuf,
+        expected: u32,
+        got: usize,
+    },
+    #[error("BCL header of {path} declares {expected} clusters, but the file holds {got}")]
+    BclClusterCount {
+        path: PathBuf,
+        expected: u32,
+        got: usize,
+    },
+    #[error("{path} is out of step with the other cycles: expected tile {expected}, got {got}")]
+    TileOutOfStep {
+        path: PathBuf,
+        expected: u32,
+        got: u32,
+    },
+    #[error("{path} uses {bits_per_bc} bits per base call and {bits_per_qs} per quality, only 2 and 2 are supported")]
+    UnsupportedBitWidth {
+        path: PathBuf,
+        bits_per_bc: u8,
+        bits_per_qs: u8,
+    },
+    #[error("{path} has {got} tiles, but the other cycles have {expected}")]
+    TileCountMismatch {
+        path: PathBuf,
+        expected: usize,
+        got: usize,
+    },
+    #[error("Cycle {cycle} has no cbcl for the surface of {path}, or has extra surfaces")]
+    SurfaceMismatch { path: PathBuf, cycle: usize },
+    #[error("Cycle {cycle} has no bcl for the tile of {path}, or has extra tiles")]
+    TileBclMismatch { path: PathBuf, cycle: usize },
+    #[error("{path} is not named after its tile, expected s_<lane>_<tile>.bcl")]
+    UnnamedBcl { path: PathBuf },
+    #[error("RunInfo declares {declared} lanes but only {found} directories found")]
+    MissingLanes { declared: u8, found: usize },
+    #[error("RunInfo declares {declared} lanes but lane directories {lanes:?} were found")]
+    UndeclaredLanes { declared: u8, lanes: Vec<u8> },
+    #[error("No filter found for tile {tile_num} in {path}")]
+    MissingFilter { tile_num: u32, path: PathBuf },
+    #[error("CBCL header of {path} is truncated: expected {expected} bytes, got {got}")]
+    TruncatedHeader {
+        path: PathBuf,
+        expected: u32,
+        got: usize,
+    },
 }
 
 impl<'a> From<nom::Err<nom::error::Error<&[u8]>>> for BclError {
@@ -58,10 +144,26 @@ impl BclTile {
             quals: vec![0; cap],
         }
     }
+
+    /// Resize the tile to hold `len` calls, keeping the existing allocations
+    pub fn reset(&mut self, len: usize) {
+        self.bases.clear();
+        self.quals.clear();
+        self.bases.resize(len, 0);
+        self.quals.resize(len, 0);
+    }
+
+    /// Keep only the first `len` calls
+    pub fn truncate(&mut self, len: usize) {
+        self.bases.truncate(len);
+        self.quals.truncate(len);
+    }
+
     pub fn get_bases(&self) -> &[u8] {
         &self.bases
     }
 
+    /// Numeric Phred scores, NOT ASCII encoded
     pub fn get_quals(&self) -> &[u8] {
         &self.quals
     }
@@ -86,23 +188,161 @@ pub struct CBclHeader {
     n_tiles: u32,
 }
 
-#[derive(Debug)]
+impl CBclHeader {
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn bits_per_bc(&self) -> u8 {
+        self.bits_per_bc
+    }
+
+    pub fn bits_per_qs(&self) -> u8 {
+        self.bits_per_qs
+    }
+
+    pub fn n_bins(&self) -> u32 {
+        self.n_bins
+    }
+
+    pub fn n_tiles(&self) -> u32 {
+        self.n_tiles
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct TileData {
     tile_num: u32,
     num_clusters: u32,
     block_size_un: u32,
     block_size_comp: u32,
     pf_excluded: bool,
-    filter: Option<&'static [u8]>,
 }
 
 impl TileData {
-    pub fn has_filter(&self) -> bool {
-        self.filter.is_some()
+    /// Metadata for a tile that was not read from a cbcl header, e.g. synthetic test data
+    ///
+    /// Block sizes are zero, so it cannot be used to drive a reader.
+    pub fn new(tile_num: u32, num_clusters: u32, pf_excluded: bool) -> Self {
+        TileData {
+            tile_num,
+            num_clusters,
+            block_size_un: 0,
+            block_size_comp: 0,
+            pf_excluded,
+        }
+    }
+
+    /// Whether non-PF clusters were already removed from this tile by the instrument
+    pub fn is_pf_excluded(&self) -> bool {
+        self.pf_excluded
     }
 
-    pub fn get_or_read_filter(&self) -> Option<&'static [u8]> {
-        todo!()
+    pub fn tile_num(&self) -> u32 {
+        self.tile_num
+    }
+
+    pub fn num_clusters(&self) -> u32 {
+        self.num_clusters
+    }
+}
+
+/// The unit of work handed from readers to the resolver
+///
+/// A unit spans a single tile across every cycle of the run: `tiles` holds one
+/// [BclTile] per cycle, in cycle order, each with one call per cluster, and
+/// `tile_data` describes the tile they came from. Cluster N of every cycle is
+/// the same cluster, so reads are assembled by walking cluster N across `tiles`
+/// at the cycles [ReadLayout](crate::assemble::layout::ReadLayout) gives each read.
+#[derive(Debug)]
+pub struct DemuxUnit {
+    pub lane: u8,
+    pub tile_data: TileData,
+    pub tiles: Vec<BclTile>,
+}
+
+impl DemuxUnit {
+    pub fn new(lane: u8, tile_data: TileData, tiles: Vec<BclTile>) -> Self {
+        DemuxUnit {
+            lane,
+            tile_data,
+            tiles,
+        }
+    }
+
+    /// Clusters in the unit after filtering, taken from its first cycle
+    pub fn n_clusters(&self) -> usize {
+        self.tiles.first().map_or(0, |t| t.get_bases().len())
+    }
+}
+
+/// Select which tiles a reader decodes based on their `pf_excluded` flag
+///
+/// Tiles that are not selected are skipped without being decompressed.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PfTileFilter {
+    #[default]
+    All,
+    /// Only tiles whose non-PF clusters have already been excluded
+    PfExcluded,
+    /// Only tiles that still contain non-PF clusters
+    NotPfExcluded,
+}
+
+impl PfTileFilter {
+    pub fn keep(&self, tile: &TileData) -> bool {
+        match self {
+            PfTileFilter::All => true,
+            PfTileFilter::PfExcluded => tile.is_pf_excluded(),
+            PfTileFilter::NotPfExcluded => !tile.is_pf_excluded(),
+        }
+    }
+}
+
+/// Keeps a reproducible fraction of each tile's clusters, for quick QC runs
+///
+/// Whether a cluster is kept depends only on the seed, its tile and its index
+/// after pass-filtering, so every cycle of a tile keeps the same clusters and
+/// reads still assemble. Downsampled output is meant for QC, e.g. checking
+/// barcode balance, and is not suitable for analysis.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Downsample {
+    threshold: u64,
+    seed: u64,
+}
+
+impl Downsample {
+    /// Keep roughly `rate` (0 to 1) of the clusters
+    pub fn new(rate: f64, seed: u64) -> Self {
+        Downsample {
+            threshold: (rate.clamp(0.0, 1.0) * u64::MAX as f64) as u64,
+            seed,
+        }
+    }
+
+    pub fn keep(&self, tile_num: u32, cluster: usize) -> bool {
+        // splitmix64 finalizer, enough to spread consecutive cluster indices
+        let mut x = self.seed ^ (u64::from(tile_num) << 32 | cluster as u64);
+        x = x.wrapping_add(0x9e3779b97f4a7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        x ^= x >> 31;
+        x <= self.threshold
+    }
+
+    /// Drop the clusters of `tile` that are not sampled
+    pub fn apply(&self, tile: &mut BclTile, tile_num: u32) {
+        let mut cluster = 0;
+        tile.bases.retain(|_| {
+            cluster += 1;
+            self.keep(tile_num, cluster - 1)
+        });
+        let mut cluster = 0;
+        tile.quals.retain(|_| {
+            cluster += 1;
+            self.keep(tile_num, cluster - 1)
+        });
     }
 }
 
@@ -121,3 +361,26 @@ pub fn into_bin_lookup(raw_bins: Option<Vec<(u32, u32)>>) -> Vec<u8> {
         Vec::with_capacity(0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phred_scores_encode_as_phred33() {
+        assert_eq!(phred_to_ascii(ILLUMINA_MIN_QUAL), b'#');
+        assert_eq!(phred_to_ascii(30), b'?');
+        assert_eq!(phred_to_ascii(0), b'!');
+        assert_eq!(phred_to_ascii(40), b'I');
+    }
+
+    #[test]
+    fn phred_conversion_clamps_and_round_trips() {
+        assert_eq!(phred_to_ascii(MAX_PHRED), b'~');
+        assert_eq!(phred_to_ascii(u8::MAX), b'~');
+        assert_eq!(ascii_to_phred(b' '), 0);
+        for q in 0..=MAX_PHRED {
+            assert_eq!(ascii_to_phred(phred_to_ascii(q)), q);
+        }
+    }
+}
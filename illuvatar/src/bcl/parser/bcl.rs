@@ -0,0 +1,18 @@
+use nom::{combinator::all_consuming, multi::fill, number::complete::le_u32, IResult};
+
+use super::cbcl::{bcl_base, bcl_qual};
+use crate::bcl::BclTile;
+
+/// Number of clusters
+/// Plain BCLs have no other header, one byte per cluster follows.
+pub(crate) fn bcl_num_clusters(input: &[u8]) -> IResult<&[u8], u32> {
+    le_u32(input)
+}
+
+/// One byte per cluster, bits 0-1 are the base and bits 2-7 the quality
+/// A zero byte is a no-call. This is the same encoding cbcls use after
+/// nibble expansion, just without quality binning.
+pub(crate) fn parse_bcl_calls<'a>(input: &'a [u8], tile: &mut BclTile) -> IResult<&'a [u8], ()> {
+    fill(bcl_base, tile.bases_mut())(input)?;
+    all_consuming(fill(bcl_qual, tile.quals_mut()))(input)
+}
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 
 use nom::{
-    combinator::{all_consuming, map, opt},
+    combinator::{all_consuming, cond, map, opt},
     multi::{count, fill},
     number::complete::{le_u16, le_u32, le_u8, u8},
     sequence::{pair, preceded, tuple},
@@ -53,23 +53,23 @@ pub(crate) fn parse_base_calls<'a>(
     fill(bcl_base, tile.bases_mut())(input)?;
     // TODO convert this into a nom parser
     if bins.len() > 0 {
-        Ok((
-            &input[tile.quals.len()..],
-            tile.quals = input[0..tile.quals.len()]
-                .iter()
-                .map(|x| bins[usize::from(x >> 2)])
-                .collect::<Vec<u8>>(),
-        ))
+        let n_quals = tile.quals.len();
+        // write in place so recycled tiles keep their allocation
+        tile.quals_mut()
+            .iter_mut()
+            .zip(input[0..n_quals].iter())
+            .for_each(|(q, x)| *q = bins[usize::from(x >> 2)]);
+        Ok((&input[n_quals..], ()))
     } else {
         fill(bcl_qual, tile.quals_mut())(input)
     }
 }
 
-fn bcl_base(input: &[u8]) -> IResult<&[u8], u8> {
+pub(crate) fn bcl_base(input: &[u8]) -> IResult<&[u8], u8> {
     map(le_u8, |x| BASE_LOOKUP[usize::from(x)])(input)
 }
 
-fn bcl_qual(input: &[u8]) -> IResult<&[u8], u8> {
+pub(crate) fn bcl_qual(input: &[u8]) -> IResult<&[u8], u8> {
     map(le_u8, |x| QUAL_LOOKUP[usize::from(x)])(input)
 }
 
@@ -88,15 +88,18 @@ pub(crate) fn cbcl_header(
         u8,                        // bits per basecall
         u8,                        // bits per qual
         u32,                       // number of bins
-        Option<Vec<(u32, u32)>>,   // qual bin pairs
+        Option<Vec<(u32, u32)>>,   // qual bin pairs, None if there are no bins
         u32,                       // number of tiles
         Vec<(u32, u32, u32, u32)>, // tile data
         u8,                        // non-PF excluded
     ),
 > {
     let (i, (bits_per_base, bits_per_qual, num_bins)) = tuple((le_u8, le_u8, le_u32))(input)?;
-    let (i, (bins, num_tiles)) =
-        pair(opt(count(pair(le_u32, le_u32), num_bins as usize)), le_u32)(i)?;
+    // no bins means no quality binning, but a declared table must be complete
+    let (i, (bins, num_tiles)) = pair(
+        cond(num_bins > 0, count(pair(le_u32, le_u32), num_bins as usize)),
+        le_u32,
+    )(i)?;
     let (i, (tile_data, pf_excluded)) = pair(count(cbcl_tile_data, num_tiles as usize), u8)(i)?;
 
     Ok((
@@ -122,3 +125,49 @@ pub(crate) fn cbcl_tile_data(input: &[u8]) -> IResult<&[u8], (u32, u32, u32, u32
         le_u32, // compressed block size (12-15)
     ))(input)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcl::{into_bin_lookup, reader::PREHEADER_SIZE, testutil::CBclBuilder};
+
+    const BINS: [(u32, u32); 4] = [(0, 2), (1, 14), (2, 21), (3, 33)];
+
+    /// The header of `builder`'s cbcl, past the preheader
+    fn header_bytes(builder: &CBclBuilder) -> Vec<u8> {
+        let cbcl = builder.build();
+        let (_, (_, size)) = cbcl_version_and_size(&cbcl).unwrap();
+        cbcl[PREHEADER_SIZE as usize..size as usize].to_vec()
+    }
+
+    #[test]
+    fn declared_bins_are_parsed() {
+        let header = header_bytes(
+            &CBclBuilder::new()
+                .bins(BINS.to_vec())
+                .tile(1101, b"A", &[1]),
+        );
+        let (_, (_, _, num_bins, bins, num_tiles, _, _)) = cbcl_header(&header).unwrap();
+        assert_eq!(num_bins, 4);
+        assert_eq!(bins.as_deref(), Some(BINS.as_slice()));
+        assert_eq!(num_tiles, 1);
+        assert_eq!(into_bin_lookup(bins), vec![ILLUMINA_MIN_QUAL, 14, 21, 33]);
+    }
+
+    #[test]
+    fn no_bins_means_no_binning() {
+        let header = header_bytes(&CBclBuilder::new().tile(1101, b"A", &[1]));
+        let (_, (_, _, num_bins, bins, _, _, _)) = cbcl_header(&header).unwrap();
+        assert_eq!(num_bins, 0);
+        assert_eq!(bins, None);
+        assert!(into_bin_lookup(bins).is_empty());
+    }
+
+    #[test]
+    fn declared_bins_must_be_provided() {
+        let mut header = header_bytes(&CBclBuilder::new().bins(BINS.to_vec()));
+        // bits per call and qual, then the bin count, then the bin table
+        header.drain(6..6 + 8 * BINS.len());
+        assert!(cbcl_header(&header).is_err());
+    }
+}
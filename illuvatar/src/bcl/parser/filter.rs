@@ -7,12 +7,59 @@ use nom::{
 };
 
 /// version and num clusters
+/// The header is 12 bytes: a zero u32 kept for backwards compatibility,
+/// the format version (3 on current instruments), then the cluster count.
 pub(crate) fn filter_header(input: &[u8]) -> IResult<&[u8], (u32, u32)> {
     preceded(le_u32, pair(le_u32, le_u32))(input)
 }
 
-/// ones and zeros
-/// 1 == pass filter, 0 == failed filter
+/// One byte per cluster
+/// Bit 0 is pass filter, the remaining bits are reserved and may be set
 pub(crate) fn filter_file<'a>(input: &'a [u8], buffer: &mut [u8]) -> IResult<&'a [u8], ()> {
     all_consuming(fill(le_u8, buffer))(input)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcl::{
+        reader::FILTER_HEADER_SIZE,
+        testutil::{filter_bytes, FILTER_VERSION},
+    };
+
+    /// A version 3 filter of five clusters, the fourth with reserved bits set
+    #[rustfmt::skip]
+    const FILTER: [u8; 17] = [
+        0, 0, 0, 0, // zero
+        3, 0, 0, 0, // version
+        5, 0, 0, 0, // cluster count
+        1, 0, 1, 0b1000_0011, 0,
+    ];
+
+    #[test]
+    fn parses_known_filter() {
+        let (body, (version, num_clusters)) = filter_header(&FILTER).unwrap();
+        assert_eq!(FILTER.len() - body.len(), FILTER_HEADER_SIZE);
+        assert_eq!(version, FILTER_VERSION);
+        assert_eq!(num_clusters, 5);
+
+        let mut flags = vec![0; num_clusters as usize];
+        filter_file(body, &mut flags).unwrap();
+        let pass = flags.iter().map(|f| f & 1 == 1).collect::<Vec<bool>>();
+        assert_eq!(pass, vec![true, false, true, true, false]);
+    }
+
+    #[test]
+    fn builder_matches_known_filter() {
+        let mut expected = FILTER;
+        expected[15] = 1;
+        assert_eq!(filter_bytes(&[true, false, true, true, false]), expected);
+    }
+
+    #[test]
+    fn short_filter_is_an_error() {
+        let mut flags = vec![0; 6];
+        let (body, _) = filter_header(&FILTER).unwrap();
+        assert!(filter_file(body, &mut flags).is_err());
+    }
+}
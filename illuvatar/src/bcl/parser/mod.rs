@@ -1,2 +1,3 @@
+pub mod bcl;
 pub mod cbcl;
 pub mod filter;
@@ -0,0 +1,52 @@
+use crossbeam::channel::{bounded, Receiver, Sender};
+
+use super::BclTile;
+
+pub const DEFAULT_TILE_POOL_CAPACITY: usize = 64;
+
+/// A free list of [BclTile]s shared between readers and demux workers
+///
+/// Readers [take](TilePool::take) a tile before decoding into it, and demux workers
+/// [give](TilePool::give) it back once they are done with it. Cloning the pool
+/// is cheap and every clone refers to the same free list.
+///
+/// The pool never blocks: if it is empty a new tile is allocated,
+/// and if it is full a returned tile is simply dropped.
+#[derive(Debug, Clone)]
+pub struct TilePool {
+    free_send: Sender<BclTile>,
+    free_recv: Receiver<BclTile>,
+}
+
+impl TilePool {
+    pub fn new(cap: usize) -> Self {
+        let (free_send, free_recv) = bounded(cap);
+        TilePool {
+            free_send,
+            free_recv,
+        }
+    }
+
+    /// Take a tile sized to hold `len` calls, reusing a recycled one if available
+    pub fn take(&self, len: usize) -> BclTile {
+        match self.free_recv.try_recv() {
+            Ok(mut tile) => {
+                tile.reset(len);
+                tile
+            }
+            Err(_) => BclTile::with_capacity(len),
+        }
+    }
+
+    /// Return a tile to the pool so its buffers can be reused
+    pub fn give(&self, tile: BclTile) {
+        // a full pool means readers are not keeping up, so just drop the tile
+        let _ = self.free_send.try_send(tile);
+    }
+}
+
+impl Default for TilePool {
+    fn default() -> Self {
+        TilePool::new(DEFAULT_TILE_POOL_CAPACITY)
+    }
+}
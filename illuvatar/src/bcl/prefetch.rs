@@ -0,0 +1,100 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+    thread,
+};
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+
+use super::BclError;
+
+/// Files with less compressed tile data than this are not worth a prefetch thread
+pub const PREFETCH_MIN_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Reads compressed tile blocks on a background thread, one block ahead of the decoder
+///
+/// The block channel holds a single block, so while tile N is being decompressed
+/// and parsed, tile N+1 is being read from disk (double buffering). Buffers are
+/// handed back through [recycle](Prefetcher::recycle) so the thread does not
+/// allocate a new one per block.
+///
+/// Dropping the prefetcher stops the thread at its next send.
+pub struct Prefetcher {
+    blocks: Receiver<Result<Vec<u8>, BclError>>,
+    recycle: Sender<Vec<u8>>,
+}
+
+impl Prefetcher {
+    /// Start reading `block_sizes` blocks from `path`, beginning at `offset`
+    pub fn spawn<P: AsRef<Path>>(
+        path: P,
+        offset: u64,
+        block_sizes: Vec<u32>,
+    ) -> Result<Self, BclError> {
+        let mut file = BufReader::new(File::open(path)?);
+        file.seek(SeekFrom::Start(offset))?;
+
+        let (block_send, blocks) = bounded(1);
+        let (recycle, recycle_recv) = bounded::<Vec<u8>>(2);
+        thread::Builder::new()
+            .name("illuv-cbcl-prefetch".to_string())
+            .spawn(move || {
+                for size in block_sizes {
+                    let mut block = recycle_recv.try_recv().unwrap_or_default();
+                    block.clear();
+                    let result = match (&mut file).take(u64::from(size)).read_to_end(&mut block) {
+                        Ok(v) if v == size as usize => Ok(block),
+                        Ok(v) => Err(BclError::CompSizeMismatch {
+                            expected: size,
+                            got: v,
+                        }),
+                        Err(e) => Err(BclError::from(e)),
+                    };
+                    // keep going after a bad block, like reading on the calling thread
+                    // does, so a reader that skips it gets the next block's own result
+                    if block_send.send(result).is_err() {
+                        // the reader hung up
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(Prefetcher { blocks, recycle })
+    }
+
+    /// Block until the next compressed block is available
+    pub fn next_block(&self) -> Result<Vec<u8>, BclError> {
+        match self.blocks.recv() {
+            Ok(block) => block,
+            // every block was already handed out
+            Err(_) => Err(BclError::EofError),
+        }
+    }
+
+    /// Hand a buffer back so the thread can read the next block into it
+    pub fn recycle(&self, buffer: Vec<u8>) {
+        let _ = self.recycle.try_send(buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcl::testutil::scratch_dir;
+
+    #[test]
+    fn blocks_after_a_bad_block_get_their_own_result() {
+        let path = scratch_dir("prefetch-short").join("L001_1.cbcl");
+        std::fs::write(&path, [0u8; 6]).unwrap();
+        let prefetcher = Prefetcher::spawn(&path, 0, vec![4, 8, 8]).unwrap();
+
+        assert_eq!(prefetcher.next_block().unwrap().len(), 4);
+        for got in [2, 0] {
+            assert!(matches!(
+                prefetcher.next_block(),
+                Err(BclError::CompSizeMismatch { expected: 8, got: g }) if g == got
+            ));
+        }
+    }
+}
@@ -1,18 +1,41 @@
 use libdeflater::Decompressor;
 use std::{
     fs::File,
-    io::{BufRead, BufReader, Read},
-    path::Path,
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
 };
 
-use samplesheet::SampleSheetSettings;
+use flate2::read::MultiGzDecoder;
+use fxhash::FxHashMap;
 
-use super::{into_bin_lookup, parser, BclError, BclTile, CBclHeader, TileData};
+use log::warn;
+
+use super::{
+    into_bin_lookup, parser,
+    pool::TilePool,
+    prefetch::{Prefetcher, PREFETCH_MIN_BYTES},
+    BclError, BclTile, CBclHeader, Downsample, PfTileFilter, TileData, CBCL_BITS_PER_BC,
+    CBCL_BITS_PER_QS,
+};
 
 pub const DEFAULT_BCL_READER_CAPACITY: usize = 1_000_000;
 pub const PREHEADER_SIZE: u32 = 6;
 pub const FILTER_HEADER_SIZE: usize = 12;
 
+/// A [FilterProvider] shared by the readers of every cycle of a lane
+pub type SharedFilters = Arc<Mutex<FilterProvider>>;
+
+/// Settings applied to every [CBclReader] of a run, see the matching setters
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReaderOptions {
+    pub pf_filter: PfTileFilter,
+    pub downsample: Option<Downsample>,
+    pub prefetch: bool,
+    /// Skip tiles that fail to decode, collecting them, rather than aborting
+    pub skip_errors: bool,
+}
+
 pub enum CbclReaderState {
     Header,
     Tile,
@@ -24,6 +47,7 @@ where
     R: BufRead,
 {
     inner: R,
+    path: PathBuf,
     buffer: Vec<u8>,
     decomp_buffer: Vec<u8>,
     header: CBclHeader,
@@ -31,13 +55,30 @@ where
     decomp: Decompressor,
     state: CbclReaderState,
     n_read: u32,
+    pool: Option<TilePool>,
+    pf_filter: PfTileFilter,
+    filters: Option<SharedFilters>,
+    downsample: Option<Downsample>,
+    skip_errors: bool,
+    skipped: Vec<SkippedTile>,
+    prefetch: bool,
+    prefetcher: Option<Prefetcher>,
+}
+
+/// A tile that failed to decode and was skipped rather than aborting the read
+#[derive(Debug)]
+pub struct SkippedTile {
+    pub path: PathBuf,
+    pub tile_num: u32,
+    pub error: BclError,
 }
 
 impl CBclReader<BufReader<File>> {
     pub fn new<P: AsRef<Path>>(cycle_info: P) -> Result<Self, BclError> {
-        let inner = BufReader::new(File::open(cycle_info)?);
+        let inner = BufReader::new(File::open(&cycle_info)?);
         Ok(CBclReader {
             inner,
+            path: cycle_info.as_ref().to_path_buf(),
             buffer: Vec::with_capacity(DEFAULT_BCL_READER_CAPACITY),
             decomp_buffer: Vec::new(),
             header: CBclHeader::default(),
@@ -45,13 +86,22 @@ impl CBclReader<BufReader<File>> {
             decomp: Decompressor::new(),
             state: CbclReaderState::Header,
             n_read: 0,
+            pool: None,
+            pf_filter: PfTileFilter::All,
+            filters: None,
+            downsample: None,
+            skip_errors: false,
+            skipped: Vec::new(),
+            prefetch: false,
+            prefetcher: None,
         })
     }
 
     pub fn with_capacity<P: AsRef<Path>>(cycle_info: P, cap: usize) -> Result<Self, BclError> {
-        let inner = BufReader::new(File::open(cycle_info)?);
+        let inner = BufReader::new(File::open(&cycle_info)?);
         Ok(CBclReader {
             inner,
+            path: cycle_info.as_ref().to_path_buf(),
             buffer: Vec::with_capacity(cap),
             header: CBclHeader::default(),
             tile_cache: Vec::new(),
@@ -59,6 +109,14 @@ impl CBclReader<BufReader<File>> {
             decomp_buffer: Vec::new(),
             state: CbclReaderState::Header,
             n_read: 0,
+            pool: None,
+            pf_filter: PfTileFilter::All,
+            filters: None,
+            downsample: None,
+            skip_errors: false,
+            skipped: Vec::new(),
+            prefetch: false,
+            prefetcher: None,
         })
     }
 
@@ -69,11 +127,13 @@ impl CBclReader<BufReader<File>> {
         cycle_info: P,
         clear_tile_cache: bool,
     ) -> Result<(), BclError> {
-        let inner = BufReader::new(File::open(cycle_info)?);
+        let inner = BufReader::new(File::open(&cycle_info)?);
         self.buffer.clear();
         self.decomp_buffer.clear();
         self.n_read = 0;
         self.inner = inner;
+        self.prefetcher = None;
+        self.path = cycle_info.as_ref().to_path_buf();
         self.header = CBclHeader::default();
         if clear_tile_cache {
             self.tile_cache.clear();
@@ -82,6 +142,72 @@ impl CBclReader<BufReader<File>> {
         Ok(())
     }
 
+    /// Path of the cbcl currently being read
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Decode into tiles taken from `pool` rather than allocating a new one per tile
+    pub fn set_pool(&mut self, pool: TilePool) {
+        self.pool = Some(pool);
+    }
+
+    /// Apply every setting in `options`
+    ///
+    /// Call before the header is read.
+    pub fn set_options(&mut self, options: &ReaderOptions) {
+        self.set_pf_filter(options.pf_filter);
+        self.downsample = options.downsample;
+        self.set_prefetch(options.prefetch);
+        self.set_skip_errors(options.skip_errors);
+    }
+
+    /// Only decode tiles selected by `pf_filter`, skipping over the rest
+    pub fn set_pf_filter(&mut self, pf_filter: PfTileFilter) {
+        self.pf_filter = pf_filter;
+    }
+
+    /// Remove clusters that did not pass filter using `filters`
+    ///
+    /// Without a provider, tiles are returned unfiltered.
+    pub fn set_filters(&mut self, filters: FilterProvider) {
+        self.filters = Some(Arc::new(Mutex::new(filters)));
+    }
+
+    /// Like [set_filters](CBclReader::set_filters), but sharing one provider's
+    /// cache with other readers, e.g. every cycle of a lane
+    pub fn share_filters(&mut self, filters: SharedFilters) {
+        self.filters = Some(filters);
+    }
+
+    /// Only keep a sample of each tile's clusters, applied after pass-filtering
+    pub fn set_downsample(&mut self, downsample: Downsample) {
+        self.downsample = Some(downsample);
+    }
+
+    /// Read the next tile's compressed block on a background thread while
+    /// the current tile is decoded
+    ///
+    /// Off by default. Files with less than [PREFETCH_MIN_BYTES] of tile data
+    /// are always read on the calling thread.
+    pub fn set_prefetch(&mut self, prefetch: bool) {
+        self.prefetch = prefetch;
+    }
+
+    /// Log and skip tiles that fail to decode instead of yielding an error
+    ///
+    /// Off by default. Skipped tiles are collected and can be retrieved
+    /// with [take_skipped](CBclReader::take_skipped); they persist across
+    /// [reset_with](CBclReader::reset_with).
+    pub fn set_skip_errors(&mut self, skip_errors: bool) {
+        self.skip_errors = skip_errors;
+    }
+
+    /// Drain the tiles skipped so far because they failed to decode
+    pub fn take_skipped(&mut self) -> Vec<SkippedTile> {
+        std::mem::take(&mut self.skipped)
+    }
+
     pub fn shrink_buffer(&mut self, to: usize) {
         self.buffer.shrink_to(to);
     }
@@ -90,24 +216,191 @@ impl CBclReader<BufReader<File>> {
         self.decomp_buffer.shrink_to(to)
     }
 
-    pub fn read_tile(&mut self) -> Option<Result<BclTile, BclError>> {
-        if self.n_read == self.header.n_tiles {
-            return None;
+    /// Parse the header without decoding any tiles
+    ///
+    /// The reader is left positioned at the first tile, so iterating afterwards
+    /// picks up where this left off. If the header was already read it is
+    /// returned as-is.
+    pub fn read_header_only(&mut self) -> Result<&CBclHeader, BclError> {
+        if let CbclReaderState::Header = self.state {
+            read_header(
+                &mut self.inner,
+                &self.path,
+                &mut self.buffer,
+                &mut self.header,
+                &mut self.tile_cache,
+            )?;
+            self.state = CbclReaderState::Tile;
+            if self.prefetch {
+                self.start_prefetch()?;
+            }
+        }
+        Ok(&self.header)
+    }
+
+    fn start_prefetch(&mut self) -> Result<(), BclError> {
+        let block_sizes = self
+            .tiles()
+            .iter()
+            .map(|t| t.block_size_comp)
+            .collect::<Vec<u32>>();
+        let total = block_sizes.iter().map(|s| u64::from(*s)).sum::<u64>();
+        if total >= PREFETCH_MIN_BYTES {
+            // tile data begins immediately after the header
+            self.prefetcher = Some(Prefetcher::spawn(
+                &self.path,
+                u64::from(self.header.size),
+                block_sizes,
+            )?);
+        }
+        Ok(())
+    }
+
+    /// Read the next compressed block into `buffer`
+    fn read_block(&mut self, block_size: u32) -> Result<(), BclError> {
+        if let Some(prefetcher) = self.prefetcher.as_ref() {
+            let block = prefetcher.next_block()?;
+            prefetcher.recycle(std::mem::replace(&mut self.buffer, block));
+            return Ok(());
         }
-        let tile_data = &self.tile_cache[self.n_read as usize];
         match (&mut self.inner)
-            .take(u64::from(tile_data.block_size_comp))
+            .take(u64::from(block_size))
             .read_to_end(&mut self.buffer)
         {
-            Ok(v) if v == tile_data.block_size_comp as usize => {}
-            Ok(v) => {
-                return Some(Err(BclError::CompSizeMismatch {
-                    expected: tile_data.block_size_comp,
-                    got: v,
-                }));
+            Ok(v) if v == block_size as usize => Ok(()),
+            Ok(v) => Err(BclError::CompSizeMismatch {
+                expected: block_size,
+                got: v,
+            }),
+            Err(e) => Err(BclError::from(e)),
+        }
+    }
+
+    /// Tile metadata from the most recently read header
+    ///
+    /// Empty until the header has been read.
+    pub fn tiles(&self) -> &[TileData] {
+        let n_tiles = self.header.n_tiles as usize;
+        // the tile cache may still hold tiles from previous files, see reset_with
+        &self.tile_cache[self.tile_cache.len().saturating_sub(n_tiles)..]
+    }
+
+    /// Lane number parsed from an `L<lane>_<surface>.cbcl` file name
+    pub fn lane(&self) -> Option<u8> {
+        let name = self.path.file_name()?.to_str()?;
+        let lane = name.strip_prefix('L')?.split('_').next()?;
+        lane.parse().ok()
+    }
+
+    /// Metadata for the tile most recently returned by [read_tile](CBclReader::read_tile)
+    pub fn last_tile(&self) -> Option<&TileData> {
+        match self.n_read {
+            0 => None,
+            n => self.tiles().get(n as usize - 1),
+        }
+    }
+
+    /// Advance past tiles that the pf filter does not select
+    ///
+    /// Their compressed blocks are consumed without being decompressed.
+    fn skip_unselected(&mut self) -> Result<(), BclError> {
+        while self.n_read < self.header.n_tiles {
+            let tile_data = self.current_tile();
+            if self.pf_filter.keep(tile_data) {
+                break;
+            }
+            self.discard_block(tile_data.block_size_comp)?;
+            self.n_read += 1;
+        }
+        Ok(())
+    }
+
+    /// Consume the next compressed block without decompressing it
+    fn discard_block(&mut self, block_size: u32) -> Result<(), BclError> {
+        if self.prefetcher.is_some() {
+            // the prefetcher reads every block, so drain this one
+            self.read_block(block_size)?;
+            self.buffer.clear();
+            return Ok(());
+        }
+        let size = u64::from(block_size);
+        match io::copy(&mut (&mut self.inner).take(size), &mut io::sink()) {
+            Ok(v) if v == size => Ok(()),
+            Ok(v) => Err(BclError::CompSizeMismatch {
+                expected: block_size,
+                got: v as usize,
+            }),
+            Err(e) => Err(BclError::from(e)),
+        }
+    }
+
+    /// Advance past the next tile [read_tile](CBclReader::read_tile) would
+    /// return, without decoding it
+    ///
+    /// Returns the tile number, or `None` once every tile has been read.
+    pub fn discard_tile(&mut self) -> Result<Option<u32>, BclError> {
+        self.skip_unselected()?;
+        if self.n_read == self.header.n_tiles {
+            return Ok(None);
+        }
+        let tile_data = self.current_tile();
+        let (tile_num, block_size) = (tile_data.tile_num, tile_data.block_size_comp);
+        self.discard_block(block_size)?;
+        self.n_read += 1;
+        Ok(Some(tile_num))
+    }
+
+    /// Record the tile that [read_tile](CBclReader::read_tile) just failed on
+    /// as skipped and move past it, returning its tile number
+    ///
+    /// Its block was already consumed, so the next tile is read from the right place.
+    pub fn skip_failed(&mut self, error: BclError) -> u32 {
+        let tile_num = self.current_tile().tile_num;
+        warn!(
+            "skipping tile {tile_num} of {}: {error}",
+            self.path.display()
+        );
+        self.skipped.push(SkippedTile {
+            path: self.path.clone(),
+            tile_num,
+            error,
+        });
+        self.buffer.clear();
+        self.decomp_buffer.clear();
+        self.n_read += 1;
+        tile_num
+    }
+
+    pub fn read_tile(&mut self) -> Option<Result<BclTile, BclError>> {
+        loop {
+            if let Err(e) = self.skip_unselected() {
+                return Some(Err(e));
+            }
+            if self.n_read == self.header.n_tiles {
+                return None;
+            }
+            match self.decode_tile() {
+                Ok(tile) => {
+                    self.n_read += 1;
+                    return Some(Ok(tile));
+                }
+                Err(e) if self.skip_errors => {
+                    self.skip_failed(e);
+                }
+                Err(e) => return Some(Err(e)),
             }
-            Err(e) => return Some(Err(BclError::from(e))),
         }
+    }
+
+    /// Metadata of the tile at `n_read`, in the file currently being read
+    fn current_tile(&self) -> &TileData {
+        &self.tiles()[self.n_read as usize]
+    }
+
+    /// Decompress and parse the tile at `n_read`
+    fn decode_tile(&mut self) -> Result<BclTile, BclError> {
+        let tile_data = self.current_tile().clone();
+        self.read_block(tile_data.block_size_comp)?;
         if (self.decomp_buffer.len() as u32) < tile_data.block_size_un {
             self.decomp_buffer
                 .resize(tile_data.block_size_un as usize, 0);
@@ -117,8 +410,8 @@ impl CBclReader<BufReader<File>> {
             &mut self.decomp_buffer.as_mut_slice(),
         ) {
             Ok(v) if (v as u32) == tile_data.block_size_un => {}
-            Ok(_) => return Some(Err(BclError::DecompSizeMismatch)),
-            Err(e) => return Some(Err(BclError::from(e))),
+            Ok(_) => return Err(BclError::DecompSizeMismatch),
+            Err(e) => return Err(BclError::from(e)),
         }
         self.buffer.clear();
         self.buffer.extend(
@@ -127,25 +420,36 @@ impl CBclReader<BufReader<File>> {
                 .flat_map(|x| [x & 0x0f, (x >> 4) & 0x0f]), // nibbles to bytes
         );
         // multiply by two to account for the nibble explosion
-        let mut tile = BclTile::with_capacity((tile_data.block_size_un * 2u32) as usize);
+        let tile_len = (tile_data.block_size_un * 2u32) as usize;
+        let mut tile = match &self.pool {
+            Some(pool) => pool.take(tile_len),
+            None => BclTile::with_capacity(tile_len),
+        };
         match parser::cbcl::parse_base_calls(&self.buffer, &mut tile, &self.header.bins) {
             Ok(_) => {}
             Err(e) => {
-                return Some(Err(BclError::from(e)));
+                return Err(BclError::from(e));
             }
         };
+        // two calls are packed per byte, so a tile with an odd number of
+        // clusters ends in a padding call
+        tile.truncate(tile_data.num_clusters as usize);
 
-        if !tile_data.pf_excluded && tile_data.has_filter() {
-            match filter_reads(&mut tile, tile_data.get_or_read_filter().as_ref().unwrap()) {
-                Ok(_) => {}
-                Err(e) => return Some(Err(BclError::from(e))),
+        if !tile_data.pf_excluded {
+            if let Some(filters) = self.filters.as_ref() {
+                // a panic elsewhere cannot leave the cache half-written, so ignore poisoning
+                let mut filters = filters.lock().unwrap_or_else(PoisonError::into_inner);
+                let filter = filters.filter(tile_data.tile_num, self.tiles())?;
+                filter_reads(&mut tile, &tile_data, filter)?;
             }
         }
+        if let Some(downsample) = self.downsample.as_ref() {
+            downsample.apply(&mut tile, tile_data.tile_num);
+        }
 
-        self.n_read += 1;
         self.buffer.clear();
         self.decomp_buffer.clear();
-        Some(Ok(tile))
+        Ok(tile)
     }
 }
 
@@ -161,14 +465,8 @@ impl Iterator for CBclReader<BufReader<File>> {
                 }
             },
             CbclReaderState::Header => {
-                match read_header(
-                    &mut self.inner,
-                    &mut self.buffer,
-                    &mut self.header,
-                    &mut self.tile_cache,
-                ) {
-                    Ok(_) => self.state = CbclReaderState::Tile,
-                    Err(e) => return Some(Err(e)),
+                if let Err(e) = self.read_header_only() {
+                    return Some(Err(e));
                 }
                 self.next()
             }
@@ -181,6 +479,7 @@ impl Iterator for CBclReader<BufReader<File>> {
 /// Read Cbcl header, including tile metadata entries
 fn read_header<'a, T>(
     mut from: T,
+    path: &Path,
     to: &mut Vec<u8>,
     header: &mut CBclHeader,
     tile_cache: &mut Vec<TileData>,
@@ -190,8 +489,12 @@ where
 {
     match (&mut from).take(u64::from(PREHEADER_SIZE)).read_to_end(to) {
         Ok(x) if x == PREHEADER_SIZE as usize => {}
-        Ok(_) => {
-            return Err(BclError::EofError);
+        Ok(x) => {
+            return Err(BclError::TruncatedHeader {
+                path: path.to_path_buf(),
+                expected: PREHEADER_SIZE,
+                got: x,
+            });
         }
         Err(e) => return Err(BclError::from(e)),
     }
@@ -199,16 +502,41 @@ where
         Ok((_, (version, h_size))) => (version, h_size),
         Err(e) => return Err(BclError::from(e)),
     };
+    // the declared size includes the preheader, so anything smaller is nonsense
+    if h_size < PREHEADER_SIZE {
+        return Err(BclError::TruncatedHeader {
+            path: path.to_path_buf(),
+            expected: PREHEADER_SIZE,
+            got: h_size as usize,
+        });
+    }
     to.clear();
     match from
         .take(u64::from(h_size - PREHEADER_SIZE))
         .read_to_end(to)
     {
         Ok(amt) if amt as u32 == h_size - PREHEADER_SIZE => {}
-        Ok(_) => return Err(BclError::EofError),
+        Ok(amt) => {
+            return Err(BclError::TruncatedHeader {
+                path: path.to_path_buf(),
+                expected: h_size,
+                got: amt + PREHEADER_SIZE as usize,
+            })
+        }
         Err(e) => return Err(BclError::from(e)),
     }
     match parser::cbcl::cbcl_header(to) {
+        Ok((_, (_, _, _, _, 0, _, _))) => return Err(BclError::NoTiles),
+        // decode unpacks each byte as two 4-bit (2 base + 2 quality) calls
+        Ok((_, (bits_per_bc, bits_per_qs, _, _, _, _, _)))
+            if bits_per_bc != CBCL_BITS_PER_BC || bits_per_qs != CBCL_BITS_PER_QS =>
+        {
+            return Err(BclError::UnsupportedBitWidth {
+                path: path.to_path_buf(),
+                bits_per_bc,
+                bits_per_qs,
+            })
+        }
         Ok((_, (bits_per_bc, bits_per_qs, n_bins, bins, n_tiles, tile_data, pf_excluded))) => {
             *header = CBclHeader {
                 version,
@@ -226,7 +554,6 @@ where
                     block_size_un: *block_size_un,
                     block_size_comp: *block_size_comp,
                     pf_excluded: pf_excluded == 1,
-                    filter: get_filter(*tile_num),
                 },
             ));
         }
@@ -236,33 +563,65 @@ where
     Ok(())
 }
 
-struct FilterFileReader<T>
+pub struct FilterFileReader<T>
 where
     T: BufRead,
 {
     inner: T,
+    path: PathBuf,
     buffer: Vec<u8>,
 }
 
 impl FilterFileReader<BufReader<File>> {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, BclError> {
-        let inner = BufReader::new(File::open(path)?);
+        let inner = BufReader::new(File::open(&path)?);
         Ok(FilterFileReader {
             inner,
+            path: path.as_ref().to_path_buf(),
             buffer: Vec::new(),
         })
     }
 
+    /// Pre-size the read buffer, e.g. to [FILTER_HEADER_SIZE] plus the expected cluster count
+    pub fn with_capacity<P: AsRef<Path>>(path: P, cap: usize) -> Result<Self, BclError> {
+        let inner = BufReader::new(File::open(&path)?);
+        Ok(FilterFileReader {
+            inner,
+            path: path.as_ref().to_path_buf(),
+            buffer: Vec::with_capacity(cap),
+        })
+    }
+
     pub fn read_filter(&mut self) -> Result<Vec<u8>, BclError> {
-        match self.inner.read_to_end(&mut self.buffer) {
-            Ok(x) if x >= FILTER_HEADER_SIZE => {}
-            Ok(_) => return Err(BclError::EofError),
+        self.buffer.clear();
+        self.buffer.resize(FILTER_HEADER_SIZE, 0);
+        match self.inner.read_exact(&mut self.buffer) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(BclError::EofError),
             Err(e) => return Err(BclError::from(e)),
         }
-        let (i, (_, num_clusters)) = parser::filter::filter_header(&self.buffer)?;
-        match num_clusters {
-            x if x == i.len() as u32 => {}
-            _ => return Err(BclError::EofError),
+        // peek at the cluster count so the rest is read without reallocating,
+        // checking it against the file first so a corrupt header can't demand
+        // an arbitrarily large buffer
+        let (_, (_, num_clusters)) = parser::filter::filter_header(&self.buffer)?;
+        let file_len = self.inner.get_ref().metadata()?.len();
+        let body_len = file_len.saturating_sub(FILTER_HEADER_SIZE as u64) as usize;
+        if num_clusters as usize != body_len {
+            return Err(BclError::FilterClusterCount {
+                path: self.path.clone(),
+                expected: num_clusters,
+                got: body_len,
+            });
+        }
+        self.buffer.reserve(body_len);
+        self.inner.read_to_end(&mut self.buffer)?;
+        let (i, _) = parser::filter::filter_header(&self.buffer)?;
+        if num_clusters as usize != i.len() {
+            return Err(BclError::FilterClusterCount {
+                path: self.path.clone(),
+                expected: num_clusters,
+                got: i.len(),
+            });
         }
         let mut filter = vec![0; num_clusters as usize];
         parser::filter::filter_file(i, filter.as_mut_slice())?;
@@ -270,20 +629,365 @@ impl FilterFileReader<BufReader<File>> {
     }
 }
 
+/// Gzip member magic bytes, used to tell `.bcl.gz` from `.bcl` regardless of extension
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Reader for the plain and gzipped BCLs of older instruments (HiSeq, MiSeq)
+///
+/// These runs store one file per tile per cycle, e.g. `L001/C1.1/s_1_1101.bcl.gz`,
+/// so each file decodes to a single [BclTile]. Use [reset_with](BclReader::reset_with)
+/// to move on to the next file while keeping the buffers.
+pub struct BclReader {
+    path: PathBuf,
+    buffer: Vec<u8>,
+    decomp_buffer: Vec<u8>,
+    pool: Option<TilePool>,
+}
+
+impl BclReader {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        BclReader {
+            path: path.as_ref().to_path_buf(),
+            buffer: Vec::new(),
+            decomp_buffer: Vec::new(),
+            pool: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn reset_with<P: AsRef<Path>>(&mut self, path: P) {
+        self.path = path.as_ref().to_path_buf();
+    }
+
+    /// Decode into tiles taken from `pool` rather than allocating new ones
+    pub fn set_pool(&mut self, pool: TilePool) {
+        self.pool = Some(pool);
+    }
+
+    /// Tile number parsed from an `s_<lane>_<tile>.bcl[.gz]` file name
+    pub fn tile_num(&self) -> Option<u32> {
+        let name = self.path.file_name()?.to_str()?;
+        let stem = name.split('.').next()?;
+        stem.rsplit('_').next()?.parse().ok()
+    }
+
+    /// Clusters the file declares, reading only its header
+    pub fn num_clusters(&self) -> Result<u32, BclError> {
+        let mut file = BufReader::new(File::open(&self.path)?);
+        let mut header = [0; 4];
+        if file.fill_buf()?.starts_with(&GZIP_MAGIC) {
+            MultiGzDecoder::new(file).read_exact(&mut header)?;
+        } else {
+            file.read_exact(&mut header)?;
+        }
+        Ok(u32::from_le_bytes(header))
+    }
+
+    /// Read and decode the whole file
+    pub fn read_tile(&mut self) -> Result<BclTile, BclError> {
+        self.buffer.clear();
+        File::open(&self.path)?.read_to_end(&mut self.buffer)?;
+
+        let calls = if self.buffer.starts_with(&GZIP_MAGIC) {
+            // gzip's trailing ISIZE is only the last member's size, mod 2^32,
+            // so it is a hint for the buffer rather than the size to expect
+            let hint = match self.buffer.len().checked_sub(4) {
+                Some(end) => u32::from_le_bytes(self.buffer[end..].try_into().unwrap()) as usize,
+                None => return Err(BclError::EofError),
+            };
+            self.decomp_buffer.clear();
+            self.decomp_buffer.reserve(hint);
+            MultiGzDecoder::new(self.buffer.as_slice()).read_to_end(&mut self.decomp_buffer)?;
+            &self.decomp_buffer
+        } else {
+            &self.buffer
+        };
+
+        let (i, num_clusters) = parser::bcl::bcl_num_clusters(calls)?;
+        if num_clusters as usize != i.len() {
+            return Err(BclError::BclClusterCount {
+                path: self.path.clone(),
+                expected: num_clusters,
+                got: i.len(),
+            });
+        }
+        let mut tile = match &self.pool {
+            Some(pool) => pool.take(i.len()),
+            None => BclTile::with_capacity(i.len()),
+        };
+        parser::bcl::parse_bcl_calls(i, &mut tile)?;
+        Ok(tile)
+    }
+}
+
 // OPTIMIZE -> reallocation may actually be faster?
 // https://github.com/rust-lang/rust/issues/91497
 // I can't tell if the resulting PR was actually merged, need to manually bench
 /// Read filter associated with a cycle, remove any indices that do not pass
-/// i.e. == 0
-fn filter_reads(tile: &mut BclTile, filter: &[u8]) -> Result<(), BclError> {
-    //let filter = FilterFileReader::new(filter_path)?.read_filter()?;
-    tile.bases.retain(|_| filter.iter().next().unwrap() == &1);
-    tile.quals.retain(|_| filter.iter().next().unwrap() == &1);
+/// i.e. bit 0 is unset
+pub(crate) fn filter_reads(
+    tile: &mut BclTile,
+    tile_data: &TileData,
+    filter: &[u8],
+) -> Result<(), BclError> {
+    if filter.len() != tile_data.num_clusters as usize {
+        return Err(BclError::FilterSizeMismatch {
+            tile_num: tile_data.tile_num,
+            expected: tile_data.num_clusters as usize,
+            got: filter.len(),
+        });
+    }
+    let mut pass = filter.iter();
+    tile.bases
+        .retain(|_| pass.next().is_some_and(|f| f & 1 == 1));
+    let mut pass = filter.iter();
+    tile.quals
+        .retain(|_| pass.next().is_some_and(|f| f & 1 == 1));
     Ok(())
 }
 
-fn get_filter(tile_num: u32) -> Option<&'static [u8]> {
-    todo!()
+/// How pass-filter data is laid out on disk for a lane
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterLayout {
+    /// One `s_<lane>_<tile>.filter` per tile, e.g. NovaSeq
+    PerTile,
+    /// A single `s_<lane>.filter` per lane with every tile's clusters
+    /// concatenated in header tile order, e.g. NextSeq 500
+    PerLane,
+}
+
+/// Locates and reads the pass-filter data for a tile, keyed by tile number
+///
+/// Filters are read lazily the first time a tile is requested and then cached,
+/// since every cycle of a lane shares the same filters. The cache holds one byte
+/// per cluster, use [clear](FilterProvider::clear) once a lane is done.
+#[derive(Debug)]
+pub struct FilterProvider {
+    lane_dir: PathBuf,
+    lane: u8,
+    layout: FilterLayout,
+    cache: FxHashMap<u32, Vec<u8>>,
+    /// Every tile of the lane, across surfaces, in per-lane filter order
+    lane_tiles: Vec<TileData>,
+}
+
+impl FilterProvider {
+    pub fn new<P: AsRef<Path>>(lane_dir: P, lane: u8, layout: FilterLayout) -> Self {
+        FilterProvider {
+            lane_dir: lane_dir.as_ref().to_path_buf(),
+            lane,
+            layout,
+            cache: FxHashMap::default(),
+            lane_tiles: Vec::new(),
+        }
+    }
+
+    /// Pick the layout based on whether a per-lane filter file is present
+    pub fn detect<P: AsRef<Path>>(lane_dir: P, lane: u8) -> Self {
+        let layout = if lane_dir.as_ref().join(format!("s_{lane}.filter")).is_file() {
+            FilterLayout::PerLane
+        } else {
+            FilterLayout::PerTile
+        };
+        FilterProvider::new(lane_dir, lane, layout)
+    }
+
+    pub fn layout(&self) -> FilterLayout {
+        self.layout
+    }
+
+    /// Locate tiles in a per-lane filter with every tile of the lane
+    ///
+    /// A per-lane filter covers every surface, so the tiles of a single cbcl
+    /// are not enough to find the offsets of the second surface's tiles. See
+    /// [cycle_tiles](super::integrity::cycle_tiles).
+    pub fn set_lane_tiles(&mut self, lane_tiles: Vec<TileData>) {
+        self.lane_tiles = lane_tiles;
+    }
+
+    pub fn clear(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Drop the cached filter of a tile that every cycle is done with
+    pub fn evict(&mut self, tile_num: u32) {
+        self.cache.remove(&tile_num);
+    }
+
+    /// Get the filter for `tile_num`, reading it from disk if it is not cached
+    ///
+    /// `tiles` is the tile cache of the cbcl being read, which is needed to
+    /// find a tile's offset within a per-lane filter unless the lane's tiles
+    /// were given with [set_lane_tiles](FilterProvider::set_lane_tiles).
+    pub fn filter(&mut self, tile_num: u32, tiles: &[TileData]) -> Result<&[u8], BclError> {
+        if !self.cache.contains_key(&tile_num) {
+            match self.layout {
+                FilterLayout::PerTile => {
+                    let path = self
+                        .lane_dir
+                        .join(format!("s_{}_{}.filter", self.lane, tile_num));
+                    let n_clusters = tiles
+                        .iter()
+                        .find(|t| t.tile_num == tile_num)
+                        .map_or(0, |t| t.num_clusters as usize);
+                    let filter =
+                        FilterFileReader::with_capacity(path, FILTER_HEADER_SIZE + n_clusters)?
+                            .read_filter()?;
+                    self.cache.insert(tile_num, filter);
+                }
+                FilterLayout::PerLane => {
+                    let path = self.lane_dir.join(format!("s_{}.filter", self.lane));
+                    let tiles = if self.lane_tiles.is_empty() {
+                        tiles
+                    } else {
+                        self.lane_tiles.as_slice()
+                    };
+                    let n_clusters = tiles.iter().map(|t| t.num_clusters as usize).sum::<usize>();
+                    let filter =
+                        FilterFileReader::with_capacity(&path, FILTER_HEADER_SIZE + n_clusters)?
+                            .read_filter()?;
+                    // split the lane filter up front so later tiles are cache hits
+                    let mut offset = 0;
+                    for tile in tiles {
+                        let end = offset + tile.num_clusters as usize;
+                        if end > filter.len() {
+                            return Err(BclError::FilterSizeMismatch {
+                                tile_num: tile.tile_num,
+                                expected: end,
+                                got: filter.len(),
+                            });
+                        }
+                        self.cache
+                            .insert(tile.tile_num, filter[offset..end].to_vec());
+                        offset = end;
+                    }
+                }
+            }
+        }
+        match self.cache.get(&tile_num) {
+            Some(filter) => Ok(filter),
+            None => Err(BclError::MissingFilter {
+                tile_num,
+                path: self.lane_dir.clone(),
+            }),
+        }
+    }
 }
 
-fn resolve_tile(tile: &BclTile, tile_meta: &TileData, settings: &SampleSheetSettings) {}
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::bcl::{
+        integrity::cycle_tiles,
+        parser::cbcl::ILLUMINA_MIN_QUAL,
+        testutil::{filter_bytes, gzip, scratch_dir, CBclBuilder},
+    };
+
+    /// Parse the header of an in-memory cbcl
+    fn parse_header(cbcl: &[u8]) -> Result<(CBclHeader, Vec<TileData>), BclError> {
+        let mut header = CBclHeader::default();
+        let mut tiles = Vec::new();
+        read_header(
+            cbcl,
+            Path::new("L001_1.cbcl"),
+            &mut Vec::new(),
+            &mut header,
+            &mut tiles,
+        )?;
+        Ok((header, tiles))
+    }
+
+    #[test]
+    fn zero_tile_header_is_an_error() {
+        let cbcl = CBclBuilder::new().build();
+        assert!(matches!(parse_header(&cbcl), Err(BclError::NoTiles)));
+    }
+
+    #[test]
+    fn header_lists_every_tile() {
+        let cbcl = CBclBuilder::new()
+            .tile(1101, b"ACGT", &[1, 2, 3, 3])
+            .tile(1102, b"AC", &[1, 1])
+            .build();
+        let (header, tiles) = parse_header(&cbcl).unwrap();
+        assert_eq!(header.n_tiles(), 2);
+        assert_eq!(
+            tiles.iter().map(|t| t.tile_num()).collect::<Vec<u32>>(),
+            vec![1101, 1102]
+        );
+    }
+
+    #[test]
+    fn odd_tiles_drop_padding_before_filtering() {
+        let dir = scratch_dir("odd-tile-filter");
+        let cbcl = dir.join("L001_1.cbcl");
+        CBclBuilder::new()
+            .tile(1101, b"ACG", &[1, 2, 3])
+            .write_to(&cbcl)
+            .unwrap();
+        fs::write(
+            dir.join("s_1_1101.filter"),
+            filter_bytes(&[true, false, true]),
+        )
+        .unwrap();
+
+        let mut reader = CBclReader::new(&cbcl).unwrap();
+        reader.read_header_only().unwrap();
+        reader.set_filters(FilterProvider::new(&dir, 1, FilterLayout::PerTile));
+        let tile = reader.read_tile().unwrap().unwrap();
+        assert_eq!(tile.get_bases(), b"AG");
+        assert_eq!(tile.get_quals(), &[ILLUMINA_MIN_QUAL, 3]);
+        assert!(reader.read_tile().is_none());
+    }
+
+    #[test]
+    fn per_lane_filter_covers_every_surface() {
+        let dir = scratch_dir("per-lane-filter");
+        let surfaces = [dir.join("L001_1.cbcl"), dir.join("L001_2.cbcl")];
+        CBclBuilder::new()
+            .tile(1101, b"AC", &[1, 1])
+            .write_to(&surfaces[0])
+            .unwrap();
+        CBclBuilder::new()
+            .tile(2101, b"GTA", &[1, 1, 1])
+            .write_to(&surfaces[1])
+            .unwrap();
+        fs::write(
+            dir.join("s_1.filter"),
+            filter_bytes(&[true, true, false, true, true]),
+        )
+        .unwrap();
+
+        let mut filters = FilterProvider::detect(&dir, 1);
+        assert_eq!(filters.layout(), FilterLayout::PerLane);
+        filters.set_lane_tiles(cycle_tiles(&surfaces).unwrap());
+        let mut reader = CBclReader::new(&surfaces[1]).unwrap();
+        reader.read_header_only().unwrap();
+        reader.set_filters(filters);
+        assert_eq!(reader.read_tile().unwrap().unwrap().get_bases(), b"TA");
+    }
+
+    #[test]
+    fn gzipped_bcl_spanning_members_decodes() {
+        let dir = scratch_dir("bcl-gz");
+        let path = dir.join("s_1_1101.bcl.gz");
+        // A, C, G, T and a no-call, qualities 30, 20, 10, 2 and 0
+        let mut bcl = 5u32.to_le_bytes().to_vec();
+        bcl.extend([30 << 2, (20 << 2) | 1, (10 << 2) | 2, (2 << 2) | 3, 0]);
+        let mut gz = gzip(&bcl[..6]);
+        gz.extend(gzip(&bcl[6..]));
+        fs::write(&path, gz).unwrap();
+
+        let mut reader = BclReader::new(&path);
+        assert_eq!(reader.tile_num(), Some(1101));
+        assert_eq!(reader.num_clusters().unwrap(), 5);
+        let tile = reader.read_tile().unwrap();
+        assert_eq!(tile.get_bases(), b"ACGTN");
+        assert_eq!(tile.get_quals(), &[30, 20, 10, 2, ILLUMINA_MIN_QUAL]);
+    }
+}
@@ -0,0 +1,186 @@
+// Builders for synthetic cbcl and filter files, so the decode paths can be
+// exercised without real run data. Only compiled for tests or with the
+// `test-util` feature.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use libdeflater::{CompressionLvl, Compressor};
+
+use super::{reader::PREHEADER_SIZE, BclTile};
+
+/// cbcl format version written by current instruments
+pub const CBCL_VERSION: u16 = 1;
+/// Filter format version written by current instruments
+pub const FILTER_VERSION: u32 = 3;
+
+struct SyntheticTile {
+    tile_num: u32,
+    num_clusters: u32,
+    block: Vec<u8>,
+}
+
+/// Assembles a valid cbcl byte stream tile by tile
+///
+/// Calls are given as bases (`A`, `C`, `G`, `T` or `N`) and quality bin
+/// indices (0-3). With [bins](CBclBuilder::bins) set, a bin index decodes to the
+/// bin's score, otherwise to the index itself, floored at
+/// [ILLUMINA_MIN_QUAL](super::parser::cbcl::ILLUMINA_MIN_QUAL).
+/// `N` is always written as a no-call, whatever its bin, and since an all-zero
+/// call is how no-calls are stored, an `A` in bin 0 is written in bin 1.
+///
+/// Two calls are packed per byte, so a tile with an odd number of clusters
+/// is padded with a no-call, which the reader drops again.
+#[derive(Default)]
+pub struct CBclBuilder {
+    bins: Vec<(u32, u32)>,
+    pf_excluded: bool,
+    tiles: Vec<SyntheticTile>,
+}
+
+impl CBclBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Quality bins as (bin, score) pairs, in bin order
+    pub fn bins(mut self, bins: Vec<(u32, u32)>) -> Self {
+        self.bins = bins;
+        self
+    }
+
+    /// Mark non-PF clusters as already removed, so filters are not applied
+    pub fn pf_excluded(mut self, pf_excluded: bool) -> Self {
+        self.pf_excluded = pf_excluded;
+        self
+    }
+
+    /// Append a tile, compressing its calls into a gzip block
+    ///
+    /// Panics if `bases` and `qual_bins` differ in length, or a bin is above 3.
+    pub fn tile(mut self, tile_num: u32, bases: &[u8], qual_bins: &[u8]) -> Self {
+        assert_eq!(bases.len(), qual_bins.len(), "one quality bin per base");
+        let nibbles = bases
+            .iter()
+            .zip(qual_bins.iter())
+            .map(|(base, bin)| encode_call(*base, *bin))
+            .collect::<Vec<u8>>();
+        let packed = nibbles
+            .chunks(2)
+            .map(|pair| pair[0] | (pair.get(1).copied().unwrap_or(0) << 4))
+            .collect::<Vec<u8>>();
+        self.tiles.push(SyntheticTile {
+            tile_num,
+            num_clusters: bases.len() as u32,
+            block: gzip(&packed),
+        });
+        self
+    }
+
+    /// Size of the header, including the preheader
+    fn header_size(&self) -> u32 {
+        PREHEADER_SIZE + 2 + 4 + 8 * self.bins.len() as u32 + 4 + 16 * self.tiles.len() as u32 + 1
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(CBCL_VERSION.to_le_bytes());
+        out.extend(self.header_size().to_le_bytes());
+        out.push(super::CBCL_BITS_PER_BC);
+        out.push(super::CBCL_BITS_PER_QS);
+        out.extend((self.bins.len() as u32).to_le_bytes());
+        for (bin, score) in self.bins.iter() {
+            out.extend(bin.to_le_bytes());
+            out.extend(score.to_le_bytes());
+        }
+        out.extend((self.tiles.len() as u32).to_le_bytes());
+        for tile in self.tiles.iter() {
+            out.extend(tile.tile_num.to_le_bytes());
+            out.extend(tile.num_clusters.to_le_bytes());
+            out.extend((tile.num_clusters.div_ceil(2)).to_le_bytes());
+            out.extend((tile.block.len() as u32).to_le_bytes());
+        }
+        out.push(u8::from(self.pf_excluded));
+        for tile in self.tiles.iter() {
+            out.extend(tile.block.iter());
+        }
+        out
+    }
+
+    /// Write the cbcl to `path`, e.g. `<tmp>/L001_1.cbcl` so the lane can be inferred
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+        fs::write(path, self.build())
+    }
+}
+
+/// A decoded tile of one cycle, one call per cluster, `quals` holding numeric scores
+pub fn bcl_tile(bases: &[u8], quals: &[u8]) -> BclTile {
+    assert_eq!(bases.len(), quals.len(), "one quality per base");
+    let mut tile = BclTile::with_capacity(bases.len());
+    tile.bases_mut().copy_from_slice(bases);
+    tile.quals_mut().copy_from_slice(quals);
+    tile
+}
+
+/// A plain per-tile bcl, one byte per call holding the base and its numeric score
+///
+/// `N` is written as a no-call, whatever its score.
+pub fn bcl_bytes(bases: &[u8], quals: &[u8]) -> Vec<u8> {
+    assert_eq!(bases.len(), quals.len(), "one quality per base");
+    let mut out = (bases.len() as u32).to_le_bytes().to_vec();
+    out.extend(bases.iter().zip(quals.iter()).map(|(base, qual)| {
+        assert!(*qual < 64, "quality {qual} does not fit in 6 bits");
+        match base {
+            b'A' => qual << 2,
+            b'C' => (qual << 2) | 1,
+            b'G' => (qual << 2) | 2,
+            b'T' => (qual << 2) | 3,
+            _ => 0,
+        }
+    }));
+    out
+}
+
+/// A filter file with one entry per cluster, `true` meaning pass filter
+pub fn filter_bytes(pass_filter: &[bool]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(super::reader::FILTER_HEADER_SIZE + pass_filter.len());
+    out.extend(0u32.to_le_bytes());
+    out.extend(FILTER_VERSION.to_le_bytes());
+    out.extend((pass_filter.len() as u32).to_le_bytes());
+    out.extend(pass_filter.iter().map(|pf| u8::from(*pf)));
+    out
+}
+
+fn encode_call(base: u8, bin: u8) -> u8 {
+    assert!(bin < 4, "quality bin {bin} does not fit in 2 bits");
+    let code = match base {
+        b'A' => 0,
+        b'C' => 1,
+        b'G' => 2,
+        b'T' => 3,
+        _ => return 0,
+    };
+    // an all-zero call is a no-call, so an A needs a nonzero bin to survive
+    (bin.max(u8::from(code == 0)) << 2) | code
+}
+
+/// A single gzip member holding `data`
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut compressor = Compressor::new(CompressionLvl::default());
+    let mut out = vec![0; compressor.gzip_compress_bound(data.len())];
+    let n = compressor
+        .gzip_compress(data, &mut out)
+        .expect("output buffer is sized by gzip_compress_bound");
+    out.truncate(n);
+    out
+}
+
+/// A fresh, empty directory under the system temp dir, unique to this process and `name`
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("illuvatar-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("temp dir is writable");
+    dir
+}
@@ -0,0 +1,342 @@
+// Demux settings that can come from the command line or a TOML config file.
+// The same struct backs both, so a new knob is declared once and picked up by
+// clap and serde alike.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use clap::{value_parser, Args, ValueEnum};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    assemble::layout::CycleRange,
+    bcl::PfTileFilter,
+    manager::readname::{Casava18ReadName, ClassicReadName, ReadNameFormatter},
+    preflight::PreflightCheck,
+};
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Unable to read config file")]
+    IoError(#[from] std::io::Error),
+    #[error("Invalid config file: {0}")]
+    ParseError(#[from] toml::de::Error),
+    #[error("{field} must be between 0 and 1, got {value}")]
+    InvalidFraction { field: &'static str, value: f64 },
+    #[error("{0} output cannot be appended to")]
+    AppendUnsupported(OutputFormat),
+    #[error("--compressor only applies to FASTQ output")]
+    CompressorUnsupported,
+    #[error("--no-empty-undetermined would delete reads earlier runs appended, so it can't be combined with --append-output")]
+    RemoveWhileAppending,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Fastq,
+    /// Unmapped BAM, with barcodes and UMIs as tags
+    #[cfg(feature = "ubam")]
+    Ubam,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Fastq => "fastq",
+            #[cfg(feature = "ubam")]
+            OutputFormat::Ubam => "bam",
+        }
+    }
+}
+
+/// How FASTQ read names are written
+#[derive(ValueEnum, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadNameFormat {
+    /// `@instrument:run:flowcell:lane:tile:x:y[:umi] read:filtered:control:index`
+    #[default]
+    Casava18,
+    /// `@instrument:lane:tile:x:y#index/read`, without UMIs
+    Classic,
+}
+
+impl ReadNameFormat {
+    pub fn formatter(&self) -> Arc<dyn ReadNameFormatter> {
+        match self {
+            ReadNameFormat::Casava18 => Arc::new(Casava18ReadName),
+            ReadNameFormat::Classic => Arc::new(ClassicReadName),
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Fastq => write!(f, "FASTQ"),
+            #[cfg(feature = "ubam")]
+            OutputFormat::Ubam => write!(f, "uBAM"),
+        }
+    }
+}
+
+/// Demux knobs shared by the CLI and `--config`
+///
+/// Keys in the config file are the long flag names, e.g.
+/// `max-undetermined-fraction = 0.5`. Precedence is CLI over config file over
+/// defaults: a value given on the command line always wins, and a switch such as
+/// `append-output` is on if either source turns it on.
+#[derive(Args, Deserialize, Debug, Default, Clone, PartialEq)]
+#[command(about = None, long_about = None)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DemuxOptions {
+    /// Fail if more than this fraction of reads are undetermined (outputs are still written)
+    #[arg(long, value_name = "FRACTION", value_parser = parse_fraction, default_value = None)]
+    pub max_undetermined_fraction: Option<f64>,
+
+    /// Drop reads whose mean quality is below this, after pass-filter
+    #[arg(long, value_name = "PHRED", default_value = None)]
+    pub min_mean_quality: Option<f64>,
+
+    /// Drop reads where more than this fraction of calls are N, after pass-filter
+    #[arg(long, value_name = "FRACTION", value_parser = parse_fraction, default_value = None)]
+    pub max_n_fraction: Option<f64>,
+
+    /// Trim trailing cycles that are almost entirely N from every read
+    #[arg(long, default_value_t = false)]
+    pub trim_dark_cycles: bool,
+
+    /// Fraction of N calls above which a cycle counts as dark [default: 0.95]
+    #[arg(long, value_name = "FRACTION", value_parser = parse_fraction, default_value = None)]
+    pub dark_cycle_threshold: Option<f64>,
+
+    /// Only decode tiles whose non-PF clusters were, or were not, already excluded [default: all]
+    #[arg(long, value_enum, value_name = "TILES", default_value = None)]
+    pub pf_tiles: Option<PfTileFilter>,
+
+    /// Read each tile's compressed block on a background thread while the previous tile decodes
+    #[arg(long, default_value_t = false)]
+    pub prefetch: bool,
+
+    /// Log and leave out tiles that fail to decode instead of aborting, listing them in the report
+    #[arg(long, default_value_t = false)]
+    pub skip_corrupt_tiles: bool,
+
+    /// Resolve clusters on a pool of this many threads rather than rayon's global pool
+    #[arg(long, value_name = "THREADS", value_parser = value_parser!(u16).range(1..), default_value = None)]
+    pub threads: Option<u16>,
+
+    /// Output file format [default: fastq]
+    #[arg(long, value_enum, default_value = None)]
+    pub output_format: Option<OutputFormat>,
+
+    /// Format of FASTQ read names [default: casava18]
+    #[arg(long, value_enum, default_value = None)]
+    pub read_name_format: Option<ReadNameFormat>,
+
+    /// Append to existing FASTQ outputs rather than overwriting them
+    #[arg(long, default_value_t = false)]
+    pub append_output: bool,
+
+    /// Write a BCL Convert-compatible Reports/fastq_list.csv, splitting outputs by lane as BCL Convert does,
+    /// and Reports/fastq_manifest.tsv with each output's read count and index FASTQ
+    #[arg(long, default_value_t = false)]
+    pub fastq_list: bool,
+
+    /// Flush FASTQ outputs at least this often (seconds) so a crash leaves usable partial files
+    #[arg(long, value_name = "SECONDS", default_value = None)]
+    pub flush_interval: Option<u64>,
+
+    /// Number samples in output filenames from this offset, e.g. 10 starts at S11
+    #[arg(long, value_name = "OFFSET", default_value = None)]
+    pub sample_number_offset: Option<u32>,
+
+    /// CSV of `Sample_ID,number` pinning output sample numbers, others are numbered positionally
+    #[arg(long, value_name = "CSV", default_value = None)]
+    pub sample_numbers: Option<PathBuf>,
+
+    /// Decode only this fraction of clusters per tile, for quick QC (output is not fit for analysis)
+    #[arg(long, value_name = "FRACTION", value_parser = parse_fraction, default_value = None)]
+    pub downsample_rate: Option<f64>,
+
+    /// Seed choosing which clusters --downsample-rate keeps [default: 0]
+    #[arg(long, value_name = "SEED", default_value = None)]
+    pub downsample_seed: Option<u64>,
+
+    /// Reverse-complement a template read, e.g. R2 (repeatable)
+    #[arg(long, value_name = "READ", value_parser = parse_template_read)]
+    pub rc_read: Vec<u8>,
+
+    /// Pipe each FASTQ through this gzip compressor, e.g. "pigz -p 8", writing .fastq.gz
+    #[arg(long, value_name = "COMMAND", default_value = None)]
+    pub compressor: Option<String>,
+
+    /// Start decoding without checking cbcls and the output directory first
+    #[arg(long, default_value_t = false)]
+    pub skip_preflight: bool,
+
+    /// Skip a single preflight check (repeatable)
+    #[arg(long, value_enum, value_name = "CHECK")]
+    pub skip_check: Vec<PreflightCheck>,
+
+    /// Don't leave empty Undetermined outputs behind when every read was assigned a sample
+    #[arg(long, default_value_t = false)]
+    pub no_empty_undetermined: bool,
+
+    /// Decode only index cycles and report clusters per sample, writing no FASTQs
+    #[arg(long, default_value_t = false)]
+    pub index_only: bool,
+
+    /// Decode only these run cycles, e.g. 1-10, for a quick preview
+    ///
+    /// Reads are cut to the range, and the sample sheet's [Reads] and
+    /// OverrideCycles are not applied.
+    #[arg(long, value_name = "START-END", default_value = None)]
+    pub cycles: Option<CycleRange>,
+
+    /// Write Prometheus metrics for the run to this file once it finishes
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_name = "FILE", default_value = None)]
+    pub metrics_file: Option<PathBuf>,
+}
+
+impl DemuxOptions {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Fill in anything not given on the command line from `file`
+    pub fn merge(self, file: DemuxOptions) -> DemuxOptions {
+        DemuxOptions {
+            max_undetermined_fraction: self
+                .max_undetermined_fraction
+                .or(file.max_undetermined_fraction),
+            min_mean_quality: self.min_mean_quality.or(file.min_mean_quality),
+            max_n_fraction: self.max_n_fraction.or(file.max_n_fraction),
+            trim_dark_cycles: self.trim_dark_cycles || file.trim_dark_cycles,
+            dark_cycle_threshold: self.dark_cycle_threshold.or(file.dark_cycle_threshold),
+            pf_tiles: self.pf_tiles.or(file.pf_tiles),
+            prefetch: self.prefetch || file.prefetch,
+            skip_corrupt_tiles: self.skip_corrupt_tiles || file.skip_corrupt_tiles,
+            threads: self.threads.or(file.threads),
+            output_format: self.output_format.or(file.output_format),
+            read_name_format: self.read_name_format.or(file.read_name_format),
+            append_output: self.append_output || file.append_output,
+            fastq_list: self.fastq_list || file.fastq_list,
+            flush_interval: self.flush_interval.or(file.flush_interval),
+            sample_number_offset: self.sample_number_offset.or(file.sample_number_offset),
+            sample_numbers: self.sample_numbers.or(file.sample_numbers),
+            downsample_rate: self.downsample_rate.or(file.downsample_rate),
+            downsample_seed: self.downsample_seed.or(file.downsample_seed),
+            rc_read: if self.rc_read.is_empty() {
+                file.rc_read
+            } else {
+                self.rc_read
+            },
+            compressor: self.compressor.or(file.compressor),
+            skip_preflight: self.skip_preflight || file.skip_preflight,
+            no_empty_undetermined: self.no_empty_undetermined || file.no_empty_undetermined,
+            index_only: self.index_only || file.index_only,
+            cycles: self.cycles.or(file.cycles),
+            #[cfg(feature = "metrics")]
+            metrics_file: self.metrics_file.or(file.metrics_file),
+            skip_check: if self.skip_check.is_empty() {
+                file.skip_check
+            } else {
+                self.skip_check
+            },
+        }
+    }
+
+    /// Check the final options, after merging
+    ///
+    /// clap checks ranges as it parses, but values from a config file need checking here.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (field, value) in [
+            ("max-undetermined-fraction", self.max_undetermined_fraction),
+            ("max-n-fraction", self.max_n_fraction),
+            ("dark-cycle-threshold", self.dark_cycle_threshold),
+            ("downsample-rate", self.downsample_rate),
+        ] {
+            if let Some(value) = value {
+                if !(0.0..=1.0).contains(&value) {
+                    return Err(ConfigError::InvalidFraction { field, value });
+                }
+            }
+        }
+        let format = self.output_format.unwrap_or_default();
+        // a BAM has a single header, so appended files would not be valid
+        if self.append_output && format != OutputFormat::Fastq {
+            return Err(ConfigError::AppendUnsupported(format));
+        }
+        if self.compressor.is_some() && format != OutputFormat::Fastq {
+            return Err(ConfigError::CompressorUnsupported);
+        }
+        // emptiness is judged by this run's stats, not by what the file already held
+        if self.append_output && self.no_empty_undetermined {
+            return Err(ConfigError::RemoveWhileAppending);
+        }
+        Ok(())
+    }
+}
+
+/// `R2` or `2` is template read 2
+fn parse_template_read(s: &str) -> Result<u8, String> {
+    match s.strip_prefix('R').unwrap_or(s).parse::<u8>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("{s} is not a template read such as R1 or R2")),
+    }
+}
+
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Ok(f),
+        Ok(f) => Err(format!("{f} is not between 0 and 1")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appending_keeps_undetermined_outputs() {
+        let options = DemuxOptions {
+            append_output: true,
+            no_empty_undetermined: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            options.validate(),
+            Err(ConfigError::RemoveWhileAppending)
+        ));
+        assert!(DemuxOptions {
+            no_empty_undetermined: true,
+            ..Default::default()
+        }
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    fn cycle_ranges_come_from_the_config_file() {
+        let file: DemuxOptions = toml::from_str("cycles = \"1-10\"").unwrap();
+        let options = DemuxOptions::default().merge(file);
+        assert_eq!(options.cycles, Some("1-10".parse().unwrap()));
+        assert!(toml::from_str::<DemuxOptions>("cycles = \"10-1\"").is_err());
+    }
+
+    #[test]
+    fn read_name_format_comes_from_the_config_file() {
+        let file: DemuxOptions = toml::from_str("read-name-format = \"classic\"").unwrap();
+        let options = DemuxOptions::default().merge(file);
+        assert_eq!(options.read_name_format, Some(ReadNameFormat::Classic));
+        assert!(toml::from_str::<DemuxOptions>("read-name-format = \"casava\"").is_err());
+    }
+}
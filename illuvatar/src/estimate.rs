@@ -0,0 +1,140 @@
+// Rough sizing of demux output, for checking a destination has room before
+// starting. Estimates are only meant to be within about 30%.
+
+use crate::assemble::layout::{ReadLayout, SegmentKind};
+
+/// Compressed size as a fraction of uncompressed FASTQ
+///
+/// gzip at its default level typically shrinks Illumina FASTQ to 25-35% of its
+/// size. Binned qualities compress better than full-resolution ones, and
+/// libraries with many identical reads (e.g. amplicons) compress much better,
+/// so this is a middle-of-the-road figure.
+pub const DEFAULT_GZIP_RATIO: f64 = 0.3;
+
+/// Bytes of a FASTQ record that do not scale with read length: the
+/// `@instrument:run:flowcell:lane:tile:x:y read:filtered:control:index` ID,
+/// the `+` separator, and newlines
+pub const FASTQ_RECORD_OVERHEAD: u64 = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleEstimate {
+    pub sample_id: String,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputEstimate {
+    /// FASTQ bytes written per cluster, before compression
+    pub bytes_per_cluster: u64,
+    pub total_bytes: u64,
+    pub samples: Vec<SampleEstimate>,
+}
+
+/// Estimate compressed FASTQ output for `clusters` clusters
+///
+/// Every written read, see [ReadSpec::is_written](crate::assemble::layout::ReadSpec::is_written),
+/// contributes one record per cluster. `samples` pairs each sample ID with its
+/// expected share of clusters; weights need not sum to one, and equal weights
+/// split output evenly when proportions are unknown. Undetermined reads are
+/// not broken out, so their share should be given as a sample if it matters.
+/// Clusters that fail pass-filter are counted, so this overestimates tiles whose
+/// non-PF clusters were not excluded on the instrument.
+pub fn estimate_output(
+    clusters: u64,
+    layout: &ReadLayout,
+    create_fastq_for_index_reads: bool,
+    samples: &[(String, f64)],
+    gzip_ratio: f64,
+) -> OutputEstimate {
+    let bytes_per_cluster = layout
+        .reads()
+        .iter()
+        .filter(|r| r.is_written(create_fastq_for_index_reads))
+        .map(|r| {
+            let kind = if r.cycles_of(SegmentKind::Template).next().is_some() {
+                SegmentKind::Template
+            } else {
+                SegmentKind::Index
+            };
+            // bases and qualities
+            2 * r.cycles_of(kind).count() as u64 + FASTQ_RECORD_OVERHEAD
+        })
+        .sum::<u64>();
+    let total_bytes = (clusters as f64 * bytes_per_cluster as f64 * gzip_ratio) as u64;
+    let total_weight = samples.iter().map(|(_, w)| w.max(0.0)).sum::<f64>();
+    let samples = samples
+        .iter()
+        .map(|(sample_id, weight)| SampleEstimate {
+            sample_id: sample_id.clone(),
+            bytes: if total_weight > 0.0 {
+                (total_bytes as f64 * weight.max(0.0) / total_weight) as u64
+            } else {
+                0
+            },
+        })
+        .collect();
+    OutputEstimate {
+        bytes_per_cluster,
+        total_bytes,
+        samples,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble::layout::RunRead;
+
+    /// 151 cycle reads around an 8 cycle i7
+    const RUN: [RunRead; 3] = [
+        RunRead {
+            cycles: 151,
+            is_index: false,
+        },
+        RunRead {
+            cycles: 8,
+            is_index: true,
+        },
+        RunRead {
+            cycles: 151,
+            is_index: false,
+        },
+    ];
+
+    #[test]
+    fn output_is_split_by_weight() {
+        let layout = ReadLayout::new(&RUN, None, None).unwrap();
+        let samples = [("A".to_string(), 3.0), ("B".to_string(), 1.0)];
+        let estimate = estimate_output(1000, &layout, false, &samples, 0.5);
+
+        assert_eq!(
+            estimate.bytes_per_cluster,
+            2 * (2 * 151 + FASTQ_RECORD_OVERHEAD)
+        );
+        assert_eq!(estimate.total_bytes, 1000 * 732 / 2);
+        assert_eq!(
+            estimate.samples,
+            vec![
+                SampleEstimate {
+                    sample_id: "A".to_string(),
+                    bytes: 274_500,
+                },
+                SampleEstimate {
+                    sample_id: "B".to_string(),
+                    bytes: 91_500,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn index_fastqs_add_their_reads() {
+        let layout = ReadLayout::new(&RUN, None, None).unwrap();
+        let estimate = estimate_output(10, &layout, true, &[], DEFAULT_GZIP_RATIO);
+        assert_eq!(
+            estimate.bytes_per_cluster,
+            2 * (2 * 151 + FASTQ_RECORD_OVERHEAD) + 2 * 8 + FASTQ_RECORD_OVERHEAD
+        );
+        assert!(estimate.samples.is_empty());
+    }
+}
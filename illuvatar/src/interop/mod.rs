@@ -0,0 +1,302 @@
+// Binary run QC metrics from the InterOp/ directory.
+// Only TileMetricsOut.bin is understood for now, which is enough for
+// the headline per-lane cluster numbers without decoding any base calls.
+
+pub mod parser;
+
+use std::{collections::BTreeMap, fs::File, io::Read, path::Path};
+
+use thiserror::Error;
+
+use parser::TileRecordV3;
+
+/// Where a run keeps its InterOp files, relative to the run directory
+pub const INTEROP_DIR: &str = "InterOp";
+pub const TILE_METRICS_FILE: &str = "TileMetricsOut.bin";
+
+const CODE_CLUSTER_DENSITY: u16 = 100;
+const CODE_PF_CLUSTER_DENSITY: u16 = 101;
+const CODE_CLUSTER_COUNT: u16 = 102;
+const CODE_PF_CLUSTER_COUNT: u16 = 103;
+
+#[derive(Error, Debug)]
+pub enum InteropError {
+    #[error("Error parsing InterOp file")]
+    ParseError {
+        msg: &'static str,
+        code: nom::error::ErrorKind,
+    },
+    #[error("I/O error")]
+    IoError(#[from] std::io::Error),
+    #[error("Unsupported InterOp version {version}")]
+    UnsupportedVersion { version: u8 },
+    #[error("InterOp record size {got} did not match expected size {expected}")]
+    RecordSizeMismatch { expected: u8, got: u8 },
+}
+
+impl From<nom::Err<nom::error::Error<&[u8]>>> for InteropError {
+    fn from(value: nom::Err<nom::error::Error<&[u8]>>) -> Self {
+        match value {
+            nom::Err::Failure(nom::error::Error { input: _, code })
+            | nom::Err::Error(nom::error::Error { input: _, code }) => InteropError::ParseError {
+                msg: "Failed parsing InterOp records",
+                code,
+            },
+            nom::Err::Incomplete(_) => InteropError::ParseError {
+                msg: "Needed more bytes to parse InterOp. File is most likely truncated.",
+                code: nom::error::ErrorKind::Fail,
+            },
+        }
+    }
+}
+
+/// Cluster metrics for a single tile
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TileMetrics {
+    pub lane: u16,
+    pub tile: u32,
+    pub cluster_count: f32,
+    pub pf_cluster_count: f32,
+    /// Clusters per mm^2
+    pub cluster_density: f32,
+    pub pf_cluster_density: f32,
+}
+
+/// Tile metrics summed over a lane
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LaneMetrics {
+    pub lane: u16,
+    pub n_tiles: usize,
+    pub cluster_count: f64,
+    pub pf_cluster_count: f64,
+    /// Mean over tiles, clusters per mm^2
+    pub cluster_density: f64,
+    pub pf_cluster_density: f64,
+}
+
+impl LaneMetrics {
+    pub fn percent_pf(&self) -> f64 {
+        if self.cluster_count > 0.0 {
+            100.0 * self.pf_cluster_count / self.cluster_count
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Read a TileMetricsOut.bin file, supporting versions 2 and 3
+pub fn read_tile_metrics<P: AsRef<Path>>(path: P) -> Result<Vec<TileMetrics>, InteropError> {
+    let mut buffer = Vec::new();
+    File::open(path)?.read_to_end(&mut buffer)?;
+    parse_tile_metrics(&buffer)
+}
+
+pub fn parse_tile_metrics(input: &[u8]) -> Result<Vec<TileMetrics>, InteropError> {
+    let (i, (version, record_size)) = parser::interop_version_and_size(input)?;
+    let mut tiles: BTreeMap<(u16, u32), TileMetrics> = BTreeMap::new();
+    match version {
+        2 => {
+            check_record_size(10, record_size)?;
+            let (_, records) = parser::tile_metrics_v2(i)?;
+            for (lane, tile, code, value) in records {
+                let entry = tiles
+                    .entry((lane, u32::from(tile)))
+                    .or_insert_with(|| TileMetrics {
+                        lane,
+                        tile: u32::from(tile),
+                        ..Default::default()
+                    });
+                match code {
+                    CODE_CLUSTER_DENSITY => entry.cluster_density = value,
+                    CODE_PF_CLUSTER_DENSITY => entry.pf_cluster_density = value,
+                    CODE_CLUSTER_COUNT => entry.cluster_count = value,
+                    CODE_PF_CLUSTER_COUNT => entry.pf_cluster_count = value,
+                    // phasing, prephasing, and alignment codes are per read
+                    _ => {}
+                }
+            }
+        }
+        3 => {
+            check_record_size(15, record_size)?;
+            let (_, (area, records)) = parser::tile_metrics_v3(i)?;
+            for record in records {
+                if let TileRecordV3::Tile(lane, tile, clusters, pf_clusters) = record {
+                    // v3 drops the density codes in favor of a single tile area
+                    let (density, pf_density) = if area > 0.0 {
+                        (clusters / area, pf_clusters / area)
+                    } else {
+                        (0.0, 0.0)
+                    };
+                    tiles.insert(
+                        (lane, tile),
+                        TileMetrics {
+                            lane,
+                            tile,
+                            cluster_count: clusters,
+                            pf_cluster_count: pf_clusters,
+                            cluster_density: density,
+                            pf_cluster_density: pf_density,
+                        },
+                    );
+                }
+            }
+        }
+        version => return Err(InteropError::UnsupportedVersion { version }),
+    }
+    Ok(tiles.into_values().collect())
+}
+
+/// Sum tile metrics into per-lane totals, ordered by lane
+pub fn summarize_lanes(tiles: &[TileMetrics]) -> Vec<LaneMetrics> {
+    let mut lanes: BTreeMap<u16, LaneMetrics> = BTreeMap::new();
+    for tile in tiles {
+        let lane = lanes.entry(tile.lane).or_insert_with(|| LaneMetrics {
+            lane: tile.lane,
+            ..Default::default()
+        });
+        lane.n_tiles += 1;
+        lane.cluster_count += f64::from(tile.cluster_count);
+        lane.pf_cluster_count += f64::from(tile.pf_cluster_count);
+        lane.cluster_density += f64::from(tile.cluster_density);
+        lane.pf_cluster_density += f64::from(tile.pf_cluster_density);
+    }
+    lanes
+        .into_values()
+        .map(|mut lane| {
+            lane.cluster_density /= lane.n_tiles as f64;
+            lane.pf_cluster_density /= lane.n_tiles as f64;
+            lane
+        })
+        .collect()
+}
+
+fn check_record_size(expected: u8, got: u8) -> Result<(), InteropError> {
+    if expected == got {
+        Ok(())
+    } else {
+        Err(InteropError::RecordSizeMismatch { expected, got })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_file(records: &[(u16, u16, u16, f32)]) -> Vec<u8> {
+        let mut bytes = vec![2, 10];
+        for (lane, tile, code, value) in records {
+            bytes.extend_from_slice(&lane.to_le_bytes());
+            bytes.extend_from_slice(&tile.to_le_bytes());
+            bytes.extend_from_slice(&code.to_le_bytes());
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn v3_file(area: f32, records: &[(u16, u32, u8, f32, f32)]) -> Vec<u8> {
+        let mut bytes = vec![3, 15];
+        bytes.extend_from_slice(&area.to_le_bytes());
+        for (lane, tile, code, a, b) in records {
+            bytes.extend_from_slice(&lane.to_le_bytes());
+            bytes.extend_from_slice(&tile.to_le_bytes());
+            bytes.push(*code);
+            bytes.extend_from_slice(&a.to_le_bytes());
+            bytes.extend_from_slice(&b.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn parses_v2_tile_metrics() {
+        let bytes = v2_file(&[
+            (1, 1101, CODE_CLUSTER_COUNT, 1000.0),
+            (1, 1101, CODE_PF_CLUSTER_COUNT, 800.0),
+            (1, 1101, CODE_CLUSTER_DENSITY, 250.0),
+            (1, 1101, CODE_PF_CLUSTER_DENSITY, 200.0),
+            // per-read phasing is ignored
+            (1, 1101, 200, 0.1),
+            (2, 1101, CODE_CLUSTER_COUNT, 500.0),
+        ]);
+        let tiles = parse_tile_metrics(&bytes).unwrap();
+        assert_eq!(
+            tiles,
+            vec![
+                TileMetrics {
+                    lane: 1,
+                    tile: 1101,
+                    cluster_count: 1000.0,
+                    pf_cluster_count: 800.0,
+                    cluster_density: 250.0,
+                    pf_cluster_density: 200.0,
+                },
+                TileMetrics {
+                    lane: 2,
+                    tile: 1101,
+                    cluster_count: 500.0,
+                    ..Default::default()
+                },
+            ]
+        );
+        let lanes = summarize_lanes(&tiles);
+        assert_eq!(lanes.len(), 2);
+        assert_eq!(lanes[0].percent_pf(), 80.0);
+    }
+
+    #[test]
+    fn parses_v3_tile_metrics_and_skips_unknown_codes() {
+        let bytes = v3_file(
+            2.0,
+            &[
+                (1, 11101, b't', 1000.0, 900.0),
+                (1, 11101, b'r', f32::from_bits(1), 95.0),
+                (1, 11102, b'?', 1.0, 1.0),
+            ],
+        );
+        assert_eq!(
+            parse_tile_metrics(&bytes).unwrap(),
+            vec![TileMetrics {
+                lane: 1,
+                tile: 11101,
+                cluster_count: 1000.0,
+                pf_cluster_count: 900.0,
+                cluster_density: 500.0,
+                pf_cluster_density: 450.0,
+            }]
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_record_sizes_and_versions() {
+        let mut bytes = v3_file(2.0, &[]);
+        bytes[1] = 16;
+        assert!(matches!(
+            parse_tile_metrics(&bytes),
+            Err(InteropError::RecordSizeMismatch {
+                expected: 15,
+                got: 16
+            })
+        ));
+        let mut bytes = v2_file(&[]);
+        bytes[0] = 4;
+        assert!(matches!(
+            parse_tile_metrics(&bytes),
+            Err(InteropError::UnsupportedVersion { version: 4 })
+        ));
+    }
+
+    #[test]
+    fn truncated_trailing_record_is_a_parse_error() {
+        let mut bytes = v2_file(&[(1, 1101, CODE_CLUSTER_COUNT, 1000.0)]);
+        bytes.extend_from_slice(&[1, 0, 0x4d]);
+        assert!(matches!(
+            parse_tile_metrics(&bytes),
+            Err(InteropError::ParseError { .. })
+        ));
+        let mut bytes = v3_file(2.0, &[(1, 11101, b't', 1000.0, 900.0)]);
+        bytes.truncate(bytes.len() - 2);
+        assert!(matches!(
+            parse_tile_metrics(&bytes),
+            Err(InteropError::ParseError { .. })
+        ));
+    }
+}
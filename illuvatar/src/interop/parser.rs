@@ -0,0 +1,131 @@
+use nom::{
+    combinator::{all_consuming, map},
+    multi::many0,
+    number::complete::{le_f32, le_u16, le_u32, le_u8},
+    sequence::{pair, tuple},
+    IResult,
+};
+
+/// Version and record size
+/// Every InterOp file starts with these two bytes.
+pub(crate) fn interop_version_and_size(input: &[u8]) -> IResult<&[u8], (u8, u8)> {
+    pair(le_u8, le_u8)(input)
+}
+
+/// 10 bytes each
+/// Version 2 records are one (code, value) pair per tile
+pub(crate) fn tile_metric_v2(input: &[u8]) -> IResult<&[u8], (u16, u16, u16, f32)> {
+    tuple((
+        le_u16, // lane (0-1)
+        le_u16, // tile (2-3)
+        le_u16, // metric code (4-5)
+        le_f32, // metric value (6-9)
+    ))(input)
+}
+
+pub(crate) fn tile_metrics_v2(input: &[u8]) -> IResult<&[u8], Vec<(u16, u16, u16, f32)>> {
+    all_consuming(many0(tile_metric_v2))(input)
+}
+
+/// Version 3 records come in two flavors, selected by a code byte
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TileRecordV3 {
+    /// (lane, tile, cluster count, PF cluster count)
+    Tile(u16, u32, f32, f32),
+    /// (lane, tile, read number, percent aligned)
+    Read(u16, u32, u32, f32),
+    /// Codes we don't know how to interpret
+    Unknown(u16, u32, u8),
+}
+
+/// 15 bytes each
+pub(crate) fn tile_metric_v3(input: &[u8]) -> IResult<&[u8], TileRecordV3> {
+    let (i, (lane, tile, code)) = tuple((le_u16, le_u32, le_u8))(input)?;
+    match code {
+        b't' => map(pair(le_f32, le_f32), |(clusters, pf_clusters)| {
+            TileRecordV3::Tile(lane, tile, clusters, pf_clusters)
+        })(i),
+        b'r' => map(pair(le_u32, le_f32), |(read, aligned)| {
+            TileRecordV3::Read(lane, tile, read, aligned)
+        })(i),
+        // records are fixed width, so we can skip what we don't understand
+        _ => map(pair(le_u32, le_u32), |_| {
+            TileRecordV3::Unknown(lane, tile, code)
+        })(i),
+    }
+}
+
+/// Version 3 adds the tile area before the records
+pub(crate) fn tile_metrics_v3(input: &[u8]) -> IResult<&[u8], (f32, Vec<TileRecordV3>)> {
+    all_consuming(pair(le_f32, many0(tile_metric_v3)))(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v3_record(lane: u16, tile: u32, code: u8, a: [u8; 4], b: [u8; 4]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(15);
+        bytes.extend_from_slice(&lane.to_le_bytes());
+        bytes.extend_from_slice(&tile.to_le_bytes());
+        bytes.push(code);
+        bytes.extend_from_slice(&a);
+        bytes.extend_from_slice(&b);
+        bytes
+    }
+
+    #[test]
+    fn parses_v2_records() {
+        let mut bytes = Vec::new();
+        for (lane, tile, code, value) in [(1u16, 1101u16, 102u16, 1000.0f32), (1, 1101, 103, 900.0)]
+        {
+            bytes.extend_from_slice(&lane.to_le_bytes());
+            bytes.extend_from_slice(&tile.to_le_bytes());
+            bytes.extend_from_slice(&code.to_le_bytes());
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        let (rest, records) = tile_metrics_v2(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(records, vec![(1, 1101, 102, 1000.0), (1, 1101, 103, 900.0)]);
+    }
+
+    #[test]
+    fn parses_v3_records() {
+        let mut bytes = 2.5f32.to_le_bytes().to_vec();
+        bytes.extend(v3_record(
+            2,
+            11101,
+            b't',
+            100.0f32.to_le_bytes(),
+            80.0f32.to_le_bytes(),
+        ));
+        bytes.extend(v3_record(
+            2,
+            11101,
+            b'r',
+            1u32.to_le_bytes(),
+            95.5f32.to_le_bytes(),
+        ));
+        bytes.extend(v3_record(2, 11101, b'x', [0; 4], [0; 4]));
+        let (rest, (area, records)) = tile_metrics_v3(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(area, 2.5);
+        assert_eq!(
+            records,
+            vec![
+                TileRecordV3::Tile(2, 11101, 100.0, 80.0),
+                TileRecordV3::Read(2, 11101, 1, 95.5),
+                TileRecordV3::Unknown(2, 11101, b'x'),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_trailing_record_is_an_error() {
+        let mut bytes = 2.5f32.to_le_bytes().to_vec();
+        bytes.extend(v3_record(1, 1101, b't', [0; 4], [0; 4]));
+        bytes.extend_from_slice(&[1, 0, 0x4d]);
+        assert!(tile_metrics_v3(&bytes).is_err());
+        assert!(tile_metrics_v2(&[1, 0, 0x4d, 0x04, 102, 0]).is_err());
+    }
+}
@@ -1,15 +1,23 @@
 use std::io::Write;
 use std::path::Path;
-use std::{fs::OpenOptions, io::stdout};
+use std::{
+    fs::OpenOptions,
+    io::{stderr, stdout},
+};
 
 use slog::{o, Drain, Level, Logger};
 use slog_async::{self};
 use slog_scope::{self, GlobalLoggerGuard};
 use slog_term;
 
+/// Log to `log_path`, or without one to stdout, or stderr if `stdout_is_output`
+///
+/// Set `stdout_is_output` when stdout carries data, e.g. FASTQ from `decode`,
+/// so log lines don't end up in it.
 pub fn init_logger<P: AsRef<Path>>(
     log_path: Option<P>,
     verbosity: u8,
+    stdout_is_output: bool,
 ) -> Result<GlobalLoggerGuard, std::io::Error> {
     let log_file: Box<dyn Write + Send> = match log_path {
         Some(p) => Box::new(
@@ -19,6 +27,7 @@ pub fn init_logger<P: AsRef<Path>>(
                 .truncate(true)
                 .open(p)?,
         ),
+        None if stdout_is_output => Box::new(stderr()),
         None => Box::new(stdout()),
     };
     let log_decorator = slog_term::PlainDecorator::new(log_file);
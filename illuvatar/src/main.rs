@@ -1,21 +1,69 @@
 pub(crate) mod accumulator;
+pub(crate) mod assemble;
 pub(crate) mod bcl;
+pub(crate) mod config;
+pub(crate) mod estimate;
+pub(crate) mod interop;
 pub(crate) mod logging;
+pub(crate) mod manager;
+#[cfg(feature = "metrics")]
+pub(crate) mod metrics;
+pub(crate) mod preflight;
+pub(crate) mod resolve;
+pub(crate) mod runinfo;
+pub(crate) mod sheet;
 
-use std::sync::OnceLock;
-use std::{path::PathBuf, process};
+use std::sync::{Arc, OnceLock};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
+};
 
-use clap::{arg, command, value_parser, Parser};
-use slog::{slog_error, slog_info, slog_o};
+use clap::{arg, command, value_parser, Parser, Subcommand};
+use slog::{slog_debug, slog_error, slog_info, slog_o, slog_warn};
 use slog_scope;
 
+use accumulator::{DarkCycleTrims, DemuxReport, DroppedReads, ReadLengthStats};
+use assemble::{
+    layout::{ReadLayout, RunRead},
+    ReadFilter, DEFAULT_DARK_CYCLE_THRESHOLD,
+};
+use bcl::{
+    reader::{ReaderOptions, SkippedTile},
+    Downsample,
+};
+use config::DemuxOptions;
+use manager::{
+    progress::{ProgressCounters, ProgressReporter},
+    reader::{LaneTask, ReadError, ReaderPool, MAX_READERS},
+    writer::{
+        data_to_writers, remove_empty_undetermined, FlushPolicy, SampleNumbering, WriteRouter,
+        WriterOptions,
+    },
+    DemuxManager,
+};
+use resolve::{Resolver, Sample};
+use runinfo::RunInfo;
 use samplesheet::{reader, SampleSheet};
 use seqdir::{SeqDir, SequencingDirectory};
+use sheet::RawSheet;
 
 use thiserror::Error;
 
 static SAMPLESHEET: OnceLock<SampleSheet> = OnceLock::new();
 
+/// Tiles queued between the readers and the demux workers, each holding every cycle
+const DEMUX_CAP: usize = 2;
+/// Records queued for the router, and for each writer
+const WRITER_CAP: usize = 4096;
+/// Async worker threads of the router, writers run on blocking threads of their own
+const ROUTER_THREADS: usize = 2;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
 #[derive(Debug, Error)]
 pub enum IlluvatarError {
     #[error(transparent)]
@@ -23,18 +71,93 @@ pub enum IlluvatarError {
     #[error(transparent)]
     SeqDirError(#[from] seqdir::SeqDirError),
     #[error(transparent)]
+    SheetError(#[from] sheet::SheetError),
+    #[error(transparent)]
     IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    BclError(#[from] bcl::BclError),
+    #[error(transparent)]
+    ConfigError(#[from] config::ConfigError),
+    #[error(transparent)]
+    AssembleError(#[from] assemble::AssembleError),
+    #[error(transparent)]
+    LayoutError(#[from] assemble::layout::LayoutError),
+    #[error(transparent)]
+    RunInfoError(#[from] runinfo::RunInfoError),
+    #[error(transparent)]
+    ResolveError(#[from] resolve::ResolveError),
+    #[error(transparent)]
+    ReadError(#[from] manager::reader::ReadError),
+    #[error(transparent)]
+    RouteError(#[from] manager::writer::RouteError),
+    #[error(transparent)]
+    NumberingError(#[from] manager::writer::NumberingError),
+    #[error(transparent)]
+    InteropError(#[from] interop::InteropError),
+    #[error(transparent)]
+    Pool(#[from] rayon::ThreadPoolBuildError),
+    #[error("{undetermined} of {total} reads were undetermined, exceeding the maximum fraction of {max}")]
+    TooManyUndetermined {
+        undetermined: u64,
+        total: u64,
+        max: f64,
+    },
+    #[error("Scan found {failed} cbcl tiles or headers that failed to decode")]
+    ScanFailed { failed: usize },
+    #[error("Preflight found {failed} problems, see above or pass --skip-preflight")]
+    PreflightFailed { failed: usize },
+    #[error("Compressor `{command}` failed: {reason}")]
+    CompressorFailed { command: String, reason: String },
+    #[error("--index-only needs index reads, but the run has none")]
+    NoIndexCycles,
     #[error("")]
     Noop,
 }
 
 fn illuvatar(args: Illuvatar) -> Result<(), IlluvatarError> {
-    let path = args.input;
+    match args.command {
+        Some(Command::Decode { file, output }) => return decode(file, output),
+        Some(Command::Scan { input }) => return scan(input),
+        Some(Command::Interop { input }) => return interop_summary(input),
+        None => {}
+    }
+    let path = args
+        .input
+        .expect("clap requires --input without a subcommand");
+    let options = match args.config {
+        Some(config) => args.options.merge(DemuxOptions::from_file(config)?),
+        None => args.options,
+    };
+    options.validate()?;
+    slog_debug!(slog_scope::logger(), "Demux options: {:?}", options);
     let seq_dir = slog_scope::scope(
         &slog_scope::logger().new(slog_o!("scope" => "SeqDir")),
-        || SeqDir::from_path(path),
+        || SeqDir::from_path(&path),
     )?;
 
+    let run_info = RunInfo::from_path(path.join(runinfo::RUN_INFO))?;
+    // tag everything from here on, so runs can be told apart in a shared log
+    slog_scope::scope(
+        &slog_scope::logger().new(slog_o!(
+            "run" => run_info.run_id().to_string(),
+            "flowcell" => run_info.flowcell().to_string(),
+        )),
+        || {
+            let output = args
+                .output
+                .expect("clap requires --output without a subcommand");
+            demux(&seq_dir, &path, &output, &run_info, &options)
+        },
+    )
+}
+
+fn demux(
+    seq_dir: &SeqDir,
+    path: &Path,
+    output: &Path,
+    run_info: &RunInfo,
+    options: &DemuxOptions,
+) -> Result<(), IlluvatarError> {
     slog_scope::scope(
         &slog_scope::logger().new(slog_o!("scope" => "SampleSheet")),
         || -> Result<(), IlluvatarError> {
@@ -50,35 +173,705 @@ fn illuvatar(args: Illuvatar) -> Result<(), IlluvatarError> {
         "Initialized samplesheet version {:?}",
         SAMPLESHEET.get().unwrap().version()
     );
+    // the sample sheet crate doesn't keep [Reads], OverrideCycles, or Library_ID
+    let raw_sheet = RawSheet::from_path(seq_dir.samplesheet()?)?;
+    let mut layout = match options.cycles {
+        Some(range) => {
+            slog_info!(
+                slog_scope::logger(),
+                "Decoding only cycles {}, ignoring the sample sheet's [Reads] and OverrideCycles",
+                range
+            );
+            ReadLayout::new(&range.restrict(run_info.reads())?, None, None)?
+        }
+        None => ReadLayout::new(
+            run_info.reads(),
+            raw_sheet.reads()?.as_deref(),
+            raw_sheet.override_cycles()?.as_deref(),
+        )?,
+    };
+    for template_read in options.rc_read.iter() {
+        layout.set_reverse_complement(*template_read)?;
+    }
+
+    if !options.skip_preflight {
+        slog_scope::scope(
+            &slog_scope::logger().new(slog_o!("scope" => "Preflight")),
+            || -> Result<(), IlluvatarError> {
+                let samplesheet = SAMPLESHEET
+                    .get()
+                    .expect("the sample sheet is read before preflight");
+                let samples = samplesheet
+                    .data()
+                    .iter()
+                    .map(Sample::from)
+                    .collect::<Vec<Sample>>();
+                run_preflight(
+                    path,
+                    Some(output),
+                    run_info,
+                    raw_sheet.reads()?.as_deref(),
+                    &samples,
+                    &options.skip_check,
+                )?;
+                log_output_estimate(
+                    path,
+                    &layout,
+                    &samples,
+                    samplesheet.settings().create_fastq_for_index_reads,
+                );
+                Ok(())
+            },
+        )?;
+    }
+
+    slog_scope::scope(
+        &slog_scope::logger().new(slog_o!("scope" => "Demux")),
+        || run_demux(path, output, run_info, &raw_sheet, &layout, options),
+    )
+}
+
+/// Read every lane, resolve its clusters to samples, and write them out
+fn run_demux(
+    seq_dir: &Path,
+    output: &Path,
+    run_info: &RunInfo,
+    raw_sheet: &RawSheet,
+    layout: &ReadLayout,
+    options: &DemuxOptions,
+) -> Result<(), IlluvatarError> {
+    let start = Instant::now();
+    let samplesheet = SAMPLESHEET
+        .get()
+        .expect("the sample sheet is read before demux");
+
+    let mut tasks = Vec::new();
+    for (lane, lane_dir) in bcl::integrity::declared_lane_dirs(seq_dir, run_info.lane_count())? {
+        let mut cycles = bcl::integrity::lane_cycle_bcls(&lane_dir)?;
+        if let Some(range) = options.cycles {
+            // a lane short of cycles is reported as MissingCycles when its tiles are resolved
+            cycles = cycles
+                .into_iter()
+                .skip(range.indices().start)
+                .take(range.indices().len())
+                .collect();
+        }
+        tasks.push(LaneTask {
+            lane,
+            lane_dir,
+            cycles,
+        });
+    }
+    let lanes = tasks.iter().map(|t| t.lane).collect::<Vec<u8>>();
+    let samples = samplesheet
+        .data()
+        .iter()
+        .map(Sample::from)
+        .collect::<Vec<Sample>>();
+    let instrument = runinfo::resolve_instrument(Some(run_info), samplesheet.header());
+    slog_info!(
+        slog_scope::logger(),
+        "Naming reads after instrument {:?} from {:?}",
+        instrument.name,
+        instrument.source
+    );
+    let mut resolver = Resolver::new(
+        run_info,
+        &instrument.name,
+        layout,
+        &samples,
+        &lanes,
+        samplesheet.settings().create_fastq_for_index_reads,
+    )?;
+    resolver.set_read_filter(ReadFilter {
+        min_mean_quality: options.min_mean_quality,
+        max_n_fraction: options.max_n_fraction,
+    });
+    if options.trim_dark_cycles {
+        resolver.set_dark_cycle_threshold(
+            options
+                .dark_cycle_threshold
+                .unwrap_or(DEFAULT_DARK_CYCLE_THRESHOLD),
+        );
+    }
+
+    fs::create_dir_all(output)?;
+    if options.index_only {
+        return count_indexes(output, run_info, tasks, &resolver, options, start);
+    }
+    let (mut router, write_send) = WriteRouter::new(WRITER_CAP, ROUTER_THREADS)?;
+    let writer_options = WriterOptions {
+        cap: WRITER_CAP,
+        flush_policy: options.flush_interval.map_or(FlushPolicy::AtEnd, |secs| {
+            FlushPolicy::Interval(Duration::from_secs(secs))
+        }),
+        append: options.append_output,
+        lanes: lanes.clone(),
+        // fastq_list.csv lists a pair of FASTQs per lane
+        split_lanes: options.fastq_list,
+        template_reads: layout.template_reads().count() as u8,
+        format: options.output_format.unwrap_or_default(),
+        numbering: sample_numbering(options)?,
+        compressor: options.compressor.clone(),
+        projects: raw_sheet.data_column("Sample_Project"),
+    };
+    let outputs = data_to_writers(
+        &mut router,
+        samplesheet.data(),
+        samplesheet.settings(),
+        output,
+        &writer_options,
+    )?;
+
+    let n_readers = tasks.len().clamp(1, MAX_READERS) as u8;
+    let progress = Arc::new(ProgressCounters::default());
+    let (manager, readers, _reporter) = demux_pipeline(run_info, tasks, progress.clone(), options)?;
+
+    let (read, resolved, routed) = thread::scope(|s| {
+        let reading = s.spawn(move || read_all(readers, n_readers));
+        let resolving = s.spawn(|| manager.resolve(&resolver, write_send));
+        let routed = router.route();
+        (
+            reading
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e)),
+            resolving
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e)),
+            routed,
+        )
+    });
+    // a failure downstream makes everything upstream of it fail to send,
+    // so report the furthest downstream error
+    routed?;
+    let resolved = resolved?;
+    if resolved.dropped.total() > 0 {
+        slog_info!(
+            slog_scope::logger(),
+            "Dropped {} clusters below --min-mean-quality and {} above --max-n-fraction",
+            resolved.dropped.low_mean_quality,
+            resolved.dropped.high_n_content
+        );
+    }
+    if resolved.dark_cycles.reads > 0 {
+        slog_info!(
+            slog_scope::logger(),
+            "Trimmed {} dark bases from {} reads",
+            resolved.dark_cycles.bases,
+            resolved.dark_cycles.reads
+        );
+    }
+    let skipped_tiles = read?;
+    if !skipped_tiles.is_empty() {
+        slog_warn!(
+            slog_scope::logger(),
+            "Skipped {} tiles that failed to decode, their clusters are missing from the outputs",
+            skipped_tiles.len()
+        );
+    }
+
+    if options.no_empty_undetermined {
+        remove_empty_undetermined(&outputs, router.stats())?;
+    }
+
+    if options.fastq_list {
+        let libraries = raw_sheet.data_column("Library_ID");
+        let fastq_list = manager::manifest::write_fastq_list(output, &outputs, &lanes, &libraries)?;
+        slog_info!(
+            slog_scope::logger(),
+            "Wrote FASTQ list to {}",
+            fastq_list.display()
+        );
+        let manifest = manager::manifest::write_fastq_manifest(output, &outputs, router.stats())?;
+        slog_info!(
+            slog_scope::logger(),
+            "Wrote FASTQ manifest to {}",
+            manifest.display()
+        );
+    }
+    let report = manager::manifest::write_demux_report(
+        output,
+        &DemuxReport {
+            stats: router.stats(),
+            read_lengths: router.read_lengths(),
+            skipped_tiles: &skipped_tiles,
+            dropped: resolved.dropped,
+            dark_cycles: resolved.dark_cycles,
+        },
+    )?;
+    slog_info!(
+        slog_scope::logger(),
+        "Wrote demux report to {}",
+        report.display()
+    );
+    slog_info!(
+        slog_scope::logger(),
+        "Demultiplexed {} lanes in {:.1?}",
+        lanes.len(),
+        start.elapsed()
+    );
+    #[cfg(feature = "metrics")]
+    write_metrics(options, router.stats().run(), "_R1", &progress, start)?;
+    // outputs are complete by now, a sample sheet disaster still fails the run
+    let run = router.stats().run();
+    accumulator::check_undetermined_fraction(
+        run.undetermined(),
+        run.total(),
+        options.max_undetermined_fraction,
+    )
+}
+
+/// Count clusters per sample from index cycles alone, writing only the demux report
+///
+/// This is the quickest check that the sample sheet matches what was sequenced.
+fn count_indexes(
+    output: &Path,
+    run_info: &RunInfo,
+    mut tasks: Vec<LaneTask>,
+    resolver: &Resolver,
+    options: &DemuxOptions,
+    start: Instant,
+) -> Result<(), IlluvatarError> {
+    let index_cycles = resolver.index_cycles();
+    if index_cycles.is_empty() {
+        return Err(IlluvatarError::NoIndexCycles);
+    }
+    let lanes = tasks.iter().map(|t| t.lane).collect::<Vec<u8>>();
+    for task in tasks.iter_mut() {
+        let mut cycles = std::mem::take(&mut task.cycles);
+        // a lane short of cycles is reported as MissingCycles when its tiles are counted
+        task.cycles = index_cycles
+            .iter()
+            .filter_map(|c| cycles.get_mut(*c).map(std::mem::take))
+            .collect();
+    }
+
+    let n_readers = tasks.len().clamp(1, MAX_READERS) as u8;
+    let progress = Arc::new(ProgressCounters::default());
+    let (manager, readers, _reporter) = demux_pipeline(run_info, tasks, progress.clone(), options)?;
+    let (read, counted) = thread::scope(|s| {
+        let reading = s.spawn(move || read_all(readers, n_readers));
+        let counted = manager.count(resolver);
+        (
+            reading
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e)),
+            counted,
+        )
+    });
+    let stats = counted?;
+    let skipped_tiles = read?;
+    if !skipped_tiles.is_empty() {
+        slog_warn!(
+            slog_scope::logger(),
+            "Skipped {} tiles that failed to decode, their clusters are missing from the counts",
+            skipped_tiles.len()
+        );
+    }
+    for (sample, n) in stats.run().per_destination.iter() {
+        slog_info!(slog_scope::logger(), "{}: {} clusters", sample, n);
+    }
+
+    let report = manager::manifest::write_demux_report(
+        output,
+        &DemuxReport {
+            stats: &stats,
+            read_lengths: &ReadLengthStats::default(),
+            skipped_tiles: &skipped_tiles,
+            dropped: DroppedReads::default(),
+            dark_cycles: DarkCycleTrims::default(),
+        },
+    )?;
+    slog_info!(
+        slog_scope::logger(),
+        "Wrote demux report to {}",
+        report.display()
+    );
+    slog_info!(
+        slog_scope::logger(),
+        "Counted indexes of {} lanes in {:.1?}",
+        lanes.len(),
+        start.elapsed()
+    );
+    #[cfg(feature = "metrics")]
+    write_metrics(options, stats.run(), "", &progress, start)?;
+    let run = stats.run();
+    accumulator::check_undetermined_fraction(
+        run.undetermined(),
+        run.total(),
+        options.max_undetermined_fraction,
+    )
+}
+
+/// Write Prometheus metrics of a finished run to `--metrics-file`, if given
+///
+/// `suffix` picks the destination each sample's reads are counted from, see
+/// [MetricsSnapshot::from_demux](metrics::MetricsSnapshot::from_demux).
+#[cfg(feature = "metrics")]
+fn write_metrics(
+    options: &DemuxOptions,
+    counts: &accumulator::DemuxCounts,
+    suffix: &str,
+    progress: &ProgressCounters,
+    start: Instant,
+) -> Result<(), IlluvatarError> {
+    if let Some(path) = &options.metrics_file {
+        let snapshot =
+            metrics::MetricsSnapshot::from_demux(counts, suffix, progress.tiles(), start.elapsed());
+        fs::write(path, snapshot.to_prometheus())?;
+        slog_info!(slog_scope::logger(), "Wrote metrics to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Build the demux manager and a reader pool with every one of `tasks` queued
+///
+/// Progress is reported until the returned [ProgressReporter] is dropped.
+fn demux_pipeline(
+    run_info: &RunInfo,
+    tasks: Vec<LaneTask>,
+    progress: Arc<ProgressCounters>,
+    options: &DemuxOptions,
+) -> Result<(DemuxManager, ReaderPool, ProgressReporter), IlluvatarError> {
+    let n_cycles = tasks.iter().map(|t| t.cycles.len()).max().unwrap_or(0);
+    let (mut manager, demux_send) = match options.threads {
+        Some(threads) => {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(usize::from(threads))
+                .thread_name(|i| format!("illuv-demux-worker-{i}"))
+                .build()?;
+            DemuxManager::with_thread_pool(Arc::new(pool), DEMUX_CAP, n_cycles)
+        }
+        None => DemuxManager::with_global_pool(DEMUX_CAP, n_cycles),
+    };
+    manager.set_read_name_formatter(options.read_name_format.unwrap_or_default().formatter());
+    let (mut readers, task_send) = ReaderPool::new(demux_send, manager.tile_pool())?;
+    readers.set_progress(progress.clone());
+    if let Some(rate) = options.downsample_rate {
+        slog_warn!(
+            slog_scope::logger(),
+            "Keeping {} of clusters, output is only fit for QC",
+            rate
+        );
+    }
+    readers.set_options(ReaderOptions {
+        pf_filter: options.pf_tiles.unwrap_or_default(),
+        downsample: options
+            .downsample_rate
+            .map(|rate| Downsample::new(rate, options.downsample_seed.unwrap_or(0))),
+        prefetch: options.prefetch,
+        skip_errors: options.skip_corrupt_tiles,
+    });
+    let expected = tasks
+        .iter()
+        .map(|task| {
+            (
+                task.lane,
+                run_info
+                    .tiles()
+                    .get(&task.lane)
+                    .map_or(0, |t| t.len() as u64),
+            )
+        })
+        .collect::<BTreeMap<u8, u64>>();
+    let reporter = ProgressReporter::spawn(progress, expected, PROGRESS_INTERVAL);
+    for task in tasks {
+        task_send
+            .send(task)
+            .expect("the reader pool holds the task receiver");
+    }
+    drop(task_send);
+    Ok((manager, readers, reporter))
+}
+
+/// Read every queued lane, returning the tiles skipped because they failed to decode
+fn read_all(mut readers: ReaderPool, n_readers: u8) -> Result<Vec<SkippedTile>, ReadError> {
+    let read = readers.read(n_readers);
+    let skipped = readers.take_skipped();
+    // hang up on the demux workers once every lane is read
+    drop(readers);
+    read.map(|_| skipped)
+}
+
+/// Positional numbering after `--sample-number-offset`, pinned by `--sample-numbers`
+fn sample_numbering(options: &DemuxOptions) -> Result<SampleNumbering, IlluvatarError> {
+    let numbering = SampleNumbering::with_offset(options.sample_number_offset.unwrap_or(0));
+    Ok(match &options.sample_numbers {
+        Some(path) => numbering.with_mapping(SampleNumbering::read_mapping(path)?)?,
+        None => numbering,
+    })
+}
+
+/// Log roughly how much gzipped FASTQ the demux will write, see [estimate::estimate_output]
+///
+/// Clusters are counted from the first cycle's headers. The sample sheet gives
+/// no expected proportions, so output is split evenly across samples.
+fn log_output_estimate(
+    seq_dir: &Path,
+    layout: &ReadLayout,
+    samples: &[Sample],
+    create_fastq_for_index_reads: bool,
+) {
+    let clusters = bcl::integrity::lane_dirs(seq_dir)
+        .map_err(bcl::BclError::from)
+        .and_then(|lanes| {
+            lanes
+                .values()
+                .map(bcl::integrity::lane_cluster_count)
+                .sum::<Result<u64, bcl::BclError>>()
+        });
+    let clusters = match clusters {
+        Ok(clusters) => clusters,
+        Err(e) => {
+            slog_warn!(
+                slog_scope::logger(),
+                "Unable to count clusters to estimate output size: {e}"
+            );
+            return;
+        }
+    };
+    let weights = samples
+        .iter()
+        .map(|s| (s.sample_id.clone(), 1.0))
+        .collect::<Vec<(String, f64)>>();
+    let estimate = estimate::estimate_output(
+        clusters,
+        layout,
+        create_fastq_for_index_reads,
+        &weights,
+        estimate::DEFAULT_GZIP_RATIO,
+    );
+    slog_info!(
+        slog_scope::logger(),
+        "Expecting about {:.1} GB of gzipped FASTQ from {} clusters",
+        estimate.total_bytes as f64 / 1e9,
+        clusters
+    );
+    for sample in estimate.samples.iter() {
+        slog_debug!(
+            slog_scope::logger(),
+            "Expecting about {:.1} GB for sample {}",
+            sample.bytes as f64 / 1e9,
+            sample.sample_id
+        );
+    }
+}
+
+fn run_preflight(
+    seq_dir: &Path,
+    output: Option<&Path>,
+    run_info: &RunInfo,
+    sheet_reads: Option<&[RunRead]>,
+    samples: &[Sample],
+    skip: &[preflight::PreflightCheck],
+) -> Result<(), IlluvatarError> {
+    let start = Instant::now();
+    let report = preflight::preflight(
+        seq_dir,
+        output,
+        Some(run_info),
+        sheet_reads,
+        Some(samples),
+        skip,
+    );
+    for warning in report.warnings.iter() {
+        slog_warn!(
+            slog_scope::logger(),
+            "{}: {}",
+            warning.check,
+            warning.message
+        );
+    }
+    for failure in report.failures.iter() {
+        slog_error!(
+            slog_scope::logger(),
+            "{}: {}",
+            failure.check,
+            failure.message
+        );
+    }
+    slog_info!(
+        slog_scope::logger(),
+        "Preflight finished in {:.1?}",
+        start.elapsed()
+    );
+    if report.is_clean() {
+        Ok(())
+    } else {
+        Err(IlluvatarError::PreflightFailed {
+            failed: report.failures.len(),
+        })
+    }
+}
 
+fn decode(file: PathBuf, output: Option<PathBuf>) -> Result<(), IlluvatarError> {
+    match output {
+        Some(path) => {
+            let n_tiles = bcl::decode::cbcl_to_fastq(&file, BufWriter::new(File::create(&path)?))?;
+            slog_info!(
+                slog_scope::logger(),
+                "Decoded {} tiles from {} into {}",
+                n_tiles,
+                file.display(),
+                path.display()
+            );
+        }
+        None => {
+            let n_tiles = bcl::decode::cbcl_to_fastq(&file, BufWriter::new(io::stdout().lock()))?;
+            slog_info!(
+                slog_scope::logger(),
+                "Decoded {} tiles from {}",
+                n_tiles,
+                file.display()
+            );
+        }
+    }
     Ok(())
 }
 
+fn interop_summary(input: PathBuf) -> Result<(), IlluvatarError> {
+    let path = input
+        .join(interop::INTEROP_DIR)
+        .join(interop::TILE_METRICS_FILE);
+    let tiles = interop::read_tile_metrics(&path)?;
+    for lane in interop::summarize_lanes(&tiles) {
+        slog_info!(
+            slog_scope::logger(),
+            "Lane {}: {} tiles, {:.0} clusters, {:.0} PF ({:.1}%), density {:.0}/mm^2 ({:.0} PF)",
+            lane.lane,
+            lane.n_tiles,
+            lane.cluster_count,
+            lane.pf_cluster_count,
+            lane.percent_pf(),
+            lane.cluster_density,
+            lane.pf_cluster_density
+        );
+    }
+    Ok(())
+}
+
+fn scan(input: PathBuf) -> Result<(), IlluvatarError> {
+    let start = Instant::now();
+    let cbcls = bcl::integrity::find_cbcls(&input)?;
+    let report = bcl::integrity::scan_cbcls(&cbcls);
+    for failure in report.failures.iter() {
+        match failure.tile_num {
+            Some(tile_num) => slog_error!(
+                slog_scope::logger(),
+                "{} tile {}: {}",
+                failure.path.display(),
+                tile_num,
+                failure.error
+            ),
+            None => slog_error!(
+                slog_scope::logger(),
+                "{}: {}",
+                failure.path.display(),
+                failure.error
+            ),
+        }
+    }
+    let mismatched_lanes = scan_declared_tiles(&input);
+    slog_info!(
+        slog_scope::logger(),
+        "Scanned {} tiles in {} cbcls in {:.1?}",
+        report.n_tiles,
+        report.n_files,
+        start.elapsed()
+    );
+    if report.is_clean() && mismatched_lanes == 0 {
+        Ok(())
+    } else {
+        Err(IlluvatarError::ScanFailed {
+            failed: report.failures.len() + mismatched_lanes,
+        })
+    }
+}
+
+/// Compare each lane's cbcl tiles against those RunInfo.xml declares, returning
+/// how many lanes differ
+///
+/// Runs without a readable RunInfo.xml, or whose RunInfo lists no tiles, are
+/// not checked.
+fn scan_declared_tiles(seq_dir: &Path) -> usize {
+    let run_info = match RunInfo::from_path(seq_dir.join(runinfo::RUN_INFO)) {
+        Ok(run_info) => run_info,
+        Err(e) => {
+            slog_info!(
+                slog_scope::logger(),
+                "Not checking declared tiles, unable to read RunInfo.xml: {}",
+                e
+            );
+            return 0;
+        }
+    };
+    let mut mismatched = 0;
+    for (lane, declared) in run_info.tiles() {
+        let lane_dir = seq_dir
+            .join(bcl::integrity::BASECALLS_DIR)
+            .join(format!("L{lane:03}"));
+        match bcl::integrity::compare_tile_sets(declared, &lane_dir) {
+            Ok(diff) if diff.is_consistent() => {}
+            Ok(diff) => {
+                mismatched += 1;
+                slog_error!(
+                    slog_scope::logger(),
+                    "Lane {} is missing declared tiles {:?} and has undeclared tiles {:?}",
+                    lane,
+                    diff.missing,
+                    diff.extra
+                );
+            }
+            Err(e) => {
+                mismatched += 1;
+                slog_error!(slog_scope::logger(), "{}: {}", lane_dir.display(), e);
+            }
+        }
+    }
+    mismatched
+}
+
 fn main() {
     let args = Illuvatar::parse();
-    let _log_guard = logging::init_logger(args.logfile.as_ref(), args.verbose).map_err(|e| {
-        eprintln!("Failed to initialize logger: {e}");
-        process::exit(1)
-    });
+    // decode writes FASTQ to stdout without --output
+    let stdout_is_output = matches!(args.command, Some(Command::Decode { output: None, .. }));
+    let _log_guard = logging::init_logger(args.logfile.as_ref(), args.verbose, stdout_is_output)
+        .map_err(|e| {
+            eprintln!("Failed to initialize logger: {e}");
+            process::exit(1)
+        });
 
-    slog_scope::scope(
+    let result = slog_scope::scope(
         &slog_scope::logger().new(slog_o!("scope" => "main")),
-        || match illuvatar(args) {
-            Ok(()) => {}
-            Err(e) => {
+        || {
+            let result = illuvatar(args);
+            if let Err(e) = &result {
                 slog_error!(slog_scope::logger(), "{}", e);
             }
+            result
         },
-    )
+    );
+    if result.is_err() {
+        // the scoped logger is gone by now, so dropping the guard flushes the async logger
+        drop(_log_guard);
+        process::exit(1)
+    }
 }
 
 #[derive(Parser, Debug)]
 #[clap(author = "Spencer Richman", version = "0.0.1", about, long_about = None)]
-#[command(arg_required_else_help(true))]
+#[command(arg_required_else_help(true), subcommand_negates_reqs(true))]
 struct Illuvatar {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Sequencing output directory
-    #[arg(short, long, value_name = "SEQUENCING DIR")]
-    input: PathBuf,
+    #[arg(short, long, value_name = "SEQUENCING DIR", required = true)]
+    input: Option<PathBuf>,
+
+    /// Directory to write outputs into
+    #[arg(short, long, value_name = "OUTPUT DIR", required = true)]
+    output: Option<PathBuf>,
 
     /// Log file name
     #[arg(short, long, global = true, default_value = None)]
@@ -87,4 +880,37 @@ struct Illuvatar {
     /// Verbosity of logging
     #[arg(short, long, global = true, value_parser = value_parser!(u8).range(0..=2), default_value_t = 0)]
     verbose: u8,
+
+    /// Load demux options from a TOML file, options given on the command line take precedence
+    #[arg(long, value_name = "TOML", default_value = None)]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    options: DemuxOptions,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Decode a single cbcl into one FASTQ record per tile, for debugging the decoder
+    Decode {
+        /// cbcl file to decode
+        file: PathBuf,
+
+        /// Write records here instead of stdout
+        #[arg(short, long, default_value = None)]
+        output: Option<PathBuf>,
+    },
+    /// Decode every cbcl in a run without writing output, reporting tiles that fail
+    /// and lanes whose tiles differ from those RunInfo.xml declares
+    Scan {
+        /// Sequencing output directory
+        #[arg(short, long, value_name = "SEQUENCING DIR")]
+        input: PathBuf,
+    },
+    /// Summarize clusters per lane from InterOp/TileMetricsOut.bin, without decoding base calls
+    Interop {
+        /// Sequencing output directory
+        #[arg(short, long, value_name = "SEQUENCING DIR")]
+        input: PathBuf,
+    },
 }
@@ -0,0 +1,289 @@
+// Unmapped BAM output, for pipelines that want barcodes and UMIs carried as
+// tags rather than packed into FASTQ read names.
+// This is a minimal BAM/BGZF encoder: a header without references, and one
+// unmapped record per WriteRecord.
+
+use std::io::Write;
+
+use libdeflater::{crc32, CompressionLvl, Compressor};
+
+use crate::{
+    manager::writer::{WriteRecord, WriteSink},
+    IlluvatarError,
+};
+
+/// Uncompressed bytes per BGZF block, leaving room for incompressible data
+/// to stay under the 64KiB block limit
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+/// Header of a BGZF block, up to BSIZE
+const BGZF_HEADER: [u8; 16] = [
+    0x1f, 0x8b, // gzip magic
+    8,    // CM deflate
+    4,    // FLG FEXTRA
+    0, 0, 0, 0,    // MTIME
+    0,    // XFL
+    0xff, // OS unknown
+    6, 0, // XLEN
+    b'B', b'C', // BGZF subfield
+    2, 0, // SLEN
+];
+
+/// An empty block, which marks the end of a BGZF file
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+const SEQ_CODES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+const FLAG_UNMAPPED: u16 = 4;
+/// reg2bin(-1, 0), the bin of every unmapped read without a position
+const UNMAPPED_BIN: u16 = 4680;
+
+/// Writes [WriteRecord]s as unmapped BAM records
+///
+/// Every record is tagged with the read group, and with `BC` and `RX` when
+/// the record carries a barcode or UMI. Records are unpaired, each destination
+/// gets its own file just like FASTQ output.
+pub struct UbamWriter<W: Write> {
+    inner: W,
+    read_group: String,
+    compressor: Compressor,
+    block: Vec<u8>,
+    compressed: Vec<u8>,
+    record: Vec<u8>,
+}
+
+impl<W: Write> UbamWriter<W> {
+    /// Start a uBAM, writing the header with a single read group
+    pub fn new(inner: W, read_group: &str) -> Result<Self, IlluvatarError> {
+        let mut writer = UbamWriter {
+            inner,
+            read_group: read_group.to_string(),
+            compressor: Compressor::new(CompressionLvl::default()),
+            block: Vec::with_capacity(BGZF_BLOCK_SIZE),
+            compressed: Vec::new(),
+            record: Vec::new(),
+        };
+        let text = format!("@HD\tVN:1.6\tSO:unsorted\n@RG\tID:{read_group}\tSM:{read_group}\n");
+        let mut header = Vec::with_capacity(12 + text.len());
+        header.extend_from_slice(b"BAM\x01");
+        header.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        header.extend_from_slice(text.as_bytes());
+        // no reference sequences
+        header.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_bytes(&header)?;
+        Ok(writer)
+    }
+
+    fn write_bytes(&mut self, mut bytes: &[u8]) -> Result<(), IlluvatarError> {
+        while !bytes.is_empty() {
+            let n = (BGZF_BLOCK_SIZE - self.block.len()).min(bytes.len());
+            self.block.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            if self.block.len() == BGZF_BLOCK_SIZE {
+                self.flush_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> Result<(), IlluvatarError> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let bound = self.compressor.deflate_compress_bound(self.block.len());
+        self.compressed.resize(bound, 0);
+        let n = self
+            .compressor
+            .deflate_compress(&self.block, &mut self.compressed)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        // BSIZE is the total block size minus one
+        let bsize = (BGZF_HEADER.len() + 2 + n + 8 - 1) as u16;
+        self.inner.write_all(&BGZF_HEADER)?;
+        self.inner.write_all(&bsize.to_le_bytes())?;
+        self.inner.write_all(&self.compressed[..n])?;
+        self.inner.write_all(&crc32(&self.block).to_le_bytes())?;
+        self.inner
+            .write_all(&(self.block.len() as u32).to_le_bytes())?;
+        self.block.clear();
+        Ok(())
+    }
+
+    fn encode(&mut self, record: &WriteRecord) {
+        // FASTQ ids start with @ and may carry a comment after whitespace
+        let name = record.id.trim_start_matches('@');
+        let name = name.split_whitespace().next().unwrap_or_default();
+        let seq = record.reads.as_bytes();
+
+        let r = &mut self.record;
+        r.clear();
+        // block_size, filled in below
+        r.extend_from_slice(&0u32.to_le_bytes());
+        r.extend_from_slice(&(-1i32).to_le_bytes()); // refID
+        r.extend_from_slice(&(-1i32).to_le_bytes()); // pos
+        r.push((name.len() + 1) as u8); // l_read_name
+        r.push(255); // mapq
+        r.extend_from_slice(&UNMAPPED_BIN.to_le_bytes());
+        r.extend_from_slice(&0u16.to_le_bytes()); // n_cigar_op
+        r.extend_from_slice(&FLAG_UNMAPPED.to_le_bytes());
+        r.extend_from_slice(&(seq.len() as u32).to_le_bytes());
+        r.extend_from_slice(&(-1i32).to_le_bytes()); // next_refID
+        r.extend_from_slice(&(-1i32).to_le_bytes()); // next_pos
+        r.extend_from_slice(&0i32.to_le_bytes()); // tlen
+        r.extend_from_slice(name.as_bytes());
+        r.push(0);
+        for pair in seq.chunks(2) {
+            let hi = seq_code(pair[0]);
+            let lo = pair.get(1).map_or(0, |b| seq_code(*b));
+            r.push(hi << 4 | lo);
+        }
+        // BAM stores raw Phred scores, not Phred+33
+        r.extend(record.qual.bytes().map(crate::bcl::ascii_to_phred));
+        push_tag(r, b"RG", &self.read_group);
+        if let Some(barcode) = &record.barcode {
+            // SAM separates the indexes of a dual-indexed barcode with a hyphen
+            r.extend_from_slice(b"BCZ");
+            r.extend(barcode.bytes().map(|b| if b == b'+' { b'-' } else { b }));
+            r.push(0);
+        }
+        if let Some(umi) = &record.umi {
+            push_tag(r, b"RX", umi);
+        }
+        let block_size = (r.len() - 4) as u32;
+        r[..4].copy_from_slice(&block_size.to_le_bytes());
+    }
+}
+
+fn seq_code(base: u8) -> u8 {
+    SEQ_CODES
+        .iter()
+        .position(|c| *c == base.to_ascii_uppercase())
+        .unwrap_or(15) as u8
+}
+
+fn push_tag(record: &mut Vec<u8>, tag: &[u8; 2], value: &str) {
+    record.extend_from_slice(tag);
+    record.push(b'Z');
+    record.extend_from_slice(value.as_bytes());
+    record.push(0);
+}
+
+impl<W: Write> WriteSink for UbamWriter<W> {
+    fn write_record(&mut self, record: &WriteRecord) -> Result<(), IlluvatarError> {
+        self.encode(record);
+        let encoded = std::mem::take(&mut self.record);
+        let result = self.write_bytes(&encoded);
+        self.record = encoded;
+        result
+    }
+
+    fn finish(&mut self) -> Result<(), IlluvatarError> {
+        self.flush_block()?;
+        self.inner.write_all(&BGZF_EOF)?;
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libdeflater::Decompressor;
+
+    use super::*;
+
+    fn record(id: &str, reads: &str, qual: &str) -> WriteRecord {
+        WriteRecord {
+            lane: 1,
+            id: id.to_string(),
+            reads: reads.to_string(),
+            qual: qual.to_string(),
+            destination: "S1".to_string(),
+            barcode: None,
+            umi: None,
+        }
+    }
+
+    /// Check the framing of every BGZF block, returning their concatenated contents
+    /// and how many blocks held data
+    fn inflate(mut bgzf: &[u8]) -> (Vec<u8>, usize) {
+        let mut decompressor = Decompressor::new();
+        let (mut out, mut n_blocks) = (Vec::new(), 0);
+        while !bgzf.is_empty() {
+            assert_eq!(bgzf[..16], BGZF_HEADER);
+            let bsize = usize::from(u16::from_le_bytes([bgzf[16], bgzf[17]]));
+            let (block, rest) = bgzf.split_at(bsize + 1);
+            let trailer = &block[block.len() - 8..];
+            let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+            let isize = u32::from_le_bytes(trailer[4..].try_into().unwrap()) as usize;
+            assert!(isize <= 0x10000);
+            let mut data = vec![0; isize];
+            let n = decompressor
+                .deflate_decompress(&block[18..block.len() - 8], &mut data)
+                .unwrap();
+            assert_eq!(n, isize);
+            assert_eq!(crc32(&data), crc);
+            if rest.is_empty() {
+                assert_eq!(block, BGZF_EOF);
+            } else {
+                n_blocks += 1;
+            }
+            out.extend(data);
+            bgzf = rest;
+        }
+        (out, n_blocks)
+    }
+
+    fn le_u32(bytes: &[u8]) -> usize {
+        u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let mut writer = UbamWriter::new(Vec::new(), "RUN.1").unwrap();
+        let mut tagged = record("@A01234:1:FC:1:1101:0:0 1:N:0:ACGT+TTGG", "ACGTN", "I#5?!");
+        tagged.barcode = Some("ACGT+TTGG".to_string());
+        tagged.umi = Some("AACC".to_string());
+        writer.write_record(&tagged).unwrap();
+        // enough plain records to need more than one block
+        let plain = record("@plain", &"ACGT".repeat(25), &"I".repeat(100));
+        for _ in 0..1000 {
+            writer.write_record(&plain).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let (bam, n_blocks) = inflate(&writer.inner);
+        assert!(n_blocks > 1);
+        assert_eq!(&bam[..4], b"BAM\x01");
+        let l_text = le_u32(&bam[4..]);
+        let text = std::str::from_utf8(&bam[8..8 + l_text]).unwrap();
+        assert!(text.contains("@RG\tID:RUN.1\tSM:RUN.1\n"));
+        assert_eq!(le_u32(&bam[8 + l_text..]), 0);
+
+        let r = &bam[12 + l_text..];
+        let block_size = le_u32(r);
+        let r = &r[..4 + block_size];
+        assert_eq!(r[12] as usize, "A01234:1:FC:1:1101:0:0".len() + 1);
+        assert_eq!(u16::from_le_bytes([r[14], r[15]]), UNMAPPED_BIN);
+        assert_eq!(u16::from_le_bytes([r[18], r[19]]), FLAG_UNMAPPED);
+        assert_eq!(le_u32(&r[20..]), 5);
+        let name_end = 36 + r[12] as usize;
+        assert_eq!(&r[36..name_end], b"A01234:1:FC:1:1101:0:0\0");
+        // A C, G T, then N and a zero pad nibble
+        assert_eq!(&r[name_end..name_end + 3], &[0x12, 0x48, 0xf0]);
+        assert_eq!(&r[name_end + 3..name_end + 8], &[40, 2, 20, 30, 0]);
+        assert_eq!(
+            &r[name_end + 8..],
+            b"RGZRUN.1\0BCZACGT-TTGG\0RXZAACC\0".as_slice()
+        );
+
+        // the untagged records follow, each with only the read group
+        let next = &bam[12 + l_text + 4 + block_size..];
+        let next_size = le_u32(next);
+        assert!(next[4..4 + next_size].ends_with(b"RGZRUN.1\0"));
+        assert_eq!(
+            bam.len(),
+            12 + l_text + 4 + block_size + 1000 * (4 + next_size)
+        );
+    }
+}
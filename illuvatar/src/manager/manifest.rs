@@ -0,0 +1,221 @@
+// Manifests describe what a demux actually wrote, so downstream orchestration
+// (Nextflow, Snakemake, DRAGEN, ...) can consume outputs without globbing for them.
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use fxhash::FxHashMap;
+
+use crate::{
+    accumulator::{DemuxReport, DemuxStats},
+    manager::writer::SampleOutputs,
+    IlluvatarError,
+};
+
+pub const REPORTS_DIR: &str = "Reports";
+pub const FASTQ_LIST: &str = "fastq_list.csv";
+pub const DEMUX_REPORT: &str = "demux_stats.json";
+pub const FASTQ_MANIFEST: &str = "fastq_manifest.tsv";
+
+/// BCL Convert writes this when the sample sheet has no library column
+pub const UNKNOWN_LIBRARY: &str = "UnknownLibrary";
+
+/// Write `<output>/Reports/fastq_list.csv` in the format BCL Convert produces
+///
+/// Columns are `RGID,RGSM,RGLB,Lane,Read1File,Read2File`, one row per pair of
+/// FASTQs, so outputs should be split by lane as BCL Convert does. Outputs
+/// holding every lane are listed under the first of `lanes`. `RGLB` is the
+/// sample's library from `libraries`, see [RawSheet::data_column](crate::sheet::RawSheet::data_column),
+/// or [UNKNOWN_LIBRARY].
+pub fn write_fastq_list<P: AsRef<Path>>(
+    output_directory: P,
+    outputs: &[SampleOutputs],
+    lanes: &[u8],
+    libraries: &FxHashMap<String, String>,
+) -> Result<PathBuf, IlluvatarError> {
+    let reports = output_directory.as_ref().join(REPORTS_DIR);
+    fs::create_dir_all(&reports)?;
+    let path = reports.join(FASTQ_LIST);
+    let mut out = BufWriter::new(File::create(&path)?);
+
+    let first_lane = lanes.iter().min().copied().unwrap_or(1);
+    writeln!(out, "RGID,RGSM,RGLB,Lane,Read1File,Read2File")?;
+    for sample in outputs {
+        let lane = sample.lane.unwrap_or(first_lane);
+        writeln!(
+            out,
+            "{},{},{},{},{},{}",
+            read_group_id(sample, lane),
+            sample.sample_id,
+            libraries
+                .get(&sample.sample_id)
+                .map_or(UNKNOWN_LIBRARY, String::as_str),
+            lane,
+            read_file(sample, 0),
+            read_file(sample, 1),
+        )?;
+    }
+    out.flush()?;
+    Ok(path)
+}
+
+/// Write `<output>/Reports/fastq_manifest.tsv`, listing what each sample actually got
+///
+/// fastq_list.csv has BCL Convert's fixed columns, so the read counts from `stats`,
+/// every template read, and the index FASTQ are listed here instead, one row per
+/// sample and lane: `Sample_ID`, `Lane`, `Reads`, `Read1File` through
+/// `Read<n>File`, and `IndexFile`. Missing files are left empty, and outputs
+/// that were removed, like empty Undetermined files, are left out. `Lane` is
+/// empty for outputs holding every lane.
+pub fn write_fastq_manifest<P: AsRef<Path>>(
+    output_directory: P,
+    outputs: &[SampleOutputs],
+    stats: &DemuxStats,
+) -> Result<PathBuf, IlluvatarError> {
+    let reports = output_directory.as_ref().join(REPORTS_DIR);
+    fs::create_dir_all(&reports)?;
+    let path = reports.join(FASTQ_MANIFEST);
+    let mut out = BufWriter::new(File::create(&path)?);
+
+    let template_reads = outputs.iter().map(|o| o.reads.len()).max().unwrap_or(0);
+    write!(out, "Sample_ID\tLane\tReads")?;
+    for read in 1..=template_reads {
+        write!(out, "\tRead{read}File")?;
+    }
+    writeln!(out, "\tIndexFile")?;
+    for sample in outputs.iter().filter(|o| {
+        o.reads
+            .iter()
+            .chain(o.index_fastq.as_ref())
+            .all(|p| p.exists())
+    }) {
+        // every cluster is written to each of the sample's reads, so R1 counts them
+        let counts = match sample.lane {
+            Some(lane) => stats.lane(lane),
+            None => Some(stats.run()),
+        };
+        let reads = counts
+            .and_then(|c| c.per_destination.get(&format!("{}_R1", sample.sample_id)))
+            .copied()
+            .unwrap_or(0);
+        write!(
+            out,
+            "{}\t{}\t{reads}",
+            sample.sample_id,
+            sample
+                .lane
+                .map_or_else(String::new, |lane| lane.to_string())
+        )?;
+        for read in 0..template_reads {
+            write!(out, "\t{}", read_file(sample, read))?;
+        }
+        writeln!(
+            out,
+            "\t{}",
+            sample
+                .index_fastq
+                .as_deref()
+                .map_or_else(String::new, |path| absolute(path).display().to_string())
+        )?;
+    }
+    out.flush()?;
+    Ok(path)
+}
+
+/// Write `<output>/Reports/demux_stats.json` with the counts and read lengths of a run
+pub fn write_demux_report<P: AsRef<Path>>(
+    output_directory: P,
+    report: &DemuxReport,
+) -> Result<PathBuf, IlluvatarError> {
+    let reports = output_directory.as_ref().join(REPORTS_DIR);
+    fs::create_dir_all(&reports)?;
+    let path = reports.join(DEMUX_REPORT);
+    fs::write(&path, report.to_json())?;
+    Ok(path)
+}
+
+/// `<index>.<index2>.<lane>`, dropping whichever indices the sample does not have
+fn read_group_id(sample: &SampleOutputs, lane: u8) -> String {
+    let mut parts = Vec::with_capacity(3);
+    parts.extend(sample.index.as_deref());
+    parts.extend(sample.index2.as_deref());
+    let lane = lane.to_string();
+    parts.push(lane.as_str());
+    parts.join(".")
+}
+
+/// Absolute path of the sample's `read`th FASTQ, empty for single-end runs as BCL Convert leaves it
+fn read_file(sample: &SampleOutputs, read: usize) -> String {
+    sample
+        .reads
+        .get(read)
+        .map_or_else(String::new, |path| absolute(path).display().to_string())
+}
+
+/// Outputs have been written by now, so canonicalizing should succeed
+fn absolute(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcl::testutil::scratch_dir;
+
+    #[test]
+    fn manifest_lists_counts_and_index_files() {
+        let dir = scratch_dir("fastq-manifest");
+        let output = |sample_id: &str, lane: u8, index: bool| {
+            let stem = format!("{sample_id}_L{lane:03}");
+            let outputs = SampleOutputs {
+                sample_id: sample_id.to_string(),
+                lane: Some(lane),
+                sample_number: 1,
+                index: None,
+                index2: None,
+                reads: vec![
+                    dir.join(format!("{stem}_R1.fastq")),
+                    dir.join(format!("{stem}_R2.fastq")),
+                ],
+                index_fastq: index.then(|| dir.join(format!("{stem}_index.fastq"))),
+            };
+            for path in outputs.reads.iter().chain(outputs.index_fastq.as_ref()) {
+                fs::write(path, "").unwrap();
+            }
+            outputs
+        };
+        let outputs = vec![
+            output("S1", 1, true),
+            output("S1", 2, false),
+            output("Undetermined", 1, false),
+        ];
+        // an empty Undetermined that was removed is not listed
+        fs::remove_file(&outputs[2].reads[0]).unwrap();
+        let mut stats = DemuxStats::default();
+        for _ in 0..3 {
+            stats.record(1, "S1_R1");
+            stats.record(1, "S1_R2");
+        }
+        stats.record(2, "S1_R1");
+
+        let path = write_fastq_manifest(&dir, &outputs, &stats).unwrap();
+        let dir = fs::canonicalize(&dir).unwrap();
+        let file = |name: &str| dir.join(name).display().to_string();
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            format!(
+                "Sample_ID\tLane\tReads\tRead1File\tRead2File\tIndexFile\n\
+                 S1\t1\t3\t{}\t{}\t{}\n\
+                 S1\t2\t1\t{}\t{}\t\n",
+                file("S1_L001_R1.fastq"),
+                file("S1_L001_R2.fastq"),
+                file("S1_L001_index.fastq"),
+                file("S1_L002_R1.fastq"),
+                file("S1_L002_R2.fastq"),
+            )
+        );
+    }
+}
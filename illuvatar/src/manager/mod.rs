@@ -1,95 +1,162 @@
-use std::{
-    fs::File,
-    io::BufReader,
-    thread::{self},
-    time::Duration,
-};
+use std::sync::Arc;
 
+#[cfg(feature = "ubam")]
+pub mod bam;
+pub mod manifest;
+pub mod pipe;
+pub mod progress;
 pub mod reader;
+pub mod readname;
 pub mod writer;
 
 use crossbeam::channel::{bounded, Receiver, Sender};
 use log::debug;
-use rayon::prelude::*;
 
 use crate::{
-    bcl::{reader::CBclReader, DemuxUnit},
-    manager::writer::WriteRecord,
+    accumulator::DemuxStats,
+    bcl::{pool::TilePool, DemuxUnit},
+    manager::{
+        reader::MAX_READERS,
+        readname::{Casava18ReadName, ReadNameFormatter},
+        writer::WriteRecord,
+    },
+    resolve::{ResolveError, ResolveStats, Resolver},
     IlluvatarError,
 };
 
-use samplesheet::SampleSheetSettings;
-
-type FileReader = CBclReader<BufReader<File>>;
-
 pub(crate) struct DemuxManager {
-    demux_pool: rayon::ThreadPool,
-    readers: Vec<FileReader>,
+    /// `None` means DemuxUnits are resolved on rayon's global pool
+    demux_pool: Option<Arc<rayon::ThreadPool>>,
     demux_recv: Receiver<DemuxUnit>,
+    tile_pool: TilePool,
+    read_names: Arc<dyn ReadNameFormatter>,
 }
 
 impl DemuxManager {
-    pub fn new(
-        num_threads: usize,
+    /// Resolve DemuxUnits on an existing thread pool instead of building a new one
+    ///
+    /// Use this when embedding illuvatar in an application that already
+    /// manages its own rayon pool, to avoid oversubscribing cores.
+    ///
+    /// `n_cycles` is the cycles each [DemuxUnit] holds, which sizes the tile pool.
+    pub fn with_thread_pool(
+        demux_pool: Arc<rayon::ThreadPool>,
+        demux_cap: usize,
+        n_cycles: usize,
+    ) -> (DemuxManager, Sender<DemuxUnit>) {
+        DemuxManager::from_pool(Some(demux_pool), demux_cap, n_cycles)
+    }
+
+    /// Resolve DemuxUnits on rayon's global thread pool
+    pub fn with_global_pool(
         demux_cap: usize,
-        settings: &SampleSheetSettings,
-    ) -> Result<(DemuxManager, Sender<DemuxUnit>), IlluvatarError> {
+        n_cycles: usize,
+    ) -> (DemuxManager, Sender<DemuxUnit>) {
+        DemuxManager::from_pool(None, demux_cap, n_cycles)
+    }
+
+    fn from_pool(
+        demux_pool: Option<Arc<rayon::ThreadPool>>,
+        demux_cap: usize,
+        n_cycles: usize,
+    ) -> (DemuxManager, Sender<DemuxUnit>) {
         // This channel holds WorkUnits
         let (demux_send, demux_recv) = bounded(demux_cap);
 
-        // DemuxUnits are sent to this pool
-        // We use a rayon threadpool because each DemuxUnit
-        // should be (relatively) short lived and is highly parallelizable
-        let demux_pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(num_threads)
-            .thread_name(|i| format!("illuv-demux-worker-{i}"))
-            .build()?;
+        // Tiles are handed back here once resolved, so size the free list
+        // to cover everything that can be in flight at once: the units queued,
+        // one being filled by each reader, and the one being resolved, each
+        // holding a tile per cycle
+        let tile_pool = TilePool::new((demux_cap + MAX_READERS + 1) * n_cycles);
 
-        Ok((
+        (
             DemuxManager {
                 demux_pool,
-                readers: vec![],
                 demux_recv,
+                tile_pool,
+                read_names: Arc::new(Casava18ReadName),
             },
             demux_send,
-        ))
+        )
+    }
+
+    /// Handle to the free list that resolved tiles are returned to
+    ///
+    /// Readers should decode into tiles taken from this pool.
+    pub fn tile_pool(&self) -> TilePool {
+        self.tile_pool.clone()
     }
 
-    pub fn resolve(&self, write_sender: Sender<WriteRecord>) {
-        // spin up the resolver
-        let recv_iter = self.demux_recv.iter();
-        // we create a parallel iterator over the demux_recv channel
-        // and make it immediately return on panic because there is no
-        // recovering from a failed demux attempt.
-        //
-        // Each thread immediately sends the resulting WriteRecord to the write queue,
-        // which is routed to the appropriate destination by the write router.
-        // Threads block until send succeeds to propagate backpressure.
+    /// Name reads with `read_names` instead of the standard [Casava18ReadName] format
+    pub fn set_read_name_formatter(&mut self, read_names: Arc<dyn ReadNameFormatter>) {
+        self.read_names = read_names;
+    }
 
-        // TODO resolve will eventually need to take settings from the samplesheet
-        // we either will clone the samplesheet settings or pass specific values
-        // as arguments, but cannot pass a reference
-        self.demux_pool.install(move || {
-            recv_iter.par_bridge().panic_fuse().for_each_with(
-                write_sender,
-                |sender: &mut Sender<WriteRecord>, demux_unit: DemuxUnit| {
-                    sender
-                        .send(resolve_tile(demux_unit))
-                        .expect("failed to send demux result to write channel")
-                },
-            )
-        });
+    /// Resolve every [DemuxUnit] received with `resolver`, until the readers hang up
+    ///
+    /// The manager is consumed so that, if resolving fails, the demux channel
+    /// closes and readers stop rather than block on a full channel. Returns the
+    /// [ResolveStats] of every tile, merged.
+    pub fn resolve(
+        self,
+        resolver: &Resolver,
+        write_sender: Sender<WriteRecord>,
+    ) -> Result<ResolveStats, IlluvatarError> {
+        // Workers send WriteRecords to the write queue as they are built,
+        // and they are routed to the appropriate destination by the write router.
+        // Workers block until send succeeds to propagate backpressure.
+        let stats = self.fold_units(ResolveStats::default(), |mut stats, unit, read_names| {
+            stats.merge(resolver.resolve_tile(unit, read_names, &write_sender)?);
+            Ok(stats)
+        })?;
         debug!("DONE RESOLVING");
+        Ok(stats)
     }
-}
 
-//// PLACEHOLDERS ////
+    /// Count the clusters of every [DemuxUnit] received per sample, until the readers hang up
+    ///
+    /// Units hold only index cycles, see [Resolver::count_tile].
+    pub fn count(self, resolver: &Resolver) -> Result<DemuxStats, IlluvatarError> {
+        let stats = self.fold_units(DemuxStats::default(), |mut stats, unit, _| {
+            stats.merge(resolver.count_tile(unit)?);
+            Ok(stats)
+        })?;
+        debug!("DONE COUNTING");
+        Ok(stats)
+    }
 
-fn resolve_tile(demux_unit: DemuxUnit) -> WriteRecord {
-    return WriteRecord {
-        reads: format!("reads for {}", demux_unit.tile_data.tile_num),
-        id: format!("test_id_{}", demux_unit.tile_data.tile_num),
-        qual: format!("qualities for {}", demux_unit.tile_data.tile_num),
-        destination: String::from("S01-TOO-12plex-P1-rep1_R1"),
-    };
+    /// Fold `step` over every [DemuxUnit] received, recycling each unit's tiles after
+    fn fold_units<T, F>(self, init: T, step: F) -> Result<T, IlluvatarError>
+    where
+        T: Send,
+        F: Fn(T, &DemuxUnit, &dyn ReadNameFormatter) -> Result<T, ResolveError> + Sync,
+    {
+        let DemuxManager {
+            demux_pool,
+            demux_recv,
+            tile_pool,
+            read_names,
+            ..
+        } = self;
+        // Units are handled one at a time, since each holds every cycle of a
+        // tile, and the pool works through a unit's clusters in parallel.
+        let fold_all = || {
+            demux_recv
+                .iter()
+                .try_fold(init, |acc, demux_unit: DemuxUnit| {
+                    let folded = step(acc, &demux_unit, read_names.as_ref());
+                    // the tile's buffers are no longer needed, recycle them for the readers
+                    for tile in demux_unit.tiles {
+                        tile_pool.give(tile);
+                    }
+                    folded
+                })
+        };
+        let result = match &demux_pool {
+            Some(pool) => pool.install(fold_all),
+            // outside of any pool, parallel iterators run on the global pool
+            None => fold_all(),
+        };
+        Ok(result?)
+    }
 }
@@ -0,0 +1,104 @@
+// A sink that hands FASTQ text to an external compressor, e.g. pigz or bgzip,
+// rather than compressing in process. The compressor reads records on its stdin
+// and writes straight into the destination file.
+
+use std::{
+    fs::File,
+    io::BufWriter,
+    process::{Child, ChildStdin, Command, Stdio},
+};
+
+use crate::{
+    manager::writer::{FastqWriter, FlushPolicy, WriteRecord, WriteSink},
+    IlluvatarError,
+};
+
+pub struct PipeWriter {
+    command: String,
+    child: Child,
+    /// Taken in [finish](WriteSink::finish) so the compressor sees EOF
+    fastq: Option<FastqWriter<BufWriter<ChildStdin>>>,
+}
+
+impl PipeWriter {
+    /// Spawn `command` with its stdout redirected into `output`
+    ///
+    /// `command` is split on whitespace, e.g. `pigz -p 8`, and must compress
+    /// stdin to stdout, which pigz and bgzip do when given no files.
+    pub fn spawn(
+        command: &str,
+        output: File,
+        flush_policy: FlushPolicy,
+    ) -> Result<Self, IlluvatarError> {
+        let mut args = command.split_whitespace();
+        let program = args
+            .next()
+            .ok_or_else(|| IlluvatarError::CompressorFailed {
+                command: command.to_string(),
+                reason: "no command given".to_string(),
+            })?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::from(output))
+            .spawn()
+            .map_err(|e| IlluvatarError::CompressorFailed {
+                command: command.to_string(),
+                reason: e.to_string(),
+            })?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        Ok(PipeWriter {
+            command: command.to_string(),
+            child,
+            fastq: Some(FastqWriter::with_policy(
+                BufWriter::new(stdin),
+                flush_policy,
+            )),
+        })
+    }
+
+    fn failed(&self, reason: String) -> IlluvatarError {
+        IlluvatarError::CompressorFailed {
+            command: self.command.clone(),
+            reason,
+        }
+    }
+}
+
+impl WriteSink for PipeWriter {
+    fn write_record(&mut self, record: &WriteRecord) -> Result<(), IlluvatarError> {
+        let Some(fastq) = self.fastq.as_mut() else {
+            return Err(self.failed("record written after finish".to_string()));
+        };
+        // a broken pipe usually means the compressor died, so report its exit status instead
+        if let Err(e) = fastq.write_record(record) {
+            return match self.child.try_wait() {
+                Ok(Some(status)) => Err(self.failed(format!("exited early with {status}"))),
+                _ => Err(e),
+            };
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), IlluvatarError> {
+        // dropping stdin closes the pipe, letting the compressor finish
+        let flushed = match self.fastq.take() {
+            Some(mut fastq) => fastq.finish(),
+            None => Ok(()),
+        };
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(self.failed(format!("exited with {status}")));
+        }
+        flushed
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        // close stdin and reap the child if the writer failed before finishing
+        if self.fastq.take().is_some() {
+            let _ = self.child.wait();
+        }
+    }
+}
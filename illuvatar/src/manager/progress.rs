@@ -0,0 +1,174 @@
+// Progress reporting for long demux runs.
+// Readers bump shared counters, and a reporter thread turns them into either
+// progress bars (interactive, `progress` feature) or periodic log lines.
+
+use std::{
+    collections::BTreeMap,
+    io::IsTerminal,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+/// Lanes beyond this are counted in the overall totals only
+pub const MAX_LANES: usize = 8;
+
+/// Tiles and clusters decoded so far, shared between readers and the reporter
+#[derive(Debug, Default)]
+pub struct ProgressCounters {
+    tiles: AtomicU64,
+    clusters: AtomicU64,
+    lane_tiles: [AtomicU64; MAX_LANES],
+}
+
+impl ProgressCounters {
+    /// Count one decoded tile, across every cycle
+    pub fn record_tile(&self, lane: u8, clusters: u32) {
+        self.tiles.fetch_add(1, Ordering::Relaxed);
+        self.clusters
+            .fetch_add(u64::from(clusters), Ordering::Relaxed);
+        if let Some(n) = usize::from(lane)
+            .checked_sub(1)
+            .and_then(|i| self.lane_tiles.get(i))
+        {
+            n.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn tiles(&self) -> u64 {
+        self.tiles.load(Ordering::Relaxed)
+    }
+
+    pub fn clusters(&self) -> u64 {
+        self.clusters.load(Ordering::Relaxed)
+    }
+
+    pub fn lane_tiles(&self, lane: u8) -> u64 {
+        usize::from(lane)
+            .checked_sub(1)
+            .and_then(|i| self.lane_tiles.get(i))
+            .map_or(0, |n| n.load(Ordering::Relaxed))
+    }
+}
+
+/// Periodically reports [ProgressCounters] until dropped
+///
+/// With the `progress` feature and an interactive stdout, this draws a bar per
+/// lane plus an overall bar. Otherwise, e.g. in a pipeline, it logs a line
+/// every `interval`.
+pub struct ProgressReporter {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ProgressReporter {
+    /// `expected` is the number of tiles each lane will decode
+    pub fn spawn(
+        counters: Arc<ProgressCounters>,
+        expected: BTreeMap<u8, u64>,
+        interval: Duration,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let interactive = cfg!(feature = "progress") && std::io::stdout().is_terminal();
+        let handle = thread::Builder::new()
+            .name("illuv-progress".to_string())
+            .spawn(move || {
+                if interactive {
+                    #[cfg(feature = "progress")]
+                    draw_bars(&counters, &expected, &thread_stop);
+                } else {
+                    log_lines(&counters, &expected, interval, &thread_stop);
+                }
+            })
+            .ok();
+        ProgressReporter { stop, handle }
+    }
+}
+
+impl Drop for ProgressReporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Sleep in short steps so a stop request is noticed promptly
+fn wait(interval: Duration, stop: &AtomicBool) {
+    let step = Duration::from_millis(100).min(interval);
+    let start = Instant::now();
+    while start.elapsed() < interval && !stop.load(Ordering::Relaxed) {
+        thread::sleep(step);
+    }
+}
+
+fn log_lines(
+    counters: &ProgressCounters,
+    expected: &BTreeMap<u8, u64>,
+    interval: Duration,
+    stop: &AtomicBool,
+) {
+    let start = Instant::now();
+    let total = expected.values().sum::<u64>();
+    while !stop.load(Ordering::Relaxed) {
+        wait(interval, stop);
+        let tiles = counters.tiles();
+        let elapsed = start.elapsed().as_secs_f64();
+        let eta = if tiles > 0 && total > tiles {
+            format!(
+                ", ETA {:.0}s",
+                elapsed / tiles as f64 * (total - tiles) as f64
+            )
+        } else {
+            String::new()
+        };
+        info!(
+            "decoded {tiles}/{total} tiles, {} clusters{eta}",
+            counters.clusters()
+        );
+    }
+}
+
+#[cfg(feature = "progress")]
+fn draw_bars(counters: &ProgressCounters, expected: &BTreeMap<u8, u64>, stop: &AtomicBool) {
+    use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+    let style = ProgressStyle::with_template("{prefix:>8} [{bar:40}] {pos}/{len} tiles ({eta})")
+        .expect("progress template is valid")
+        .progress_chars("=> ");
+    let multi = MultiProgress::new();
+    let lanes = expected
+        .iter()
+        .map(|(lane, n)| {
+            let bar = multi.add(ProgressBar::new(*n).with_style(style.clone()));
+            bar.set_prefix(format!("L{lane:03}"));
+            (*lane, bar)
+        })
+        .collect::<Vec<_>>();
+    let overall = multi.add(ProgressBar::new(expected.values().sum()).with_style(style));
+    overall.set_prefix("overall");
+
+    loop {
+        // one last update after stopping, so the bars end on the final counts
+        let stopping = stop.load(Ordering::Relaxed);
+        for (lane, bar) in lanes.iter() {
+            bar.set_position(counters.lane_tiles(*lane));
+        }
+        overall.set_position(counters.tiles());
+        if stopping {
+            break;
+        }
+        wait(Duration::from_millis(200), stop);
+    }
+    for (_, bar) in lanes.iter() {
+        bar.finish();
+    }
+    overall.finish();
+}
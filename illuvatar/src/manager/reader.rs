@@ -1,13 +1,30 @@
-use std::{fs::File, future::Future, io::BufReader, path::Path};
+use std::{
+    future::Future,
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+};
 
 use crossbeam::channel::{unbounded, Receiver, RecvError, SendError, Sender};
 
-use log::{debug, error};
-use seqdir::lane::Bcl;
+use log::debug;
 use thiserror::Error;
 use tokio::runtime;
 
-use crate::bcl::{reader::CBclReader, BclError, DemuxUnit};
+use crate::{
+    assemble::lockstep::LaneReader,
+    bcl::{
+        integrity::cycle_tiles,
+        pool::TilePool,
+        reader::{
+            FilterLayout, FilterProvider, ReaderOptions, SkippedTile, DEFAULT_BCL_READER_CAPACITY,
+        },
+        BclError, DemuxUnit,
+    },
+    manager::progress::ProgressCounters,
+};
+
+/// Lanes read at once, each reader holding one tile of every cycle in memory
+pub const MAX_READERS: usize = 2;
 
 #[derive(Debug, Error)]
 pub enum ReadError {
@@ -17,18 +34,24 @@ pub enum ReadError {
     SendError(#[from] SendError<DemuxUnit>),
     #[error(transparent)]
     RecvError(#[from] RecvError),
-    #[error("`init` has already been called on this reader")]
-    AlreadyInitError,
-    #[error("adapter has not been initialized")]
-    NoReaderError,
-    #[error("illuvatar does not support BCLs")]
-    BclUnsupportedError,
+    #[error("reader task failed: {0}")]
+    ReaderFailed(#[from] tokio::task::JoinError),
+}
+
+/// Every base call file of one lane, for a reader to decode in lockstep into [DemuxUnit]s
+#[derive(Debug, Clone)]
+pub struct LaneTask {
+    pub lane: u8,
+    /// `BaseCalls/L<lane>`, where the lane's filters live
+    pub lane_dir: PathBuf,
+    /// The cbcls or per-tile bcls of each cycle, in cycle order, see [lane_cycle_bcls](crate::bcl::integrity::lane_cycle_bcls)
+    pub cycles: Vec<Vec<PathBuf>>,
 }
 
 pub trait RoutableRead {
     fn read(
         &mut self,
-        receiver: Receiver<Bcl>,
+        receiver: Receiver<LaneTask>,
         destination: Sender<DemuxUnit>,
     ) -> impl Future<Output = Result<(), ReadError>>;
 }
@@ -37,95 +60,150 @@ pub trait RoutableRead {
 pub(crate) struct ReaderPool {
     runtime: runtime::Runtime,
     handles: Vec<tokio::task::JoinHandle<Result<(), ReadError>>>,
-    pub receiver: Receiver<Bcl>,
+    pub receiver: Receiver<LaneTask>,
     destination: Sender<DemuxUnit>,
+    tile_pool: TilePool,
+    options: ReaderOptions,
+    progress: Option<Arc<ProgressCounters>>,
+    skipped: Arc<Mutex<Vec<SkippedTile>>>,
 }
 
 impl ReaderPool {
-    pub fn new(destination: Sender<DemuxUnit>) -> Result<(ReaderPool, Sender<Bcl>), ReadError> {
+    pub fn new(
+        destination: Sender<DemuxUnit>,
+        tile_pool: TilePool,
+    ) -> Result<(ReaderPool, Sender<LaneTask>), ReadError> {
         let runtime = runtime::Builder::new_multi_thread()
             .thread_name("illuvatar-reader")
             .enable_all()
             .build()
             .unwrap();
 
-        let (sender, receiver) = unbounded::<Bcl>();
+        let (sender, receiver) = unbounded::<LaneTask>();
         Ok((
             ReaderPool {
                 runtime,
                 handles: Vec::new(),
                 receiver,
                 destination,
+                tile_pool,
+                options: ReaderOptions::default(),
+                progress: None,
+                skipped: Arc::default(),
             },
             sender,
         ))
     }
 
-    pub fn read(&mut self, readers: u8) {
+    /// Apply `options` to every cbcl reader
+    pub fn set_options(&mut self, options: ReaderOptions) {
+        self.options = options;
+    }
+
+    /// Drain the tiles skipped so far by every reader, see [ReaderOptions::skip_errors]
+    pub fn take_skipped(&mut self) -> Vec<SkippedTile> {
+        let mut skipped = self.skipped.lock().unwrap_or_else(PoisonError::into_inner);
+        std::mem::take(&mut *skipped)
+    }
+
+    /// Count every decoded tile in `progress`, e.g. for a [ProgressReporter](crate::manager::progress::ProgressReporter)
+    pub fn set_progress(&mut self, progress: Arc<ProgressCounters>) {
+        self.progress = Some(progress);
+    }
+
+    /// Read lanes with `readers` concurrent readers until the task sender is dropped
+    ///
+    /// Readers decode and block on sending, so each runs on its own blocking
+    /// thread. Returns the first reader's error, once every reader has finished.
+    pub fn read(&mut self, readers: u8) -> Result<(), ReadError> {
         for _ in 0..readers {
             let read_recv = self.receiver.clone();
             let dest = self.destination.clone();
-            self.handles
-                .push(self.runtime.spawn(async move {
-                    CBclReaderAdapter::default().read(read_recv, dest).await
-                }));
-        }
-        let mut finished = false;
-        while !finished {
-            finished = self.handles.iter().all(|h| h.is_finished());
+            let mut adapter = LaneReaderAdapter {
+                tile_pool: self.tile_pool.clone(),
+                options: self.options,
+                progress: self.progress.clone(),
+                skipped: self.skipped.clone(),
+            };
+            let handle = self.runtime.handle().clone();
+            self.handles.push(
+                self.runtime
+                    .spawn_blocking(move || handle.block_on(adapter.read(read_recv, dest))),
+            );
         }
+        let handles = std::mem::take(&mut self.handles);
+        let result = self.runtime.block_on(async {
+            let mut result = Ok(());
+            for handle in handles {
+                let finished = handle.await.map_err(ReadError::from).and_then(|r| r);
+                if result.is_ok() {
+                    result = finished;
+                }
+            }
+            result
+        });
         debug!("reader pool is exiting");
+        result
     }
 }
 
-/// A simple wrapper around a CBCLReader that implements [RoutableRead]
-///
-/// This lets us spin up a reader thread without initializaing the reader itself 
-#[derive(Default)]
-struct CBclReaderAdapter {
-    reader: Option<CBclReader<BufReader<File>>>,
+/// Reads each [LaneTask] it receives with a [LaneReader]
+struct LaneReaderAdapter {
+    tile_pool: TilePool,
+    options: ReaderOptions,
+    progress: Option<Arc<ProgressCounters>>,
+    skipped: Arc<Mutex<Vec<SkippedTile>>>,
 }
 
-impl CBclReaderAdapter {
-    fn init<P: AsRef<Path>>(&mut self, value: P) -> Result<(), ReadError> {
-        match self.reader {
-            None => {
-                self.reader = Some(CBclReader::new(value)?);
-                Ok(())
-            }
-            Some(_) => Err(ReadError::AlreadyInitError),
-        }
-    }
-}
-
-impl RoutableRead for CBclReaderAdapter {
+impl RoutableRead for LaneReaderAdapter {
     async fn read(
         &mut self,
-        receiver: Receiver<Bcl>,
+        receiver: Receiver<LaneTask>,
         destination: Sender<DemuxUnit>,
     ) -> Result<(), ReadError> {
-        // spin until we have a task to take
-        match receiver.recv() {
-            Ok(Bcl::CBcl(path)) => {
-                self.init(path.as_path())?;
-            }
-            Ok(Bcl::Bcl(_)) => return Err(ReadError::BclUnsupportedError),
-            Err(e) => return Err(e.into()),
-        }
-
-        let mut reader = self.reader.take().unwrap();
-        // read the BCL we initialized with
-        for demux_unit in &mut reader {
-            destination.send(demux_unit?)?;
-        }
-        // read more BCLs until the sender is dropped
-        while let Ok(Bcl::CBcl(bcl)) = receiver.recv() {
-            reader.reset_with(bcl, false)?;
-            for demux_unit in &mut reader {
-                destination.send(demux_unit?)?;
+        // read lanes until the sender is dropped
+        while let Ok(task) = receiver.recv() {
+            let mut reader = LaneReader::new(&task.cycles, DEFAULT_BCL_READER_CAPACITY)?;
+            reader.set_pool(self.tile_pool.clone());
+            reader.set_options(self.options);
+            let mut filters = FilterProvider::detect(&task.lane_dir, task.lane);
+            // per-lane filters are located from cbcl headers, bcl instruments filter per tile
+            if filters.layout() == FilterLayout::PerLane && matches!(reader, LaneReader::Cbcl(_)) {
+                if let Some(first_cycle) = task.cycles.first() {
+                    filters.set_lane_tiles(cycle_tiles(first_cycle)?);
+                }
             }
+            reader.set_filters(Arc::new(Mutex::new(filters)));
+            let sent = send_units(
+                &mut reader,
+                task.lane,
+                &destination,
+                self.progress.as_deref(),
+            );
+            self.skipped
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .extend(reader.take_skipped());
+            sent?;
         }
         debug!("READER EXITING");
         Ok(())
     }
 }
+
+/// Send every tile of a lane on for resolving
+fn send_units(
+    reader: &mut LaneReader,
+    lane: u8,
+    destination: &Sender<DemuxUnit>,
+    progress: Option<&ProgressCounters>,
+) -> Result<(), ReadError> {
+    while let Some(unit) = reader.next_unit() {
+        let (tile_data, tiles) = unit?;
+        if let Some(progress) = progress {
+            progress.record_tile(lane, tile_data.num_clusters());
+        }
+        destination.send(DemuxUnit::new(lane, tile_data, tiles))?;
+    }
+    Ok(())
+}
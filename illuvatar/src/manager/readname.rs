@@ -0,0 +1,143 @@
+// FASTQ read names. Downstream tools disagree on what a read name looks like,
+// so the format is pluggable rather than baked into the resolver.
+
+use std::fmt::Write;
+
+/// Everything a read name can be built from
+#[derive(Debug, Clone, Copy)]
+pub struct ReadNameContext<'a> {
+    pub instrument: &'a str,
+    pub run_number: u32,
+    pub flowcell: &'a str,
+    pub lane: u8,
+    pub tile: u32,
+    pub x: u32,
+    pub y: u32,
+    /// One-based output read number, 1 for R1
+    pub read: u8,
+    /// Whether the cluster failed pass-filter
+    pub filtered: bool,
+    pub control: u16,
+    /// Index bases, with `+` between i7 and i5 for dual indexes
+    pub index: &'a str,
+    pub umi: Option<&'a str>,
+}
+
+/// Builds the header line of a FASTQ record, including the leading `@`
+pub trait ReadNameFormatter: Send + Sync {
+    /// Write the read name into `out`, which is cleared first
+    fn format(&self, ctx: &ReadNameContext, out: &mut String);
+}
+
+/// The standard Illumina format since CASAVA 1.8, as bcl2fastq and BCL Convert write it
+///
+/// `@instrument:run:flowcell:lane:tile:x:y[:umi] read:filtered:control:index`,
+/// where `filtered` is `Y` or `N`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Casava18ReadName;
+
+impl ReadNameFormatter for Casava18ReadName {
+    fn format(&self, ctx: &ReadNameContext, out: &mut String) {
+        out.clear();
+        // writing to a String cannot fail
+        let _ = write!(
+            out,
+            "@{}:{}:{}:{}:{}:{}:{}",
+            ctx.instrument, ctx.run_number, ctx.flowcell, ctx.lane, ctx.tile, ctx.x, ctx.y
+        );
+        if let Some(umi) = ctx.umi {
+            let _ = write!(out, ":{umi}");
+        }
+        let _ = write!(
+            out,
+            " {}:{}:{}:{}",
+            ctx.read,
+            if ctx.filtered { 'Y' } else { 'N' },
+            ctx.control,
+            ctx.index
+        );
+    }
+}
+
+/// The format used before CASAVA 1.8, `@instrument:lane:tile:x:y#index/read`
+///
+/// It has no field for a UMI, so any UMI is dropped.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ClassicReadName;
+
+impl ReadNameFormatter for ClassicReadName {
+    fn format(&self, ctx: &ReadNameContext, out: &mut String) {
+        out.clear();
+        let _ = write!(
+            out,
+            "@{}:{}:{}:{}:{}#{}/{}",
+            ctx.instrument, ctx.lane, ctx.tile, ctx.x, ctx.y, ctx.index, ctx.read
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context<'a>(index: &'a str, umi: Option<&'a str>) -> ReadNameContext<'a> {
+        ReadNameContext {
+            instrument: "A01234",
+            run_number: 123,
+            flowcell: "HXXXXXDSX",
+            lane: 2,
+            tile: 1101,
+            x: 1000,
+            y: 2000,
+            read: 1,
+            filtered: false,
+            control: 0,
+            index,
+            umi,
+        }
+    }
+
+    fn name(formatter: &dyn ReadNameFormatter, ctx: &ReadNameContext) -> String {
+        // stale contents must not leak into the next name
+        let mut out = "leftover".to_string();
+        formatter.format(ctx, &mut out);
+        out
+    }
+
+    #[test]
+    fn casava18_names() {
+        assert_eq!(
+            name(&Casava18ReadName, &context("ACGTACGT", None)),
+            "@A01234:123:HXXXXXDSX:2:1101:1000:2000 1:N:0:ACGTACGT"
+        );
+        assert_eq!(
+            name(&Casava18ReadName, &context("ACGTACGT", Some("TTAGGC"))),
+            "@A01234:123:HXXXXXDSX:2:1101:1000:2000:TTAGGC 1:N:0:ACGTACGT"
+        );
+        let ctx = ReadNameContext {
+            read: 2,
+            filtered: true,
+            ..context("ACGTACGT+GGCCTTAA", None)
+        };
+        assert_eq!(
+            name(&Casava18ReadName, &ctx),
+            "@A01234:123:HXXXXXDSX:2:1101:1000:2000 2:Y:0:ACGTACGT+GGCCTTAA"
+        );
+    }
+
+    #[test]
+    fn classic_names_drop_the_umi() {
+        assert_eq!(
+            name(&ClassicReadName, &context("ACGTACGT", None)),
+            "@A01234:2:1101:1000:2000#ACGTACGT/1"
+        );
+        assert_eq!(
+            name(&ClassicReadName, &context("ACGTACGT", Some("TTAGGC"))),
+            "@A01234:2:1101:1000:2000#ACGTACGT/1"
+        );
+        assert_eq!(
+            name(&ClassicReadName, &context("ACGTACGT+GGCCTTAA", None)),
+            "@A01234:2:1101:1000:2000#ACGTACGT+GGCCTTAA/1"
+        );
+    }
+}
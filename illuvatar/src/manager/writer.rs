@@ -1,25 +1,39 @@
 use std::{
-    fs::{File, OpenOptions},
+    fs::{self, File, OpenOptions},
     future::Future,
-    io::{BufWriter, Write},
-    path::Path,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
 };
 
-use crossbeam::channel::{bounded, Receiver, SendError, Sender, TrySendError};
+use crossbeam::channel::{bounded, never, Receiver, SendError, Sender, TrySendError};
+use flate2::read::MultiGzDecoder;
 use fxhash::FxHashMap;
-use log::{debug, error};
+use log::{debug, error, warn};
 use samplesheet::{SampleSheetData, SampleSheetSettings};
 use thiserror::Error;
 use tokio::runtime;
 
-use crate::IlluvatarError;
+#[cfg(feature = "ubam")]
+use crate::manager::bam::UbamWriter;
+use crate::{
+    accumulator::{DemuxStats, ReadLengthStats, UNDETERMINED_PREFIX},
+    config::OutputFormat,
+    manager::pipe::PipeWriter,
+    IlluvatarError,
+};
 
 #[derive(Debug)]
 pub struct WriteRecord {
+    pub lane: u8,
     pub id: String,
     pub reads: String,
     pub qual: String,
     pub destination: String,
+    /// Observed index sequence, written as the BC tag of uBAM output
+    pub barcode: Option<String>,
+    /// Written as the RX tag of uBAM output
+    pub umi: Option<String>,
 }
 
 /// wrap any writer struct into a message-passing interface
@@ -38,11 +52,29 @@ pub(crate) trait RoutableWrite {
     ) -> impl Future<Output = Result<(), IlluvatarError>> + Send;
 }
 
+/// A destination that [WriteRecord]s land in
+///
+/// Implement this to send output somewhere other than a local file,
+/// e.g. an object store upload or a subprocess's stdin. Every sink can be
+/// installed into a [WriteRouter], which handles the channel plumbing.
+pub trait WriteSink {
+    fn write_record(&mut self, record: &WriteRecord) -> Result<(), IlluvatarError>;
+
+    /// Called once after the last record, to flush or close the destination
+    fn finish(&mut self) -> Result<(), IlluvatarError>;
+}
+
+/// Most writers a [WriteRouter] can run at once, e.g. R1, R2 and index for 1000+ samples
+pub const MAX_WRITERS: usize = 4096;
+
 pub(crate) struct WriteRouter {
-    lookup: FxHashMap<String, Sender<WriteRecord>>,
+    /// Writers of each destination, with the lane each takes or `None` for every lane
+    lookup: FxHashMap<String, Vec<(Option<u8>, Sender<WriteRecord>)>>,
     runtime: runtime::Runtime,
     handles: Vec<tokio::task::JoinHandle<Result<(), IlluvatarError>>>,
     pub write_recv: Receiver<WriteRecord>,
+    read_lengths: ReadLengthStats,
+    stats: DemuxStats,
 }
 
 /// WriteRouter sends [WriteRecord]s to the appropriate implementor of [RoutableWrite]
@@ -58,6 +90,8 @@ impl WriteRouter {
 
         let runtime = runtime::Builder::new_multi_thread()
             .worker_threads(max_threads)
+            // every writer holds a blocking thread for the whole run
+            .max_blocking_threads(MAX_WRITERS)
             .thread_name("illuvatar-writer")
             .enable_all()
             .build()?;
@@ -68,6 +102,8 @@ impl WriteRouter {
                 handles: Vec::new(),
                 lookup: FxHashMap::default(),
                 write_recv,
+                read_lengths: ReadLengthStats::default(),
+                stats: DemuxStats::default(),
             },
             write_send,
         ))
@@ -75,7 +111,10 @@ impl WriteRouter {
 
     /// Given a writer that implements [RoutableWrite], install it into the router
     ///
-    /// Each writer is spawned into a multithreaded async runtime.
+    /// Each writer is spawned onto its own blocking thread of the router's
+    /// runtime, since writers block on their channel and their output. With a
+    /// `lane`, the writer only takes the records of `key` from that lane, so a
+    /// destination can be split across one writer per lane.
     pub fn install_writer<
         RW: RoutableWrite<RouteSend = Sender<WriteRecord>, RouteRecv = Receiver<WriteRecord>>
             + Send
@@ -84,13 +123,17 @@ impl WriteRouter {
     >(
         &mut self,
         key: String,
+        lane: Option<u8>,
         mut writer: RW,
         cap: usize,
     ) -> Result<(), IlluvatarError> {
         let (send, recv) = writer.connect(cap)?;
-        self.lookup.insert(key.clone(), send);
-        self.handles
-            .push(self.runtime.spawn(async move { writer.write(recv).await }));
+        self.lookup.entry(key).or_default().push((lane, send));
+        let handle = self.runtime.handle().clone();
+        self.handles.push(
+            self.runtime
+                .spawn_blocking(move || handle.block_on(writer.write(recv))),
+        );
 
         Ok(())
     }
@@ -98,24 +141,61 @@ impl WriteRouter {
     /// Route [WriteRecord] to their corresponding [FastqWriter].
     ///
     /// This blocks to exert backpressure. When the sender is dropped, waits for all writers to
-    /// finish writing and then returns.
-    pub fn route(&mut self) -> Result<(), RouteError> {
+    /// finish writing and then returns. If routing or any writer fails, the remaining
+    /// writers are still finished, and a writer's error is returned over the routing
+    /// error it caused.
+    pub fn route(&mut self) -> Result<(), IlluvatarError> {
+        let mut routed = Ok(());
         while let Ok(msg) = self.write_recv.recv() {
-            self.route_record(msg)?
+            if let Err(e) = self.route_record(msg) {
+                routed = Err(e);
+                break;
+            }
         }
+        // hang up, so that if routing failed the demux workers stop sending
+        drop(std::mem::replace(&mut self.write_recv, never()));
         // channel is dead, time to cleanup
         self.lookup.clear(); // trigger writers to finish and flush
-        let mut finished = false;
-        while !finished {
-            finished = self.handles.iter().all(|h| h.is_finished());
-        }
+        let handles = std::mem::take(&mut self.handles);
+        let written = self.runtime.block_on(async {
+            let mut written = Ok(());
+            for handle in handles {
+                let finished = match handle.await {
+                    Ok(finished) => finished,
+                    Err(e) => Err(RouteError::from(e).into()),
+                };
+                if written.is_ok() {
+                    written = finished;
+                }
+            }
+            written
+        });
         debug!("router is exiting");
-        Ok(())
+        written?;
+        Ok(routed?)
+    }
+
+    /// Read lengths of every record routed so far, keyed by destination
+    pub fn read_lengths(&self) -> &ReadLengthStats {
+        &self.read_lengths
+    }
+
+    /// Records routed so far, for the whole run and per lane
+    pub fn stats(&self) -> &DemuxStats {
+        &self.stats
     }
 
     /// Send a [WriteRecord] to its final destination
-    fn route_record(&self, msg: WriteRecord) -> Result<(), RouteError> {
-        if let Some(destination) = self.lookup.get(&msg.destination) {
+    fn route_record(&mut self, msg: WriteRecord) -> Result<(), RouteError> {
+        let destination = self.lookup.get(&msg.destination).and_then(|writers| {
+            writers
+                .iter()
+                .find(|(lane, _)| lane.map_or(true, |l| l == msg.lane))
+                .map(|(_, send)| send)
+        });
+        if let Some(destination) = destination {
+            self.read_lengths.record(&msg.destination, msg.reads.len());
+            self.stats.record(msg.lane, &msg.destination);
             destination.send(msg)?
         } else {
             return Err(RouteError::UnknownDestination(msg.destination));
@@ -132,78 +212,502 @@ pub enum RouteError {
     TrySendError(#[from] TrySendError<WriteRecord>),
     #[error("attempt to write to unknown destination {0}")]
     UnknownDestination(String),
+    #[error("writer task failed: {0}")]
+    WriterFailed(#[from] tokio::task::JoinError),
+    #[error("{needed} output files are needed, but at most {max} can be written at once")]
+    TooManyWriters { needed: usize, max: usize },
+    #[error("Sample_Project {project:?} of sample {sample} is not a directory name")]
+    InvalidProject { sample: String, project: String },
+}
+
+/// When a [FastqWriter] flushes its buffer to disk
+///
+/// Flushing periodically means a crash leaves a consistent prefix of each
+/// output file rather than losing everything still sitting in the buffer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Only flush once all records have been written
+    #[default]
+    AtEnd,
+    /// Flush after every N records
+    Records(usize),
+    /// Flush after a record if at least this long has passed since the last flush
+    Interval(Duration),
+}
+
+/// How output files are created by [data_to_writers]
+#[derive(Debug, Clone, Default)]
+pub struct WriterOptions {
+    /// Capacity of each writer's channel
+    pub cap: usize,
+    pub flush_policy: FlushPolicy,
+    /// Append to existing output files instead of truncating them
+    ///
+    /// This supports distributed demux where each node writes different lanes
+    /// into shared storage. The lanes about to be written are checked against
+    /// existing records, warning if any are already present. With a compressor,
+    /// each invocation appends a new gzip member, and a multi-member `.fastq.gz`
+    /// is still a valid gzip file.
+    pub append: bool,
+    /// Lanes this invocation will write, used to detect duplicated data when appending
+    pub lanes: Vec<u8>,
+    /// Write each lane of a sample to its own files, named `_L<lane>_` as BCL Convert does
+    pub split_lanes: bool,
+    /// Template reads of the run, each sample getting `_R1` through `_R<n>`
+    pub template_reads: u8,
+    pub format: OutputFormat,
+    /// How the `S{num}` in output filenames is assigned
+    pub numbering: SampleNumbering,
+    /// Pipe FASTQ through this external gzip compressor, e.g. `pigz -p 8`
+    pub compressor: Option<String>,
+    /// `Sample_Project` of each sample, whose files are written into a directory
+    /// of that name, as BCL Convert does. Samples without one stay at the top level.
+    pub projects: FxHashMap<String, String>,
+}
+
+impl WriterOptions {
+    /// Extension of each output file, `fastq.gz` when piping to a compressor
+    pub fn extension(&self) -> String {
+        match (&self.compressor, self.format) {
+            (Some(_), OutputFormat::Fastq) => format!("{}.gz", self.format.extension()),
+            _ => self.format.extension().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum NumberingError {
+    #[error("Unable to read sample number mapping")]
+    IoError(#[from] std::io::Error),
+    #[error("Line {line} of the sample number mapping is not `Sample_ID,number`")]
+    InvalidLine { line: usize },
+    #[error("Sample number 0 given for {0}, sample numbers start at 1")]
+    ZeroNumber(String),
+    #[error("Sample number {number} is mapped to both {first} and {second}")]
+    DuplicateNumber {
+        number: u32,
+        first: String,
+        second: String,
+    },
+}
+
+/// Assigns the `S{num}` sample number used in output filenames
+///
+/// By default samples are numbered by their first appearance in the sample
+/// sheet, starting at 1, and a sample listed on several lanes keeps one number.
+/// Invocations that demux different lanes or sheets into the same output
+/// directory can pin numbers to sample IDs with an explicit mapping, and/or start
+/// positional numbering after an offset, so that two invocations never both
+/// write an `S1`. Positional numbers skip any number taken by the mapping.
+#[derive(Debug, Clone, Default)]
+pub struct SampleNumbering {
+    offset: u32,
+    explicit: FxHashMap<String, u32>,
+    assigned: FxHashMap<String, u32>,
+    next: u32,
+}
+
+impl SampleNumbering {
+    /// Positional numbering that starts at `offset + 1`
+    pub fn with_offset(offset: u32) -> Self {
+        SampleNumbering {
+            offset,
+            ..Default::default()
+        }
+    }
+
+    /// Pin sample numbers to sample IDs, falling back to positional numbering
+    pub fn with_mapping(mut self, mapping: FxHashMap<String, u32>) -> Result<Self, NumberingError> {
+        let mut by_number: FxHashMap<u32, &String> = FxHashMap::default();
+        for (sample_id, number) in mapping.iter() {
+            if *number == 0 {
+                return Err(NumberingError::ZeroNumber(sample_id.clone()));
+            }
+            if let Some(first) = by_number.insert(*number, sample_id) {
+                return Err(NumberingError::DuplicateNumber {
+                    number: *number,
+                    first: first.clone(),
+                    second: sample_id.clone(),
+                });
+            }
+        }
+        self.explicit = mapping;
+        Ok(self)
+    }
+
+    /// Read a `Sample_ID,number` mapping, one sample per line
+    ///
+    /// Blank lines and a `Sample_ID` header line are skipped.
+    pub fn read_mapping<P: AsRef<Path>>(path: P) -> Result<FxHashMap<String, u32>, NumberingError> {
+        let mut mapping = FxHashMap::default();
+        let reader = BufReader::new(File::open(path)?);
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("Sample_ID") {
+                continue;
+            }
+            let (sample_id, number) = line
+                .split_once(',')
+                .and_then(|(id, n)| Some((id.trim(), n.trim().parse::<u32>().ok()?)))
+                .ok_or(NumberingError::InvalidLine { line: i + 1 })?;
+            mapping.insert(sample_id.to_string(), number);
+        }
+        Ok(mapping)
+    }
+
+    /// The number for `sample_id`, assigning the next free one on first sight
+    pub fn number(&mut self, sample_id: &str) -> u32 {
+        if let Some(number) = self.explicit.get(sample_id) {
+            return *number;
+        }
+        if let Some(number) = self.assigned.get(sample_id) {
+            return *number;
+        }
+        let taken = self.explicit.values().copied().collect::<Vec<u32>>();
+        let mut number = self.offset + self.next + 1;
+        while taken.contains(&number) {
+            self.next += 1;
+            number += 1;
+        }
+        self.next += 1;
+        self.assigned.insert(sample_id.to_string(), number);
+        number
+    }
+}
+
+/// The files [data_to_writers] created for a single sample
+#[derive(Debug, Clone)]
+pub struct SampleOutputs {
+    pub sample_id: String,
+    /// The lane these files hold, `None` if they hold every lane
+    pub lane: Option<u8>,
+    /// The `S{num}` in the output filenames
+    pub sample_number: u32,
+    pub index: Option<String>,
+    pub index2: Option<String>,
+    /// One file per template read, R1 first
+    pub reads: Vec<PathBuf>,
+    pub index_fastq: Option<PathBuf>,
 }
 
 // Initialize file writers for each row of samplesheet data
+//
+// Undetermined outputs are installed too, as sample 0, so like BCL Convert they
+// exist even when no read is undetermined. See [remove_empty_undetermined].
 pub(crate) fn data_to_writers<P: AsRef<Path>>(
     router: &mut WriteRouter,
     data: &[SampleSheetData],
     settings: &SampleSheetSettings,
     output_directory: P,
-    writer_cap: usize,
-) -> Result<(), IlluvatarError> {
-    for sample in data.iter() {
-        let r1_path = output_directory
-            .as_ref()
-            .join(format!("{}_R1.fastq", sample.sample_id));
-        let r2_path = output_directory
-            .as_ref()
-            .join(format!("{}_R2.fastq", sample.sample_id));
-
-        let r1_file = File::create(&r1_path)?;
-        let r2_file = File::create(&r2_path)?;
-
-        let r1_writer = FastqWriter {
-            inner: BufWriter::new(r1_file),
-        };
-        let r2_writer = FastqWriter {
-            inner: BufWriter::new(r2_file),
-        };
+    options: &WriterOptions,
+) -> Result<Vec<SampleOutputs>, IlluvatarError> {
+    // a sample listed on several lanes writes all of them to the same files,
+    // unless lanes are split
+    let samples = data
+        .iter()
+        .enumerate()
+        .filter(|(i, s)| !data[..*i].iter().any(|d| d.sample_id == s.sample_id))
+        .map(|(_, s)| s)
+        .collect::<Vec<&SampleSheetData>>();
+    // every writer holds a blocking thread, so refuse up front rather than
+    // have writers past the limit never start and routing hang
+    let per_output =
+        usize::from(options.template_reads) + usize::from(settings.create_fastq_for_index_reads);
+    let needed = samples
+        .iter()
+        .map(|s| sample_lanes(data, &s.sample_id, options).len())
+        .chain([sample_lanes(&[], UNDETERMINED_PREFIX, options).len()])
+        .sum::<usize>()
+        * per_output;
+    if needed > MAX_WRITERS {
+        return Err(RouteError::TooManyWriters {
+            needed,
+            max: MAX_WRITERS,
+        }
+        .into());
+    }
+
+    let mut outputs = Vec::with_capacity(samples.len() + 1);
+    let mut numbering = options.numbering.clone();
+    for sample in samples {
+        let sample_number = numbering.number(&sample.sample_id);
+        let lanes = sample_lanes(data, &sample.sample_id, options);
+        for lane in lanes {
+            let mut sample_outputs = install_sample(
+                router,
+                &sample.sample_id,
+                sample_number,
+                lane,
+                settings.create_fastq_for_index_reads,
+                output_directory.as_ref(),
+                options,
+            )?;
+            sample_outputs.index = sample.index.clone();
+            sample_outputs.index2 = sample.index2.clone();
+            outputs.push(sample_outputs);
+        }
+    }
+    for lane in sample_lanes(&[], UNDETERMINED_PREFIX, options) {
+        outputs.push(install_sample(
+            router,
+            UNDETERMINED_PREFIX,
+            0,
+            lane,
+            settings.create_fastq_for_index_reads,
+            output_directory.as_ref(),
+            options,
+        )?);
+    }
+    Ok(outputs)
+}
+
+/// Lanes that get their own files for `sample_id`, or a single `None` if lanes are not split
+///
+/// Samples without a lane, and Undetermined, are on every lane being written.
+fn sample_lanes(
+    data: &[SampleSheetData],
+    sample_id: &str,
+    options: &WriterOptions,
+) -> Vec<Option<u8>> {
+    if !options.split_lanes {
+        return vec![None];
+    }
+    let rows = data
+        .iter()
+        .filter(|d| d.sample_id == sample_id)
+        .collect::<Vec<&SampleSheetData>>();
+    options
+        .lanes
+        .iter()
+        .filter(|lane| rows.is_empty() || rows.iter().any(|d| d.lane.map_or(true, |l| l == **lane)))
+        .map(|lane| Some(*lane))
+        .collect()
+}
+
+fn install_sample(
+    router: &mut WriteRouter,
+    sample_id: &str,
+    sample_number: u32,
+    lane: Option<u8>,
+    index_fastq: bool,
+    output_directory: &Path,
+    options: &WriterOptions,
+) -> Result<SampleOutputs, IlluvatarError> {
+    let output_directory = match options.projects.get(sample_id) {
+        Some(project) => {
+            // the project comes from the sheet, so keep it from escaping the output
+            let mut components = Path::new(project).components();
+            if !matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+            ) {
+                return Err(RouteError::InvalidProject {
+                    sample: sample_id.to_string(),
+                    project: project.to_string(),
+                }
+                .into());
+            }
+            let project_directory = output_directory.join(project);
+            fs::create_dir_all(&project_directory)?;
+            project_directory
+        }
+        None => output_directory.to_path_buf(),
+    };
+    let extension = options.extension();
+    let stem = match lane {
+        Some(lane) => format!("{sample_id}_S{sample_number}_L{lane:03}"),
+        None => format!("{sample_id}_S{sample_number}"),
+    };
+    let mut reads = Vec::with_capacity(usize::from(options.template_reads));
+    for read in 1..=options.template_reads {
+        let path = output_directory.join(format!("{stem}_R{read}.{extension}"));
+        install_output(
+            router,
+            format!("{sample_id}_R{read}"),
+            lane,
+            &path,
+            sample_id,
+            options,
+        )?;
+        reads.push(path);
+    }
+
+    let mut sample_outputs = SampleOutputs {
+        sample_id: sample_id.to_string(),
+        lane,
+        sample_number,
+        index: None,
+        index2: None,
+        reads,
+        index_fastq: None,
+    };
 
-        let r1_key = format!("{}_R1", sample.sample_id);
-        let r2_key = format!("{}_R2", sample.sample_id);
-        router.install_writer(r1_key, r1_writer, writer_cap)?;
-        router.install_writer(r2_key, r2_writer, writer_cap)?;
+    if index_fastq {
+        let index_path = output_directory.join(format!("{stem}_index.{extension}"));
+        let index_key = format!("{sample_id}_index");
+        install_output(router, index_key, lane, &index_path, sample_id, options)?;
+        sample_outputs.index_fastq = Some(index_path);
+    }
+    Ok(sample_outputs)
+}
 
-        if settings.create_fastq_for_index_reads {
-            let index_path = output_directory
-                .as_ref()
-                .join(format!("{}_index.fastq", sample.sample_id));
-            let index_file = OpenOptions::new().write(true).open(&index_path)?;
-            let index_writer = FastqWriter {
-                inner: BufWriter::new(index_file),
-            };
-            let index_key = format!("{}_index", sample.sample_id);
-            router.install_writer(index_key, index_writer, writer_cap)?;
+/// Delete the Undetermined outputs if no read was undetermined
+///
+/// Call once routing has finished, for `--no-empty-undetermined`. Compressed
+/// outputs are never zero bytes, so this goes by `stats` rather than file size.
+/// Outputs split by lane are removed lane by lane.
+pub fn remove_empty_undetermined(
+    outputs: &[SampleOutputs],
+    stats: &DemuxStats,
+) -> Result<(), IlluvatarError> {
+    let undetermined = |lane: Option<u8>| match lane {
+        Some(lane) => stats.lane(lane).map_or(0, |counts| counts.undetermined()),
+        None => stats.run().undetermined(),
+    };
+    for output in outputs
+        .iter()
+        .filter(|o| o.sample_id == UNDETERMINED_PREFIX && undetermined(o.lane) == 0)
+    {
+        for path in output.reads.iter().chain(output.index_fastq.as_ref()) {
+            std::fs::remove_file(path)?;
         }
     }
     Ok(())
 }
 
+/// Open `path` and install a writer for it in the requested output format
+#[cfg_attr(not(feature = "ubam"), allow(unused_variables))]
+fn install_output(
+    router: &mut WriteRouter,
+    key: String,
+    lane: Option<u8>,
+    path: &Path,
+    read_group: &str,
+    options: &WriterOptions,
+) -> Result<(), IlluvatarError> {
+    if let (Some(command), OutputFormat::Fastq) = (&options.compressor, options.format) {
+        let writer = PipeWriter::spawn(command, open_output(path, options)?, options.flush_policy)?;
+        return router.install_writer(key, lane, writer, options.cap);
+    }
+    let file = BufWriter::new(open_output(path, options)?);
+    match options.format {
+        OutputFormat::Fastq => router.install_writer(
+            key,
+            lane,
+            FastqWriter::with_policy(file, options.flush_policy),
+            options.cap,
+        ),
+        #[cfg(feature = "ubam")]
+        OutputFormat::Ubam => {
+            router.install_writer(key, lane, UbamWriter::new(file, read_group)?, options.cap)
+        }
+    }
+}
+
+/// Create or truncate an output file, or open it for appending
+fn open_output(path: &Path, options: &WriterOptions) -> Result<File, IlluvatarError> {
+    if !options.append {
+        return Ok(File::create(path)?);
+    }
+    if path.is_file() {
+        let present = lanes_in_fastq(path)?;
+        for lane in options.lanes.iter().filter(|l| present.contains(l)) {
+            warn!(
+                "{} already contains reads from lane {lane}, appending will duplicate them",
+                path.display()
+            );
+        }
+    }
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+/// Collect the lanes of every record in an existing FASTQ
+///
+/// Lanes are read from the fourth field of Illumina read IDs
+/// (`@instrument:run:flowcell:lane:...`). This reads the whole file,
+/// decompressing every member of a `.fastq.gz`.
+fn lanes_in_fastq(path: &Path) -> Result<Vec<u8>, IlluvatarError> {
+    let file = File::open(path)?;
+    let reader: Box<dyn BufRead> = match path.extension() {
+        Some(ext) if ext == "gz" => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+        _ => Box::new(BufReader::new(file)),
+    };
+    let mut lanes = Vec::new();
+    for line in reader.lines().step_by(4) {
+        let line = line?;
+        if let Some(Ok(lane)) = line.split(':').nth(3).map(str::parse::<u8>) {
+            if !lanes.contains(&lane) {
+                lanes.push(lane);
+            }
+        }
+    }
+    Ok(lanes)
+}
+
 // TODO move this elsewhere
 pub(crate) struct FastqWriter<W: Write> {
     inner: W,
+    flush_policy: FlushPolicy,
+    since_flush: usize,
+    last_flush: Instant,
+}
+
+impl<W: Write> FastqWriter<W> {
+    pub(crate) fn with_policy(inner: W, flush_policy: FlushPolicy) -> Self {
+        FastqWriter {
+            inner,
+            flush_policy,
+            since_flush: 0,
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// Flush if the flush policy says it is time to
+    fn maybe_flush(&mut self) -> Result<(), IlluvatarError> {
+        self.since_flush += 1;
+        let due = match self.flush_policy {
+            FlushPolicy::AtEnd => false,
+            FlushPolicy::Records(n) => self.since_flush >= n,
+            FlushPolicy::Interval(interval) => self.last_flush.elapsed() >= interval,
+        };
+        if due {
+            self.inner.flush()?;
+            self.since_flush = 0;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
 }
 
 impl FastqWriter<BufWriter<File>> {
     fn new<P: AsRef<Path>>(path: P) -> Result<FastqWriter<BufWriter<File>>, IlluvatarError> {
         let file = File::open(path)?;
-        Ok(FastqWriter {
-            inner: BufWriter::new(file),
-        })
+        Ok(FastqWriter::with_policy(
+            BufWriter::new(file),
+            FlushPolicy::default(),
+        ))
     }
+}
 
-    /// Write a single fastq record to the file
-    fn write_record(&mut self, record: WriteRecord) -> Result<(), IlluvatarError> {
+/// The default sink, writing FASTQ text to any [Write]
+impl<W: Write> WriteSink for FastqWriter<W> {
+    /// Write a single fastq record
+    fn write_record(&mut self, record: &WriteRecord) -> Result<(), IlluvatarError> {
         writeln!(self.inner, "{}", record.id)?;
         writeln!(self.inner, "{}", record.reads)?;
         writeln!(self.inner, "+")?;
         writeln!(self.inner, "{}", record.qual)?;
+        self.maybe_flush()
+    }
+
+    fn finish(&mut self) -> Result<(), IlluvatarError> {
+        self.inner.flush()?;
         Ok(())
     }
 }
 
-impl RoutableWrite for FastqWriter<BufWriter<File>> {
+impl<S: WriteSink + Send> RoutableWrite for S {
     type RouteRecv = Receiver<WriteRecord>;
     type RouteSend = Sender<WriteRecord>;
 
@@ -214,7 +718,7 @@ impl RoutableWrite for FastqWriter<BufWriter<File>> {
 
     async fn write(&mut self, recv: Self::RouteRecv) -> Result<(), IlluvatarError> {
         while let Ok(record) = recv.recv() {
-            match self.write_record(record) {
+            match self.write_record(&record) {
                 Ok(()) => {}
                 Err(e) => {
                     debug!("failed to write record");
@@ -226,7 +730,96 @@ impl RoutableWrite for FastqWriter<BufWriter<File>> {
         }
         // receiver is dead, assume this is fine and flush
         debug!("WRITER EXITING");
-        self.inner.flush()?;
-        Ok(())
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcl::testutil::{gzip, scratch_dir};
+
+    #[test]
+    fn lanes_are_found_in_every_gzip_member() {
+        let path = scratch_dir("appended-gz").join("S1_S1_R1.fastq.gz");
+        let mut appended =
+            gzip(b"@A01234:1:HXXXXXDSX:1:1101:1000:1000 1:N:0:ACGT\nACGT\n+\nFFFF\n");
+        appended.extend(gzip(
+            b"@A01234:1:HXXXXXDSX:2:1101:1000:1000 1:N:0:ACGT\nACGT\n+\nFFFF\n",
+        ));
+        std::fs::write(&path, appended).unwrap();
+
+        assert_eq!(lanes_in_fastq(&path).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn samples_get_a_fastq_per_template_read() {
+        let dir = scratch_dir("template-reads");
+        let (mut router, send) = WriteRouter::new(16, 1).unwrap();
+        let options = WriterOptions {
+            cap: 16,
+            template_reads: 3,
+            ..Default::default()
+        };
+        let outputs = install_sample(&mut router, "S1", 1, None, false, &dir, &options).unwrap();
+        assert_eq!(
+            outputs.reads,
+            vec![
+                dir.join("S1_S1_R1.fastq"),
+                dir.join("S1_S1_R2.fastq"),
+                dir.join("S1_S1_R3.fastq"),
+            ]
+        );
+
+        send.send(WriteRecord {
+            lane: 1,
+            id: "@A01234:1:HXXXXXDSX:1:1101:0:0 3:N:0:".to_string(),
+            reads: "ACGT".to_string(),
+            qual: "FFFF".to_string(),
+            destination: "S1_R3".to_string(),
+            barcode: None,
+            umi: None,
+        })
+        .unwrap();
+        drop(send);
+        router.route().unwrap();
+        assert!(std::fs::read_to_string(dir.join("S1_S1_R3.fastq"))
+            .unwrap()
+            .contains("ACGT"));
+    }
+
+    #[test]
+    fn samples_are_written_into_their_project() {
+        let dir = scratch_dir("sample-projects");
+        let (mut router, _send) = WriteRouter::new(16, 1).unwrap();
+        let options = WriterOptions {
+            cap: 16,
+            template_reads: 1,
+            projects: [("S1".to_string(), "ProjA".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let outputs = install_sample(&mut router, "S1", 1, None, true, &dir, &options).unwrap();
+        assert_eq!(outputs.reads, vec![dir.join("ProjA/S1_S1_R1.fastq")]);
+        assert_eq!(
+            outputs.index_fastq,
+            Some(dir.join("ProjA/S1_S1_index.fastq"))
+        );
+        let outputs = install_sample(&mut router, "S2", 2, None, false, &dir, &options).unwrap();
+        assert_eq!(outputs.reads, vec![dir.join("S2_S2_R1.fastq")]);
+
+        let options = WriterOptions {
+            projects: [("S3".to_string(), "../elsewhere".to_string())]
+                .into_iter()
+                .collect(),
+            ..options
+        };
+        assert!(matches!(
+            install_sample(&mut router, "S3", 3, None, false, &dir, &options),
+            Err(IlluvatarError::RouteError(
+                RouteError::InvalidProject { .. }
+            ))
+        ));
     }
 }
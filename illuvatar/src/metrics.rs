@@ -0,0 +1,193 @@
+// Prometheus text exposition of demux/monitor progress.
+// This only formats counters, serving them is left to the caller.
+
+use std::{fmt::Write, time::Duration};
+
+use fxhash::FxHashMap;
+
+use crate::accumulator::{DemuxCounts, UNDETERMINED_PREFIX};
+
+/// Point-in-time copy of the counters we export
+#[derive(Debug, Default, Clone)]
+pub struct MetricsSnapshot {
+    /// Number of sequencing directories in each state, keyed by state name
+    pub dirs_per_state: FxHashMap<String, u64>,
+    /// Reads demultiplexed per sample, keyed by sample ID
+    pub reads_per_sample: FxHashMap<String, u64>,
+    pub undetermined_reads: u64,
+    pub tiles_decoded: u64,
+    /// Seconds since demux started
+    pub elapsed_secs: f64,
+}
+
+impl MetricsSnapshot {
+    /// Snapshot of a finished demux, counting each sample from its `<Sample_ID><suffix>` destination
+    ///
+    /// Every read of a cluster has a destination of its own, so pass `_R1` to
+    /// count each cluster once, or `""` when destinations are plain Sample_IDs.
+    pub fn from_demux(
+        counts: &DemuxCounts,
+        suffix: &str,
+        tiles_decoded: u64,
+        elapsed: Duration,
+    ) -> Self {
+        let mut snapshot = MetricsSnapshot {
+            tiles_decoded,
+            elapsed_secs: elapsed.as_secs_f64(),
+            ..Default::default()
+        };
+        for (destination, n) in counts.per_destination.iter() {
+            match destination.strip_suffix(suffix) {
+                Some(UNDETERMINED_PREFIX) => snapshot.undetermined_reads += n,
+                Some(sample) => {
+                    *snapshot
+                        .reads_per_sample
+                        .entry(sample.to_string())
+                        .or_default() += n
+                }
+                None => {}
+            }
+        }
+        snapshot
+    }
+
+    pub fn total_reads(&self) -> u64 {
+        self.reads_per_sample.values().sum::<u64>() + self.undetermined_reads
+    }
+
+    pub fn undetermined_fraction(&self) -> f64 {
+        match self.total_reads() {
+            0 => 0.0,
+            total => self.undetermined_reads as f64 / total as f64,
+        }
+    }
+
+    /// Reads demultiplexed per second
+    pub fn throughput(&self) -> f64 {
+        if self.elapsed_secs > 0.0 {
+            self.total_reads() as f64 / self.elapsed_secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Render in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "illuvatar_dirs",
+            "gauge",
+            "sequencing directories per state",
+        );
+        for (state, count) in sorted(&self.dirs_per_state) {
+            // writing to a String cannot fail
+            let _ = writeln!(out, "illuvatar_dirs{{state=\"{}\"}} {count}", escape(state));
+        }
+
+        write_header(
+            &mut out,
+            "illuvatar_sample_reads_total",
+            "counter",
+            "reads demultiplexed per sample",
+        );
+        for (sample, count) in sorted(&self.reads_per_sample) {
+            let _ = writeln!(
+                out,
+                "illuvatar_sample_reads_total{{sample=\"{}\"}} {count}",
+                escape(sample)
+            );
+        }
+
+        write_metric(
+            &mut out,
+            "illuvatar_undetermined_reads_total",
+            "counter",
+            "reads that could not be assigned to a sample",
+            self.undetermined_reads,
+        );
+        write_metric(
+            &mut out,
+            "illuvatar_undetermined_fraction",
+            "gauge",
+            "fraction of reads that could not be assigned to a sample",
+            self.undetermined_fraction(),
+        );
+        write_metric(
+            &mut out,
+            "illuvatar_tiles_decoded_total",
+            "counter",
+            "tiles decoded from cbcl files",
+            self.tiles_decoded,
+        );
+        write_metric(
+            &mut out,
+            "illuvatar_reads_per_second",
+            "gauge",
+            "demultiplexing throughput",
+            self.throughput(),
+        );
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn write_metric<V: std::fmt::Display>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: V,
+) {
+    write_header(out, name, kind, help);
+    let _ = writeln!(out, "{name} {value}");
+}
+
+/// Stable output order makes scrapes diffable
+fn sorted(map: &FxHashMap<String, u64>) -> Vec<(&String, &u64)> {
+    let mut entries = map.iter().collect::<Vec<_>>();
+    entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
+/// Label values must escape backslashes, quotes, and newlines
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demux_counts_each_cluster_once() {
+        let mut counts = DemuxCounts::default();
+        for (destination, n) in [
+            ("S1_R1", 6),
+            ("S1_R2", 6),
+            ("S1_index", 6),
+            ("Undetermined_R1", 2),
+            ("Undetermined_R2", 2),
+        ] {
+            counts.per_destination.insert(destination.to_string(), n);
+        }
+        let snapshot = MetricsSnapshot::from_demux(&counts, "_R1", 4, Duration::from_secs(2));
+
+        assert_eq!(snapshot.reads_per_sample.get("S1"), Some(&6));
+        assert_eq!(snapshot.reads_per_sample.len(), 1);
+        assert_eq!(snapshot.undetermined_reads, 2);
+        assert_eq!(snapshot.undetermined_fraction(), 0.25);
+        assert_eq!(snapshot.throughput(), 4.0);
+        assert!(snapshot
+            .to_prometheus()
+            .contains("illuvatar_sample_reads_total{sample=\"S1\"} 6\n"));
+    }
+}
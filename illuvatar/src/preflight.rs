@@ -0,0 +1,687 @@
+// Cheap checks run before any decoding starts, so that problems which would
+// otherwise surface hours into a demux are reported in seconds instead.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs::{self, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use rayon::prelude::*;
+use serde::Deserialize;
+
+use crate::{
+    assemble::layout::{verify_read_structure_consistency, RunRead},
+    bcl::integrity::{
+        diff_tile_sets, find_bcls, find_cbcls, header_tile_numbers, lane_cluster_report,
+        lane_cycle_bcls, lane_dirs, BASECALLS_DIR,
+    },
+    resolve::Sample,
+    runinfo::{
+        parameters::{RunParameters, RunParametersError, RUN_PARAMETERS},
+        RunInfo,
+    },
+};
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PreflightCheck {
+    /// Every cbcl header parses, and every cycle of a lane declares the same tiles
+    ///
+    /// Runs of per-tile bcls have no headers, so only their tiles are compared.
+    Cbcls,
+    /// The output directory exists, or can be created, and is writable
+    Output,
+    /// Every tile RunInfo declares is listed in the cbcl headers with clusters
+    Clusters,
+    /// Every lane has samples, warning about lanes whose reads would all be undetermined
+    Samples,
+    /// RunInfo.xml, RunParameters.xml and the sample sheet agree on each read's cycles
+    Reads,
+    /// There is a lane directory for each lane RunInfo.xml declares, and no others
+    Lanes,
+    /// Every lane directory has a cycle directory for each cycle RunInfo.xml declares
+    Cycles,
+    /// Every sample is on a lane RunInfo.xml declares
+    SampleLanes,
+    /// Every sample's indexes fit the index reads RunInfo.xml declares
+    Indexes,
+}
+
+impl std::fmt::Display for PreflightCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreflightCheck::Cbcls => write!(f, "cbcls"),
+            PreflightCheck::Output => write!(f, "output"),
+            PreflightCheck::Clusters => write!(f, "clusters"),
+            PreflightCheck::Samples => write!(f, "samples"),
+            PreflightCheck::Reads => write!(f, "reads"),
+            PreflightCheck::Lanes => write!(f, "lanes"),
+            PreflightCheck::Cycles => write!(f, "cycles"),
+            PreflightCheck::SampleLanes => write!(f, "sample-lanes"),
+            PreflightCheck::Indexes => write!(f, "indexes"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PreflightFailure {
+    pub check: PreflightCheck,
+    pub message: String,
+}
+
+/// Everything preflight found wrong, rather than just the first problem
+#[derive(Debug, Default)]
+pub struct PreflightReport {
+    pub failures: Vec<PreflightFailure>,
+    /// Problems that don't stop a demux, but likely aren't what was intended
+    pub warnings: Vec<PreflightFailure>,
+}
+
+impl PreflightReport {
+    pub fn is_clean(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    fn fail(&mut self, check: PreflightCheck, message: String) {
+        self.failures.push(PreflightFailure { check, message });
+    }
+
+    fn warn(&mut self, check: PreflightCheck, message: String) {
+        self.warnings.push(PreflightFailure { check, message });
+    }
+}
+
+/// Run every check not listed in `skip`
+///
+/// The sample sheet is not checked here, since it has already been parsed by
+/// the time this runs. `output` of `None` skips the output check, `run_info`
+/// of `None` the clusters and reads checks, and `samples` of `None` the
+/// samples check. `sheet_reads` are the sample sheet's [Reads], if it has any.
+pub fn preflight(
+    seq_dir: &Path,
+    output: Option<&Path>,
+    run_info: Option<&RunInfo>,
+    sheet_reads: Option<&[RunRead]>,
+    samples: Option<&[Sample]>,
+    skip: &[PreflightCheck],
+) -> PreflightReport {
+    let mut report = PreflightReport::default();
+    if !skip.contains(&PreflightCheck::Cbcls) {
+        check_cbcls(seq_dir, &mut report);
+    }
+    // older instruments don't list tiles in RunInfo.xml, so there is nothing to check
+    if let Some(declared) = run_info
+        .map(RunInfo::tiles)
+        .filter(|tiles| !tiles.is_empty() && !skip.contains(&PreflightCheck::Clusters))
+    {
+        check_clusters(seq_dir, declared, &mut report);
+    }
+    if let Some(run_info) = run_info.filter(|_| !skip.contains(&PreflightCheck::Reads)) {
+        check_reads(seq_dir, run_info, sheet_reads, &mut report);
+    }
+    if let Some(run_info) = run_info {
+        if !skip.contains(&PreflightCheck::Lanes) {
+            check_lanes(seq_dir, run_info, &mut report);
+        }
+        if !skip.contains(&PreflightCheck::Cycles) {
+            check_cycles(seq_dir, run_info, &mut report);
+        }
+        if let Some(samples) = samples {
+            if !skip.contains(&PreflightCheck::SampleLanes) {
+                check_sample_lanes(run_info, samples, &mut report);
+            }
+            if !skip.contains(&PreflightCheck::Indexes) {
+                check_indexes(run_info, samples, &mut report);
+            }
+        }
+    }
+    if let Some(samples) = samples.filter(|_| !skip.contains(&PreflightCheck::Samples)) {
+        check_samples(seq_dir, samples, &mut report);
+    }
+    if let Some(output) = output.filter(|_| !skip.contains(&PreflightCheck::Output)) {
+        check_output(output, &mut report);
+    }
+    report
+}
+
+/// Read every cbcl header, comparing each surface's tiles across cycles
+fn check_cbcls(seq_dir: &Path, report: &mut PreflightReport) {
+    let cbcls = match find_cbcls(seq_dir) {
+        Ok(cbcls) if cbcls.is_empty() => {
+            match find_bcls(seq_dir) {
+                Ok(bcls) if !bcls.is_empty() => check_bcls(seq_dir, report),
+                _ => report.fail(PreflightCheck::Cbcls, "no cbcls or bcls found".to_string()),
+            }
+            return;
+        }
+        Ok(cbcls) => cbcls,
+        Err(e) => {
+            report.fail(PreflightCheck::Cbcls, format!("unable to list cbcls: {e}"));
+            return;
+        }
+    };
+    let headers = cbcls
+        .par_iter()
+        .map(|path| (path, header_tile_numbers(path)))
+        .collect::<Vec<_>>();
+
+    // every cycle of a lane has the same surfaces, e.g. L001_1.cbcl, holding the same
+    // tiles in the same order, or reads cannot be assembled
+    let mut expected: BTreeMap<(PathBuf, &OsStr), (&PathBuf, Vec<u32>)> = BTreeMap::new();
+    for (path, tiles) in headers {
+        let tiles = match tiles {
+            Ok(tiles) => tiles,
+            Err(e) => {
+                report.fail(PreflightCheck::Cbcls, format!("{}: {e}", path.display()));
+                continue;
+            }
+        };
+        let (Some(cycle_dir), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+        let lane_dir = cycle_dir.parent().unwrap_or(cycle_dir).to_path_buf();
+        match expected.get(&(lane_dir.clone(), name)) {
+            Some((first, first_tiles)) => {
+                let diff = diff_tile_sets(first_tiles, &tiles);
+                if !diff.is_consistent() {
+                    report.fail(
+                        PreflightCheck::Cbcls,
+                        format!(
+                            "{} declares different tiles than {} (missing {:?}, extra {:?})",
+                            path.display(),
+                            first.display(),
+                            diff.missing,
+                            diff.extra
+                        ),
+                    );
+                } else if *first_tiles != tiles {
+                    report.fail(
+                        PreflightCheck::Cbcls,
+                        format!(
+                            "{} lists its tiles in a different order than {}",
+                            path.display(),
+                            first.display()
+                        ),
+                    );
+                }
+            }
+            None => {
+                expected.insert((lane_dir, name), (path, tiles));
+            }
+        }
+    }
+}
+
+/// Per-tile bcls have no headers, so only check every cycle of a lane has the same tiles
+fn check_bcls(seq_dir: &Path, report: &mut PreflightReport) {
+    let Ok(lanes) = lane_dirs(seq_dir) else {
+        return;
+    };
+    for (lane, lane_dir) in lanes {
+        let cycles = match lane_cycle_bcls(&lane_dir) {
+            Ok(cycles) => cycles,
+            Err(e) => {
+                report.fail(
+                    PreflightCheck::Cbcls,
+                    format!("{}: {e}", lane_dir.display()),
+                );
+                continue;
+            }
+        };
+        let names = |bcls: &[PathBuf]| {
+            bcls.iter()
+                .filter_map(|bcl| bcl.file_name().map(OsStr::to_os_string))
+                .collect::<Vec<_>>()
+        };
+        let Some(first) = cycles.first().map(|bcls| names(bcls)) else {
+            continue;
+        };
+        if let Some(cycle) = cycles.iter().position(|bcls| names(bcls) != first) {
+            report.fail(
+                PreflightCheck::Cbcls,
+                format!(
+                    "cycle {} of lane {lane} has different tiles than cycle 1",
+                    cycle + 1
+                ),
+            );
+        }
+    }
+}
+
+/// Confirm each lane's declared tiles are present and hold clusters
+fn check_clusters(seq_dir: &Path, declared: &BTreeMap<u8, Vec<u32>>, report: &mut PreflightReport) {
+    let basecalls = seq_dir.join(BASECALLS_DIR);
+    for (lane, tiles) in declared.iter() {
+        let lane_dir = basecalls.join(format!("L{lane:03}"));
+        let lane_report = match lane_cluster_report(tiles, &lane_dir) {
+            Ok(lane_report) => lane_report,
+            Err(e) => {
+                report.fail(
+                    PreflightCheck::Clusters,
+                    format!("{}: {e}", lane_dir.display()),
+                );
+                continue;
+            }
+        };
+        if !lane_report.tiles.missing.is_empty() {
+            report.fail(
+                PreflightCheck::Clusters,
+                format!(
+                    "lane {lane} is missing declared tiles {:?}",
+                    lane_report.tiles.missing
+                ),
+            );
+        }
+        if !lane_report.empty.is_empty() {
+            report.fail(
+                PreflightCheck::Clusters,
+                format!(
+                    "lane {lane} has no clusters in tiles {:?}",
+                    lane_report.empty
+                ),
+            );
+        }
+        if lane_report.expected != lane_report.present {
+            report.fail(
+                PreflightCheck::Clusters,
+                format!(
+                    "lane {lane} has {} clusters in declared tiles but {} in total (undeclared tiles {:?})",
+                    lane_report.expected, lane_report.present, lane_report.tiles.extra
+                ),
+            );
+        }
+    }
+}
+
+/// Cross-check each read's cycles across RunInfo.xml, RunParameters.xml and the sample sheet
+///
+/// Not every instrument writes RunParameters.xml, nor in a form that is understood,
+/// so a missing file or one without planned reads is only a warning.
+fn check_reads(
+    seq_dir: &Path,
+    run_info: &RunInfo,
+    sheet_reads: Option<&[RunRead]>,
+    report: &mut PreflightReport,
+) {
+    let path = seq_dir.join(RUN_PARAMETERS);
+    let run_parameters = match RunParameters::from_path(&path) {
+        Ok(run_parameters) => Some(run_parameters),
+        Err(RunParametersError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e @ RunParametersError::MissingPlannedReads) => {
+            report.warn(PreflightCheck::Reads, format!("{e}, not cross-checked"));
+            None
+        }
+        Err(e) => {
+            report.fail(PreflightCheck::Reads, format!("{}: {e}", path.display()));
+            None
+        }
+    };
+    for disagreement in verify_read_structure_consistency(
+        run_info.reads(),
+        run_parameters.as_ref().map(RunParameters::planned_cycles),
+        sheet_reads,
+    ) {
+        report.fail(PreflightCheck::Reads, disagreement.to_string());
+    }
+}
+
+/// Compare the lane directories present with the lanes RunInfo.xml declares
+fn check_lanes(seq_dir: &Path, run_info: &RunInfo, report: &mut PreflightReport) {
+    // a run without lane directories is already reported by the cbcls check
+    let Ok(lanes) = lane_dirs(seq_dir) else {
+        return;
+    };
+    let declared = 1..=run_info.lane_count();
+    let missing = declared
+        .clone()
+        .filter(|lane| !lanes.contains_key(lane))
+        .collect::<Vec<u8>>();
+    let extra = lanes
+        .keys()
+        .filter(|lane| !declared.contains(lane))
+        .copied()
+        .collect::<Vec<u8>>();
+    if !missing.is_empty() || !extra.is_empty() {
+        report.fail(
+            PreflightCheck::Lanes,
+            format!(
+                "RunInfo.xml declares {} lanes but {} lane directories were found (missing {missing:?}, extra {extra:?})",
+                run_info.lane_count(),
+                lanes.len()
+            ),
+        );
+    }
+}
+
+/// Confirm each lane directory holds a cycle directory for every cycle of the run
+fn check_cycles(seq_dir: &Path, run_info: &RunInfo, report: &mut PreflightReport) {
+    let Ok(lanes) = lane_dirs(seq_dir) else {
+        return;
+    };
+    let total_cycles = run_info
+        .reads()
+        .iter()
+        .map(|read| usize::from(read.cycles))
+        .sum::<usize>();
+    for (lane, lane_dir) in lanes {
+        match lane_cycle_bcls(&lane_dir) {
+            Ok(cycles) if cycles.len() == total_cycles => {}
+            Ok(cycles) => report.fail(
+                PreflightCheck::Cycles,
+                format!(
+                    "lane {lane} has {} cycle directories, but RunInfo.xml declares {total_cycles} cycles",
+                    cycles.len()
+                ),
+            ),
+            Err(e) => report.fail(
+                PreflightCheck::Cycles,
+                format!("{}: {e}", lane_dir.display()),
+            ),
+        }
+    }
+}
+
+/// Fail samples placed on a lane the flowcell doesn't have
+fn check_sample_lanes(run_info: &RunInfo, samples: &[Sample], report: &mut PreflightReport) {
+    for sample in samples.iter() {
+        if let Some(lane) = sample
+            .lane
+            .filter(|lane| !(1..=run_info.lane_count()).contains(lane))
+        {
+            report.fail(
+                PreflightCheck::SampleLanes,
+                format!(
+                    "sample {} is on lane {lane}, but RunInfo.xml declares {} lanes",
+                    sample.sample_id,
+                    run_info.lane_count()
+                ),
+            );
+        }
+    }
+}
+
+/// Fail samples with more indexes than index reads, or indexes longer than their read
+///
+/// Shorter indexes are fine, they are matched against the start of their read.
+fn check_indexes(run_info: &RunInfo, samples: &[Sample], report: &mut PreflightReport) {
+    let index_cycles = run_info
+        .reads()
+        .iter()
+        .filter(|read| read.is_index)
+        .map(|read| usize::from(read.cycles))
+        .collect::<Vec<usize>>();
+    for sample in samples.iter() {
+        if sample.indexes.len() > index_cycles.len() {
+            report.fail(
+                PreflightCheck::Indexes,
+                format!(
+                    "sample {} has {} indexes, but RunInfo.xml declares {} index reads",
+                    sample.sample_id,
+                    sample.indexes.len(),
+                    index_cycles.len()
+                ),
+            );
+            continue;
+        }
+        for (i, (index, cycles)) in sample.indexes.iter().zip(index_cycles.iter()).enumerate() {
+            if index.len() > *cycles {
+                report.fail(
+                    PreflightCheck::Indexes,
+                    format!(
+                        "index {} of sample {} is {} bases, but its index read has {cycles} cycles",
+                        i + 1,
+                        sample.sample_id,
+                        index.len()
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Warn about lanes no sample is on, since every one of their reads would be undetermined
+fn check_samples(seq_dir: &Path, samples: &[Sample], report: &mut PreflightReport) {
+    // a run without lane directories is already reported by the cbcls check
+    let Ok(lanes) = lane_dirs(seq_dir) else {
+        return;
+    };
+    for lane in lanes.keys() {
+        if !samples.iter().any(|s| s.lane.map_or(true, |l| l == *lane)) {
+            report.warn(
+                PreflightCheck::Samples,
+                format!("lane {lane} has no samples, every read will be undetermined"),
+            );
+        }
+    }
+}
+
+/// Create the directory if needed, then prove it is writable with a scratch file
+fn check_output(output: &Path, report: &mut PreflightReport) {
+    if let Err(e) = fs::create_dir_all(output) {
+        report.fail(
+            PreflightCheck::Output,
+            format!("unable to create {}: {e}", output.display()),
+        );
+        return;
+    }
+    let probe = output.join(".illuvatar-preflight");
+    match OpenOptions::new().create(true).write(true).open(&probe) {
+        Ok(_) => {
+            let _ = fs::remove_file(probe);
+        }
+        Err(e) => report.fail(
+            PreflightCheck::Output,
+            format!("{} is not writable: {e}", output.display()),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcl::testutil::scratch_dir;
+
+    /// A run directory with `cycles` empty cycle directories for each of `lanes`
+    fn run_dir(name: &str, lanes: &[u8], cycles: u16) -> PathBuf {
+        let dir = scratch_dir(name);
+        for lane in lanes {
+            let lane_dir = dir.join(BASECALLS_DIR).join(format!("L{lane:03}"));
+            for cycle in 1..=cycles {
+                fs::create_dir_all(lane_dir.join(format!("C{cycle}.1"))).unwrap();
+            }
+            fs::create_dir_all(lane_dir).unwrap();
+        }
+        dir
+    }
+
+    /// RunInfo.xml for a 5 cycle R1 and a 2 cycle i7
+    fn run_info(lane_count: u8) -> RunInfo {
+        RunInfo::parse(&format!(
+            r#"<RunInfo><Run Id="R" Number="1"><Reads>
+  <Read Number="1" NumCycles="5" IsIndexedRead="N"/>
+  <Read Number="2" NumCycles="2" IsIndexedRead="Y"/>
+</Reads><FlowcellLayout LaneCount="{lane_count}"/></Run></RunInfo>"#
+        ))
+        .unwrap()
+    }
+
+    fn sample(sample_id: &str, lane: Option<u8>) -> Sample {
+        Sample {
+            sample_id: sample_id.to_string(),
+            lane,
+            indexes: vec![b"AC".to_vec()],
+        }
+    }
+
+    fn messages(report: &PreflightReport, check: PreflightCheck) -> Vec<&str> {
+        report
+            .failures
+            .iter()
+            .filter(|f| f.check == check)
+            .map(|f| f.message.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn lanes_without_samples_are_warned_about() {
+        let dir = run_dir("preflight-samples", &[1, 2], 0);
+        let samples = [sample("S1", Some(1))];
+        let report = preflight(
+            &dir,
+            None,
+            None,
+            None,
+            Some(&samples),
+            &[PreflightCheck::Cbcls],
+        );
+
+        assert!(report.is_clean());
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].message.starts_with("lane 2 "));
+
+        let samples = [sample("S1", None)];
+        let report = preflight(
+            &dir,
+            None,
+            None,
+            None,
+            Some(&samples),
+            &[PreflightCheck::Cbcls],
+        );
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn reads_are_cross_checked_with_run_parameters() {
+        let dir = run_dir("preflight-reads", &[1], 7);
+        let run_info = run_info(1);
+        let skip = [PreflightCheck::Cbcls];
+
+        // without RunParameters.xml only the sheet is checked
+        let report = preflight(&dir, None, Some(&run_info), None, None, &skip);
+        assert!(report.is_clean());
+        assert!(report.warnings.is_empty());
+
+        fs::write(
+            dir.join(RUN_PARAMETERS),
+            "<Read1NumberOfCycles>5</Read1NumberOfCycles>\n\
+             <IndexRead1NumberOfCycles>1</IndexRead1NumberOfCycles>",
+        )
+        .unwrap();
+        let sheet = [
+            RunRead {
+                cycles: 5,
+                is_index: false,
+            },
+            RunRead {
+                cycles: 3,
+                is_index: true,
+            },
+        ];
+        let report = preflight(&dir, None, Some(&run_info), Some(&sheet), None, &skip);
+        assert_eq!(
+            messages(&report, PreflightCheck::Reads),
+            vec!["read 2 has 2 cycles in RunInfo.xml, 1 cycles in RunParameters.xml, 3 cycles in the sample sheet"]
+        );
+        assert_eq!(report.failures.len(), 1);
+
+        let skip = [PreflightCheck::Cbcls, PreflightCheck::Reads];
+        assert!(preflight(&dir, None, Some(&run_info), Some(&sheet), None, &skip).is_clean());
+    }
+
+    #[test]
+    fn lane_directories_must_match_the_lane_count() {
+        let dir = run_dir("preflight-lanes", &[1, 3], 7);
+        let report = preflight(
+            &dir,
+            None,
+            Some(&run_info(2)),
+            None,
+            None,
+            &[PreflightCheck::Cbcls],
+        );
+        assert_eq!(
+            messages(&report, PreflightCheck::Lanes),
+            vec!["RunInfo.xml declares 2 lanes but 2 lane directories were found (missing [2], extra [3])"]
+        );
+        assert_eq!(report.failures.len(), 1);
+        assert!(preflight(
+            &dir,
+            None,
+            Some(&run_info(3)),
+            None,
+            None,
+            &[PreflightCheck::Cbcls, PreflightCheck::Lanes],
+        )
+        .is_clean());
+    }
+
+    #[test]
+    fn lanes_must_have_every_cycle() {
+        let dir = run_dir("preflight-cycles", &[1], 6);
+        let report = preflight(
+            &dir,
+            None,
+            Some(&run_info(1)),
+            None,
+            None,
+            &[PreflightCheck::Cbcls],
+        );
+        assert_eq!(
+            messages(&report, PreflightCheck::Cycles),
+            vec!["lane 1 has 6 cycle directories, but RunInfo.xml declares 7 cycles"]
+        );
+        assert_eq!(report.failures.len(), 1);
+    }
+
+    #[test]
+    fn samples_must_be_on_declared_lanes() {
+        let dir = run_dir("preflight-sample-lanes", &[1], 7);
+        let samples = [sample("S1", Some(1)), sample("S2", Some(2))];
+        let report = preflight(
+            &dir,
+            None,
+            Some(&run_info(1)),
+            None,
+            Some(&samples),
+            &[PreflightCheck::Cbcls],
+        );
+        assert_eq!(
+            messages(&report, PreflightCheck::SampleLanes),
+            vec!["sample S2 is on lane 2, but RunInfo.xml declares 1 lanes"]
+        );
+        assert_eq!(report.failures.len(), 1);
+    }
+
+    #[test]
+    fn indexes_must_fit_their_index_reads() {
+        let dir = run_dir("preflight-indexes", &[1], 7);
+        let mut long = sample("S2", None);
+        long.indexes = vec![b"ACG".to_vec()];
+        let mut dual = sample("S3", None);
+        dual.indexes = vec![b"AC".to_vec(), b"GT".to_vec()];
+        let mut short = sample("S4", None);
+        short.indexes = vec![b"A".to_vec()];
+        let samples = [sample("S1", None), long, dual, short];
+
+        let report = preflight(
+            &dir,
+            None,
+            Some(&run_info(1)),
+            None,
+            Some(&samples),
+            &[PreflightCheck::Cbcls],
+        );
+        assert_eq!(
+            messages(&report, PreflightCheck::Indexes),
+            vec![
+                "index 1 of sample S2 is 3 bases, but its index read has 2 cycles",
+                "sample S3 has 2 indexes, but RunInfo.xml declares 1 index reads",
+            ]
+        );
+        assert_eq!(report.failures.len(), 2);
+    }
+}
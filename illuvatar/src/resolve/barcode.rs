@@ -0,0 +1,282 @@
+// Mismatch-tolerant barcode lookup.
+// Every variant of every sample's barcode within the allowed mismatches is
+// precomputed, so resolving a read's index is a single hash lookup.
+
+use fxhash::FxHashMap;
+use thiserror::Error;
+use triple_accel::hamming;
+
+/// Bases substituted when enumerating variants, N included since no-calls count as mismatches
+const VARIANT_BASES: [u8; 5] = [b'A', b'C', b'G', b'T', b'N'];
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum BarcodeError {
+    #[error("Sample {sample} has {got} index parts, expected {expected}")]
+    PartCountMismatch {
+        sample: usize,
+        expected: usize,
+        got: usize,
+    },
+    #[error("Index {part} of sample {sample} is {got} bases, expected {expected}")]
+    LengthMismatch {
+        sample: usize,
+        part: usize,
+        expected: usize,
+        got: usize,
+    },
+    #[error("{} pairs of samples are too similar for the allowed mismatches, e.g. samples {} and {}", .0.len(), .0[0].0, .0[0].1)]
+    UnsafeMismatches(Vec<(usize, usize)>),
+}
+
+/// Result of looking up an observed index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarcodeMatch {
+    /// Position of the matching sample, as passed to [BarcodeIndex::build]
+    Sample(usize),
+    /// Within the allowed mismatches of more than one sample
+    Ambiguous,
+}
+
+/// Precomputed map from every tolerated barcode variant to its sample
+///
+/// Barcodes have one or more parts (i7, then i5), each with its own
+/// mismatch allowance. Lookups take the parts concatenated in the same order.
+#[derive(Debug, Clone)]
+pub struct BarcodeIndex {
+    lookup: FxHashMap<Vec<u8>, BarcodeMatch>,
+    part_lengths: Vec<usize>,
+}
+
+impl BarcodeIndex {
+    /// Build an index, refusing barcodes that are ambiguous at these mismatches
+    ///
+    /// Two samples collide when every part is within twice its mismatch
+    /// allowance, since some variant is then within range of both.
+    pub fn new(barcodes: &[Vec<Vec<u8>>], mismatches: &[u8]) -> Result<Self, BarcodeError> {
+        let (index, collisions) = BarcodeIndex::build(barcodes, mismatches)?;
+        if collisions.is_empty() {
+            Ok(index)
+        } else {
+            Err(BarcodeError::UnsafeMismatches(collisions))
+        }
+    }
+
+    /// Build an index, marking variants shared by several samples as [BarcodeMatch::Ambiguous]
+    ///
+    /// Also returns the colliding sample pairs, so callers can decide whether to
+    /// warn or lower the mismatch allowance.
+    pub fn build(
+        barcodes: &[Vec<Vec<u8>>],
+        mismatches: &[u8],
+    ) -> Result<(Self, Vec<(usize, usize)>), BarcodeError> {
+        let part_lengths = match barcodes.first() {
+            Some(first) => first.iter().map(|p| p.len()).collect::<Vec<usize>>(),
+            None => Vec::new(),
+        };
+        for (sample, parts) in barcodes.iter().enumerate() {
+            if parts.len() != part_lengths.len() {
+                return Err(BarcodeError::PartCountMismatch {
+                    sample,
+                    expected: part_lengths.len(),
+                    got: parts.len(),
+                });
+            }
+            for (part, (barcode, expected)) in parts.iter().zip(part_lengths.iter()).enumerate() {
+                if barcode.len() != *expected {
+                    return Err(BarcodeError::LengthMismatch {
+                        sample,
+                        part,
+                        expected: *expected,
+                        got: barcode.len(),
+                    });
+                }
+            }
+        }
+
+        let allowed = |part: usize| u32::from(mismatches.get(part).copied().unwrap_or(0));
+        let mut collisions = Vec::new();
+        for (a, a_parts) in barcodes.iter().enumerate() {
+            for (b, b_parts) in barcodes.iter().enumerate().skip(a + 1) {
+                let collide = a_parts
+                    .iter()
+                    .zip(b_parts.iter())
+                    .enumerate()
+                    .all(|(part, (x, y))| hamming(x, y) <= 2 * allowed(part));
+                if collide {
+                    collisions.push((a, b));
+                }
+            }
+        }
+
+        let mut lookup = FxHashMap::default();
+        for (sample, parts) in barcodes.iter().enumerate() {
+            let per_part = parts
+                .iter()
+                .enumerate()
+                .map(|(part, barcode)| variants(barcode, allowed(part)))
+                .collect::<Vec<Vec<Vec<u8>>>>();
+            for key in concatenations(&per_part) {
+                lookup
+                    .entry(key)
+                    .and_modify(|m| {
+                        if *m != BarcodeMatch::Sample(sample) {
+                            *m = BarcodeMatch::Ambiguous
+                        }
+                    })
+                    .or_insert(BarcodeMatch::Sample(sample));
+            }
+        }
+
+        Ok((
+            BarcodeIndex {
+                lookup,
+                part_lengths,
+            },
+            collisions,
+        ))
+    }
+
+    /// Look up an observed index, every part concatenated in order
+    pub fn lookup(&self, index: &[u8]) -> Option<BarcodeMatch> {
+        self.lookup.get(index).copied()
+    }
+
+    /// Total length of the concatenated index that [lookup](BarcodeIndex::lookup) expects
+    pub fn index_len(&self) -> usize {
+        self.part_lengths.iter().sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lookup.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lookup.is_empty()
+    }
+}
+
+/// Every sequence within `max` substitutions of `barcode`, including itself
+fn variants(barcode: &[u8], max: u32) -> Vec<Vec<u8>> {
+    let mut out = vec![barcode.to_vec()];
+    let mut frontier = vec![(barcode.to_vec(), 0usize)];
+    for _ in 0..max {
+        let mut next = Vec::new();
+        for (seq, start) in frontier {
+            // only substitute at or after the last substituted position, so
+            // each variant is generated once
+            for pos in start..seq.len() {
+                for base in VARIANT_BASES {
+                    if base == barcode[pos] {
+                        continue;
+                    }
+                    let mut variant = seq.clone();
+                    variant[pos] = base;
+                    out.push(variant.clone());
+                    next.push((variant, pos + 1));
+                }
+            }
+        }
+        frontier = next;
+    }
+    out
+}
+
+/// Cartesian product of per-part variants, concatenated
+fn concatenations(per_part: &[Vec<Vec<u8>>]) -> Vec<Vec<u8>> {
+    per_part.iter().fold(vec![Vec::new()], |acc, variants| {
+        acc.iter()
+            .flat_map(|prefix| {
+                variants.iter().map(move |v| {
+                    let mut key = prefix.clone();
+                    key.extend_from_slice(v);
+                    key
+                })
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn barcodes(samples: &[&[&str]]) -> Vec<Vec<Vec<u8>>> {
+        samples
+            .iter()
+            .map(|parts| parts.iter().map(|p| p.as_bytes().to_vec()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn variants_cover_every_substitution_once() {
+        // 4 substitutions per position, N included, and C(4, 2) pairs of positions
+        assert_eq!(variants(b"ACGT", 0).len(), 1);
+        assert_eq!(variants(b"ACGT", 1).len(), 1 + 4 * 4);
+        assert_eq!(variants(b"ACGT", 2).len(), 1 + 4 * 4 + 6 * 16);
+
+        let mut unique = variants(b"ACGT", 2);
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), 113);
+    }
+
+    #[test]
+    fn shared_variants_are_ambiguous() {
+        // two apart, so ACCT is one mismatch from both
+        let samples = barcodes(&[&["ACGT"], &["ACCA"]]);
+        let (index, collisions) = BarcodeIndex::build(&samples, &[1]).unwrap();
+
+        assert_eq!(collisions, vec![(0, 1)]);
+        assert_eq!(index.lookup(b"ACGT"), Some(BarcodeMatch::Sample(0)));
+        assert_eq!(index.lookup(b"ACCA"), Some(BarcodeMatch::Sample(1)));
+        assert_eq!(index.lookup(b"ACCT"), Some(BarcodeMatch::Ambiguous));
+        assert_eq!(index.lookup(b"TTTT"), None);
+    }
+
+    #[test]
+    fn collisions_refuse_the_index() {
+        let samples = barcodes(&[&["ACGT"], &["ACCA"], &["TTTT"]]);
+        assert_eq!(
+            BarcodeIndex::new(&samples, &[1]).unwrap_err(),
+            BarcodeError::UnsafeMismatches(vec![(0, 1)])
+        );
+        assert!(BarcodeIndex::new(&samples, &[0]).is_ok());
+    }
+
+    #[test]
+    fn dual_indexes_are_looked_up_concatenated() {
+        let samples = barcodes(&[&["AC", "GT"], &["TT", "GG"]]);
+        // a mismatch is only allowed in i7
+        let index = BarcodeIndex::new(&samples, &[1, 0]).unwrap();
+
+        assert_eq!(index.index_len(), 4);
+        assert_eq!(index.lookup(b"ACGT"), Some(BarcodeMatch::Sample(0)));
+        assert_eq!(index.lookup(b"NCGT"), Some(BarcodeMatch::Sample(0)));
+        assert_eq!(index.lookup(b"TTGG"), Some(BarcodeMatch::Sample(1)));
+        assert_eq!(index.lookup(b"ACGA"), None);
+        assert_eq!(index.lookup(b"GTAC"), None);
+        // 1 + 4 * 2 i7 variants per sample, one i5 each
+        assert_eq!(index.len(), 2 * 9);
+    }
+
+    #[test]
+    fn barcodes_must_share_a_shape() {
+        assert_eq!(
+            BarcodeIndex::new(&barcodes(&[&["AC", "GT"], &["TT"]]), &[0, 0]).unwrap_err(),
+            BarcodeError::PartCountMismatch {
+                sample: 1,
+                expected: 2,
+                got: 1,
+            }
+        );
+        assert_eq!(
+            BarcodeIndex::new(&barcodes(&[&["ACGT"], &["ACG"]]), &[0]).unwrap_err(),
+            BarcodeError::LengthMismatch {
+                sample: 1,
+                part: 0,
+                expected: 4,
+                got: 3,
+            }
+        );
+    }
+}
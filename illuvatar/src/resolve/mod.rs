@@ -1,3 +1,734 @@
-use triple_accel::{hamming, hamming_search};
+// Resolving turns a DemuxUnit into FASTQ records: each cluster's index is
+// matched against the samples on its lane, then every output read of the
+// cluster is assembled and sent on to the matched sample's writers.
 
-pub fn resolve_tile() {}
+pub mod barcode;
+
+use std::ops::Range;
+
+use crossbeam::channel::{SendError, Sender};
+use fxhash::FxHashMap;
+use log::warn;
+use rayon::prelude::*;
+use samplesheet::SampleSheetData;
+use thiserror::Error;
+
+use crate::{
+    accumulator::{DarkCycleTrims, DemuxStats, DroppedReads, UNDETERMINED_PREFIX},
+    assemble::{
+        assemble_read, extract_index,
+        layout::{ReadLayout, SegmentKind},
+        reverse_complement, trailing_dark_cycles, AssembleError, ReadFilter,
+    },
+    bcl::{phred_to_ascii, BclTile, DemuxUnit},
+    manager::{
+        readname::{ReadNameContext, ReadNameFormatter},
+        writer::WriteRecord,
+    },
+    runinfo::RunInfo,
+};
+use barcode::{BarcodeError, BarcodeIndex, BarcodeMatch};
+
+/// Clusters each demux worker resolves at a time
+pub const CLUSTER_CHUNK: usize = 16_384;
+
+/// Mismatches allowed in each index, unless that makes two samples ambiguous
+pub const DEFAULT_BARCODE_MISMATCHES: u8 = 1;
+
+#[derive(Error, Debug)]
+pub enum ResolveError {
+    #[error(transparent)]
+    BarcodeError(#[from] BarcodeError),
+    #[error(transparent)]
+    AssembleError(#[from] AssembleError),
+    #[error(transparent)]
+    SendError(#[from] SendError<WriteRecord>),
+    #[error("Sample {sample} has {got} indexes, but the run has {expected} index reads")]
+    TooManyIndexes {
+        sample: String,
+        expected: usize,
+        got: usize,
+    },
+    #[error(
+        "Index {index} of sample {sample} is {got} bases, but its index read has {cycles} cycles"
+    )]
+    IndexTooLong {
+        sample: String,
+        index: usize,
+        cycles: usize,
+        got: usize,
+    },
+    #[error("Tile {tile_num} of lane {lane} has {got} cycles, but the run has {expected}")]
+    MissingCycles {
+        lane: u8,
+        tile_num: u32,
+        expected: usize,
+        got: usize,
+    },
+    #[error("Cycle {cycle} of tile {tile_num} has {got} clusters, but cycle 1 has {expected}")]
+    ClusterCountMismatch {
+        tile_num: u32,
+        cycle: usize,
+        expected: usize,
+        got: usize,
+    },
+}
+
+/// A sample sheet row, as far as resolving is concerned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub sample_id: String,
+    /// `None` for samples that are on every lane
+    pub lane: Option<u8>,
+    /// i7, then i5 if the sample has one
+    pub indexes: Vec<Vec<u8>>,
+}
+
+impl From<&SampleSheetData> for Sample {
+    fn from(data: &SampleSheetData) -> Self {
+        Sample {
+            sample_id: data.sample_id.clone(),
+            lane: data.lane,
+            indexes: [data.index.as_deref(), data.index2.as_deref()]
+                .into_iter()
+                .flatten()
+                .filter(|index| !index.is_empty())
+                .map(|index| index.trim().to_ascii_uppercase().into_bytes())
+                .collect(),
+        }
+    }
+}
+
+/// A read written to its own FASTQ, e.g. R1
+#[derive(Debug, Clone)]
+struct OutputRead {
+    /// `_R1` for R1
+    suffix: String,
+    /// One-based output read number, 1 for R1
+    number: u8,
+    /// Run cycles of the whole read, N cycles included
+    cycles: Range<usize>,
+    /// Whether each cycle of `cycles` is emitted
+    mask: Vec<bool>,
+    reverse_complement: bool,
+}
+
+/// Tallies of what resolving did to clusters besides assigning them, per tile
+///
+/// Each chunk of clusters keeps its own, merged once the tile is resolved.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ResolveStats {
+    /// Clusters dropped by the [ReadFilter], by the first read that failed it
+    pub dropped: DroppedReads,
+    /// Reads shortened because their last cycles were dark
+    pub dark_cycles: DarkCycleTrims,
+}
+
+impl ResolveStats {
+    pub fn merge(&mut self, other: ResolveStats) {
+        self.dropped.merge(other.dropped);
+        self.dark_cycles.merge(other.dark_cycles);
+    }
+}
+
+/// Assigns the clusters of one lane to its samples
+#[derive(Debug, Clone)]
+enum LaneSamples {
+    /// No sample is on the lane, so every cluster is undetermined
+    Empty,
+    /// A single sample without indexes takes every cluster
+    Single(String),
+    Indexed {
+        sample_ids: Vec<String>,
+        index: BarcodeIndex,
+        /// Length of each sample index, observed indexes are cut to this
+        part_lengths: Vec<usize>,
+    },
+}
+
+impl LaneSamples {
+    fn new(
+        lane: u8,
+        samples: &[Sample],
+        index_reads: &[Vec<usize>],
+    ) -> Result<LaneSamples, ResolveError> {
+        let on_lane = samples
+            .iter()
+            .filter(|s| s.lane.map_or(true, |l| l == lane))
+            .collect::<Vec<&Sample>>();
+        match on_lane.as_slice() {
+            [] => {
+                warn!("No samples are on lane {lane}, every read will be undetermined");
+                return Ok(LaneSamples::Empty);
+            }
+            [sample] if sample.indexes.is_empty() => {
+                return Ok(LaneSamples::Single(sample.sample_id.clone()))
+            }
+            _ => {}
+        }
+        for sample in on_lane.iter() {
+            if sample.indexes.len() > index_reads.len() {
+                return Err(ResolveError::TooManyIndexes {
+                    sample: sample.sample_id.clone(),
+                    expected: index_reads.len(),
+                    got: sample.indexes.len(),
+                });
+            }
+            for (i, (index, cycles)) in sample.indexes.iter().zip(index_reads).enumerate() {
+                if index.len() > cycles.len() {
+                    return Err(ResolveError::IndexTooLong {
+                        sample: sample.sample_id.clone(),
+                        index: i + 1,
+                        cycles: cycles.len(),
+                        got: index.len(),
+                    });
+                }
+            }
+        }
+
+        let barcodes = on_lane
+            .iter()
+            .map(|s| s.indexes.clone())
+            .collect::<Vec<Vec<Vec<u8>>>>();
+        let part_lengths = barcodes[0].iter().map(|p| p.len()).collect::<Vec<usize>>();
+        let mismatches = vec![DEFAULT_BARCODE_MISMATCHES; part_lengths.len()];
+        let index = match BarcodeIndex::new(&barcodes, &mismatches) {
+            Ok(index) => index,
+            Err(BarcodeError::UnsafeMismatches(collisions)) => {
+                warn!(
+                    "Lane {lane} has {} pairs of samples too similar for {} mismatches, e.g. {} and {}, matching indexes exactly",
+                    collisions.len(),
+                    DEFAULT_BARCODE_MISMATCHES,
+                    on_lane[collisions[0].0].sample_id,
+                    on_lane[collisions[0].1].sample_id,
+                );
+                BarcodeIndex::new(&barcodes, &vec![0; part_lengths.len()])?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(LaneSamples::Indexed {
+            sample_ids: on_lane.iter().map(|s| s.sample_id.clone()).collect(),
+            index,
+            part_lengths,
+        })
+    }
+
+    /// The sample an observed index belongs to, parts given separately
+    fn assign<'a>(&'a self, observed: &[Vec<u8>], key: &mut Vec<u8>) -> &'a str {
+        match self {
+            LaneSamples::Empty => UNDETERMINED_PREFIX,
+            LaneSamples::Single(sample_id) => sample_id,
+            LaneSamples::Indexed {
+                sample_ids,
+                index,
+                part_lengths,
+            } => {
+                key.clear();
+                for (part, len) in observed.iter().zip(part_lengths) {
+                    key.extend_from_slice(&part[..part.len().min(*len)]);
+                }
+                match index.lookup(key) {
+                    Some(BarcodeMatch::Sample(i)) => &sample_ids[i],
+                    Some(BarcodeMatch::Ambiguous) | None => UNDETERMINED_PREFIX,
+                }
+            }
+        }
+    }
+}
+
+/// Everything needed to turn [DemuxUnit]s into [WriteRecord]s, shared by every demux worker
+///
+/// Records are sent to `<Sample_ID>_R<n>`, or `Undetermined_R<n>` for clusters
+/// matching no sample, plus `<Sample_ID>_index` with every index base of the
+/// cluster when index FASTQs are requested. Cluster positions are not read from
+/// the run's `.locs` files, so read names carry the cluster's index within its
+/// tile as x, keeping them unique, and a y of 0.
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    instrument: String,
+    run_number: u32,
+    flowcell: String,
+    total_cycles: usize,
+    reads: Vec<OutputRead>,
+    /// Run cycles of each index read, i.e. i7 then i5
+    index_reads: Vec<Vec<usize>>,
+    umi_cycles: Vec<usize>,
+    index_fastq: bool,
+    lanes: FxHashMap<u8, LaneSamples>,
+    read_filter: ReadFilter,
+    /// Trim trailing cycles with more than this fraction of N calls, `None` to keep them
+    dark_cycle_threshold: Option<f64>,
+}
+
+impl Resolver {
+    /// Prepare to resolve `lanes` of a run laid out as `layout`
+    ///
+    /// Read IDs name `instrument`, see [resolve_instrument](crate::runinfo::resolve_instrument).
+    pub fn new(
+        run_info: &RunInfo,
+        instrument: &str,
+        layout: &ReadLayout,
+        samples: &[Sample],
+        lanes: &[u8],
+        index_fastq: bool,
+    ) -> Result<Resolver, ResolveError> {
+        let reads = layout
+            .template_reads()
+            .enumerate()
+            .map(|(i, read)| OutputRead {
+                suffix: format!("_R{}", i + 1),
+                number: i as u8 + 1,
+                cycles: usize::from(read.cycles.start)..usize::from(read.cycles.end),
+                mask: read.template_mask(),
+                reverse_complement: read.reverse_complement,
+            })
+            .collect();
+        let index_reads = layout
+            .reads()
+            .iter()
+            .map(|r| {
+                r.cycles_of(SegmentKind::Index)
+                    .map(usize::from)
+                    .collect::<Vec<usize>>()
+            })
+            .filter(|cycles| !cycles.is_empty())
+            .collect::<Vec<Vec<usize>>>();
+
+        let mut lane_samples = FxHashMap::default();
+        for lane in lanes {
+            lane_samples.insert(*lane, LaneSamples::new(*lane, samples, &index_reads)?);
+        }
+
+        Ok(Resolver {
+            instrument: instrument.to_string(),
+            run_number: run_info.run_number(),
+            flowcell: run_info.flowcell().to_string(),
+            total_cycles: usize::from(layout.total_cycles()),
+            reads,
+            index_reads,
+            umi_cycles: layout.umi_cycles().into_iter().map(usize::from).collect(),
+            index_fastq,
+            lanes: lane_samples,
+            read_filter: ReadFilter::default(),
+            dark_cycle_threshold: None,
+        })
+    }
+
+    /// Drop clusters with a read failing `read_filter`
+    ///
+    /// Every read of a dropped cluster is dropped, so paired outputs stay in step.
+    pub fn set_read_filter(&mut self, read_filter: ReadFilter) {
+        self.read_filter = read_filter;
+    }
+
+    /// Trim each tile's trailing dark cycles, see [trailing_dark_cycles], from every read
+    ///
+    /// Reads are trimmed before the [ReadFilter] checks them.
+    pub fn set_dark_cycle_threshold(&mut self, threshold: f64) {
+        self.dark_cycle_threshold = Some(threshold);
+    }
+
+    /// Run cycles of every index read, i7 then i5
+    ///
+    /// These are the cycles, in order, that [count_tile](Resolver::count_tile) expects.
+    pub fn index_cycles(&self) -> Vec<usize> {
+        self.index_reads.iter().flatten().copied().collect()
+    }
+
+    /// Check `unit` has at least `cycles` cycles, each with as many clusters as the first
+    fn check_unit(&self, unit: &DemuxUnit, cycles: usize) -> Result<(), ResolveError> {
+        let tiles = unit.tiles.as_slice();
+        let tile_num = unit.tile_data.tile_num();
+        if tiles.len() < cycles {
+            return Err(ResolveError::MissingCycles {
+                lane: unit.lane,
+                tile_num,
+                expected: cycles,
+                got: tiles.len(),
+            });
+        }
+        let n_clusters = unit.n_clusters();
+        if let Some((cycle, tile)) = tiles
+            .iter()
+            .enumerate()
+            .find(|(_, t)| t.get_bases().len() != n_clusters)
+        {
+            return Err(ResolveError::ClusterCountMismatch {
+                tile_num,
+                cycle: cycle + 1,
+                expected: n_clusters,
+                got: tile.get_bases().len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Assign every cluster of `unit` to a sample, counting clusters per Sample_ID
+    ///
+    /// `unit` holds only the tiles of [index_cycles](Resolver::index_cycles), in
+    /// that order. Nothing is assembled or sent, and the read filter and dark
+    /// cycle trimming don't apply since template cycles aren't decoded.
+    pub fn count_tile(&self, unit: &DemuxUnit) -> Result<DemuxStats, ResolveError> {
+        let index_cycles = self.index_reads.iter().map(Vec::len).sum();
+        self.check_unit(unit, index_cycles)?;
+        let n_clusters = unit.n_clusters();
+        (0..n_clusters.div_ceil(CLUSTER_CHUNK))
+            .into_par_iter()
+            .map(|chunk| {
+                let start = chunk * CLUSTER_CHUNK;
+                self.count_clusters(unit, start..n_clusters.min(start + CLUSTER_CHUNK))
+            })
+            .try_reduce(DemuxStats::default, |mut a, b| {
+                a.merge(b);
+                Ok(a)
+            })
+    }
+
+    fn count_clusters(
+        &self,
+        unit: &DemuxUnit,
+        clusters: Range<usize>,
+    ) -> Result<DemuxStats, ResolveError> {
+        let lane_samples = self.lanes.get(&unit.lane).unwrap_or(&LaneSamples::Empty);
+        let mut tiles = unit.tiles.iter();
+        let index_tiles = self
+            .index_reads
+            .iter()
+            .map(|cycles| tiles.by_ref().take(cycles.len()).collect())
+            .collect::<Vec<Vec<&BclTile>>>();
+
+        let mut observed = vec![Vec::new(); index_tiles.len()];
+        let mut key = Vec::new();
+        let mut stats = DemuxStats::default();
+        for cluster in clusters {
+            for (tiles, bases) in index_tiles.iter().zip(observed.iter_mut()) {
+                extract_index(tiles, cluster, bases)?;
+            }
+            stats.record(unit.lane, lane_samples.assign(&observed, &mut key));
+        }
+        Ok(stats)
+    }
+
+    /// Assemble every cluster of `unit` and send its records to `destination`
+    ///
+    /// Clusters are resolved in chunks on the current rayon pool, so records
+    /// of a tile reach the writers in no particular order.
+    pub fn resolve_tile(
+        &self,
+        unit: &DemuxUnit,
+        read_names: &dyn ReadNameFormatter,
+        destination: &Sender<WriteRecord>,
+    ) -> Result<ResolveStats, ResolveError> {
+        self.check_unit(unit, self.total_cycles)?;
+        let tiles = unit.tiles.as_slice();
+        let n_clusters = unit.n_clusters();
+        // a cycle is dark across the whole tile, so this is worked out once per tile
+        let dark_cycles = self
+            .reads
+            .iter()
+            .map(|read| match self.dark_cycle_threshold {
+                Some(threshold) => {
+                    trailing_dark_cycles(&tiles[read.cycles.clone()], &read.mask, threshold)
+                }
+                None => 0,
+            })
+            .collect::<Vec<usize>>();
+        (0..n_clusters.div_ceil(CLUSTER_CHUNK))
+            .into_par_iter()
+            .map(|chunk| {
+                let start = chunk * CLUSTER_CHUNK;
+                let clusters = start..n_clusters.min(start + CLUSTER_CHUNK);
+                self.resolve_clusters(unit, clusters, &dark_cycles, read_names, destination)
+            })
+            .try_reduce(ResolveStats::default, |mut a, b| {
+                a.merge(b);
+                Ok(a)
+            })
+    }
+
+    fn resolve_clusters(
+        &self,
+        unit: &DemuxUnit,
+        clusters: Range<usize>,
+        dark_cycles: &[usize],
+        read_names: &dyn ReadNameFormatter,
+        destination: &Sender<WriteRecord>,
+    ) -> Result<ResolveStats, ResolveError> {
+        let tiles = unit.tiles.as_slice();
+        let tile_num = unit.tile_data.tile_num();
+        let lane_samples = self.lanes.get(&unit.lane).unwrap_or(&LaneSamples::Empty);
+        let index_tiles = self
+            .index_reads
+            .iter()
+            .map(|cycles| cycles.iter().map(|c| &tiles[*c]).collect())
+            .collect::<Vec<Vec<&BclTile>>>();
+        let umi_tiles = self
+            .umi_cycles
+            .iter()
+            .map(|c| &tiles[*c])
+            .collect::<Vec<&BclTile>>();
+
+        let mut observed = vec![Vec::new(); index_tiles.len()];
+        let mut key = Vec::new();
+        let mut index_name = String::new();
+        let mut umi = Vec::new();
+        let mut seq = Vec::new();
+        let mut qual = Vec::new();
+        // every read of a cluster is assembled before any is sent, in case one is filtered
+        let mut assembled = vec![(Vec::new(), Vec::new()); self.reads.len()];
+        let mut id = String::new();
+        let mut stats = ResolveStats::default();
+        'clusters: for cluster in clusters {
+            for ((read, (seq, qual)), dark) in
+                self.reads.iter().zip(assembled.iter_mut()).zip(dark_cycles)
+            {
+                assemble_read(&tiles[read.cycles.clone()], &read.mask, cluster, seq, qual);
+                let len = seq.len().saturating_sub(*dark);
+                stats.dark_cycles.record(seq.len() - len);
+                seq.truncate(len);
+                qual.truncate(len);
+                if let Err(reason) = self.read_filter.check(seq, qual) {
+                    stats.dropped.record(reason);
+                    continue 'clusters;
+                }
+            }
+
+            index_name.clear();
+            for (part, (tiles, bases)) in index_tiles.iter().zip(observed.iter_mut()).enumerate() {
+                extract_index(tiles, cluster, bases)?;
+                if part > 0 {
+                    index_name.push('+');
+                }
+                index_name.extend(bases.iter().map(|b| char::from(*b)));
+            }
+            extract_index(&umi_tiles, cluster, &mut umi)?;
+            let umi_name = (!umi.is_empty()).then(|| String::from_utf8_lossy(&umi).into_owned());
+            let sample = lane_samples.assign(&observed, &mut key);
+            let ctx = ReadNameContext {
+                instrument: &self.instrument,
+                run_number: self.run_number,
+                flowcell: &self.flowcell,
+                lane: unit.lane,
+                tile: tile_num,
+                x: cluster as u32,
+                y: 0,
+                read: 1,
+                // non-PF clusters were removed by the reader
+                filtered: false,
+                control: 0,
+                index: &index_name,
+                umi: umi_name.as_deref(),
+            };
+
+            for (read, (seq, qual)) in self.reads.iter().zip(assembled.iter_mut()) {
+                if read.reverse_complement {
+                    reverse_complement(seq, qual);
+                }
+                read_names.format(
+                    &ReadNameContext {
+                        read: read.number,
+                        ..ctx
+                    },
+                    &mut id,
+                );
+                destination.send(record(
+                    unit.lane,
+                    &id,
+                    seq,
+                    qual,
+                    format!("{sample}{}", read.suffix),
+                    &index_name,
+                    &umi_name,
+                ))?;
+            }
+            if self.index_fastq && !index_tiles.is_empty() {
+                seq.clear();
+                qual.clear();
+                for tile in index_tiles.iter().flatten() {
+                    seq.push(tile.get_bases()[cluster]);
+                    qual.push(tile.get_quals()[cluster]);
+                }
+                read_names.format(&ctx, &mut id);
+                destination.send(record(
+                    unit.lane,
+                    &id,
+                    &seq,
+                    &qual,
+                    format!("{sample}_index"),
+                    &index_name,
+                    &umi_name,
+                ))?;
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// A FASTQ record from an assembled read, `qual` holding numeric scores
+fn record(
+    lane: u8,
+    id: &str,
+    seq: &[u8],
+    qual: &[u8],
+    destination: String,
+    index_name: &str,
+    umi: &Option<String>,
+) -> WriteRecord {
+    WriteRecord {
+        lane,
+        id: id.to_string(),
+        reads: String::from_utf8_lossy(seq).into_owned(),
+        qual: qual
+            .iter()
+            .map(|q| char::from(phred_to_ascii(*q)))
+            .collect(),
+        destination,
+        barcode: (!index_name.is_empty()).then(|| index_name.to_string()),
+        umi: umi.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assemble::layout::RunRead,
+        bcl::{testutil::bcl_tile, TileData},
+        manager::readname::Casava18ReadName,
+    };
+    use crossbeam::channel::unbounded;
+    use samplesheet::OverrideCycle;
+
+    /// A 5-cycle R1 then a 2-cycle i7
+    const RUN: [RunRead; 2] = [
+        RunRead {
+            cycles: 5,
+            is_index: false,
+        },
+        RunRead {
+            cycles: 2,
+            is_index: true,
+        },
+    ];
+
+    /// One tile of `lane`, a string of calls per cycle with one base per cluster
+    fn unit(lane: u8, cycles: &[&[u8]]) -> DemuxUnit {
+        let tiles = cycles
+            .iter()
+            .map(|bases| bcl_tile(bases, &vec![30; bases.len()]))
+            .collect::<Vec<BclTile>>();
+        DemuxUnit::new(
+            lane,
+            TileData::new(1101, cycles[0].len() as u32, true),
+            tiles,
+        )
+    }
+
+    fn sample(sample_id: &str, index: &str) -> Sample {
+        Sample {
+            sample_id: sample_id.to_string(),
+            lane: None,
+            indexes: vec![index.as_bytes().to_vec()],
+        }
+    }
+
+    fn resolve(resolver: &Resolver, unit: &DemuxUnit) -> Vec<WriteRecord> {
+        let (send, recv) = unbounded();
+        resolver
+            .resolve_tile(unit, &Casava18ReadName, &send)
+            .unwrap();
+        drop(send);
+        recv.iter().collect()
+    }
+
+    #[test]
+    fn umi_cycles_are_cut_from_reads_into_names() {
+        let overrides = vec![
+            vec![OverrideCycle::U(2), OverrideCycle::Y(3)],
+            vec![OverrideCycle::I(2)],
+        ];
+        let layout = ReadLayout::new(&RUN, None, Some(overrides.as_slice())).unwrap();
+        let resolver = Resolver::new(
+            &RunInfo::default(),
+            "A01234",
+            &layout,
+            &[sample("S1", "AC")],
+            &[1],
+            false,
+        )
+        .unwrap();
+        let records = resolve(
+            &resolver,
+            &unit(1, &[b"G", b"T", b"A", b"C", b"G", b"A", b"C"]),
+        );
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].destination, "S1_R1");
+        assert_eq!(records[0].reads, "ACG");
+        assert_eq!(records[0].umi.as_deref(), Some("GT"));
+        assert!(records[0].id.contains(":GT "));
+    }
+
+    #[test]
+    fn clusters_of_a_tile_get_their_own_names() {
+        let layout = ReadLayout::new(&RUN, None, None).unwrap();
+        let resolver = Resolver::new(
+            &RunInfo::default(),
+            "A01234",
+            &layout,
+            &[sample("S1", "AC")],
+            &[1],
+            false,
+        )
+        .unwrap();
+        let records = resolve(
+            &resolver,
+            &unit(1, &[b"AA", b"CC", b"GG", b"TT", b"AA", b"AA", b"CC"]),
+        );
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].reads, records[1].reads);
+        assert_ne!(records[0].id, records[1].id);
+    }
+
+    #[test]
+    fn clusters_are_assigned_by_their_index() {
+        let layout = ReadLayout::new(&RUN, None, None).unwrap();
+        let resolver = Resolver::new(
+            &RunInfo::default(),
+            "A01234",
+            &layout,
+            &[sample("S1", "AC"), sample("S2", "GT")],
+            &[1],
+            false,
+        )
+        .unwrap();
+        // S1, S2, and an index matching neither
+        let records = resolve(
+            &resolver,
+            &unit(1, &[b"ACG", b"ACG", b"ACG", b"ACG", b"ACG", b"AGC", b"CTC"]),
+        );
+
+        let mut destinations = records
+            .iter()
+            .map(|r| (r.destination.as_str(), r.barcode.as_deref()))
+            .collect::<Vec<_>>();
+        destinations.sort_unstable();
+        assert_eq!(
+            destinations,
+            vec![
+                ("S1_R1", Some("AC")),
+                ("S2_R1", Some("GT")),
+                ("Undetermined_R1", Some("CC")),
+            ]
+        );
+        assert!(matches!(
+            resolver.resolve_tile(&unit(1, &[b"A", b"A"]), &Casava18ReadName, &unbounded().0),
+            Err(ResolveError::MissingCycles {
+                expected: 7,
+                got: 2,
+                ..
+            })
+        ));
+    }
+}
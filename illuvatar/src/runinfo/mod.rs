@@ -0,0 +1,309 @@
+// RunInfo.xml describes what the instrument was set up to sequence: the reads
+// and their cycles, the flowcell, and on newer instruments every tile.
+// Only the handful of elements demux needs are parsed.
+
+pub(crate) mod parameters;
+pub(crate) mod parser;
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use samplesheet::SampleSheetHeader;
+use thiserror::Error;
+
+use crate::assemble::layout::RunRead;
+
+pub const RUN_INFO: &str = "RunInfo.xml";
+
+#[derive(Error, Debug)]
+pub enum RunInfoError {
+    #[error("Unable to read RunInfo.xml")]
+    IoError(#[from] std::io::Error),
+    #[error("RunInfo.xml has no <{0}> element")]
+    MissingElement(&'static str),
+    #[error("RunInfo.xml <{element}> has an invalid {attribute}: {value:?}")]
+    InvalidAttribute {
+        element: &'static str,
+        attribute: &'static str,
+        value: String,
+    },
+    #[error("RunInfo.xml lists tile {0:?}, expected `<lane>_<tile>`")]
+    InvalidTile(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunInfo {
+    run_id: String,
+    run_number: u32,
+    flowcell: String,
+    instrument: String,
+    reads: Vec<RunRead>,
+    lane_count: u8,
+    /// Declared tiles per lane, in document order. Empty for instruments that
+    /// don't list them, e.g. MiSeq.
+    tiles: BTreeMap<u8, Vec<u32>>,
+}
+
+impl RunInfo {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, RunInfoError> {
+        RunInfo::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(input: &str) -> Result<Self, RunInfoError> {
+        let run = parser::elements(input, "Run")
+            .into_iter()
+            .next()
+            .ok_or(RunInfoError::MissingElement("Run"))?;
+        let run_number = parse_attr(&run, "Run", "Number")?;
+
+        let mut reads = parser::elements(input, "Read")
+            .iter()
+            .map(|read| {
+                let number: usize = parse_attr(read, "Read", "Number")?;
+                let cycles = parse_attr(read, "Read", "NumCycles")?;
+                let is_index = read.attr("IsIndexedRead") == Some("Y");
+                Ok((number, RunRead { cycles, is_index }))
+            })
+            .collect::<Result<Vec<(usize, RunRead)>, RunInfoError>>()?;
+        reads.sort_by_key(|(number, _)| *number);
+
+        let layout = parser::elements(input, "FlowcellLayout")
+            .into_iter()
+            .next()
+            .ok_or(RunInfoError::MissingElement("FlowcellLayout"))?;
+        let lane_count = parse_attr(&layout, "FlowcellLayout", "LaneCount")?;
+
+        let mut tiles: BTreeMap<u8, Vec<u32>> = BTreeMap::new();
+        for tile in parser::elements(input, "Tile") {
+            let text = tile.text.unwrap_or_default();
+            let (lane, tile_num) =
+                parser::lane_tile(text).ok_or_else(|| RunInfoError::InvalidTile(text.into()))?;
+            tiles.entry(lane).or_default().push(tile_num);
+        }
+
+        Ok(RunInfo {
+            run_id: run.attr("Id").unwrap_or_default().to_string(),
+            run_number,
+            flowcell: element_text(input, "Flowcell"),
+            instrument: element_text(input, "Instrument"),
+            reads: reads.into_iter().map(|(_, read)| read).collect(),
+            lane_count,
+            tiles,
+        })
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    pub fn run_number(&self) -> u32 {
+        self.run_number
+    }
+
+    pub fn flowcell(&self) -> &str {
+        &self.flowcell
+    }
+
+    pub fn instrument(&self) -> &str {
+        &self.instrument
+    }
+
+    /// Reads in run order, ready for [ReadLayout::new](crate::assemble::layout::ReadLayout::new)
+    pub fn reads(&self) -> &[RunRead] {
+        &self.reads
+    }
+
+    pub fn lane_count(&self) -> u8 {
+        self.lane_count
+    }
+
+    /// Bare tile numbers declared for each lane, empty if RunInfo lists none
+    pub fn tiles(&self) -> &BTreeMap<u8, Vec<u32>> {
+        &self.tiles
+    }
+}
+
+/// Where the instrument named in read IDs came from, see [resolve_instrument]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstrumentSource {
+    RunInfo,
+    /// The sample sheet [Header]'s InstrumentType or InstrumentPlatform
+    SampleSheet,
+    /// Neither names one, read IDs have an empty instrument
+    Unknown,
+}
+
+/// The instrument read IDs are named after
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instrument {
+    pub name: String,
+    pub source: InstrumentSource,
+}
+
+/// Pick the instrument for read IDs, preferring RunInfo.xml's `<Instrument>`
+///
+/// RunInfo names the instrument itself, e.g. `A01234`, as read IDs from other
+/// tools do. Only if it is unavailable or empty does this fall back to the
+/// sample sheet's InstrumentType, then InstrumentPlatform.
+pub fn resolve_instrument(run_info: Option<&RunInfo>, header: &SampleSheetHeader) -> Instrument {
+    if let Some(name) = run_info.map(RunInfo::instrument).filter(|i| !i.is_empty()) {
+        return Instrument {
+            name: name.to_string(),
+            source: InstrumentSource::RunInfo,
+        };
+    }
+    match [&header.instrument_type, &header.instrument_platform]
+        .into_iter()
+        .flatten()
+        .map(|name| name.trim())
+        .find(|name| !name.is_empty())
+    {
+        Some(name) => Instrument {
+            name: name.to_string(),
+            source: InstrumentSource::SampleSheet,
+        },
+        None => Instrument {
+            name: String::new(),
+            source: InstrumentSource::Unknown,
+        },
+    }
+}
+
+fn parse_attr<T: std::str::FromStr>(
+    element: &parser::Element,
+    element_name: &'static str,
+    attribute: &'static str,
+) -> Result<T, RunInfoError> {
+    let value = element.attr(attribute).unwrap_or_default();
+    value.parse().map_err(|_| RunInfoError::InvalidAttribute {
+        element: element_name,
+        attribute,
+        value: value.to_string(),
+    })
+}
+
+fn element_text(input: &str, element_name: &str) -> String {
+    parser::elements(input, element_name)
+        .into_iter()
+        .find_map(|e| e.text)
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOVASEQ: &str = r#"<?xml version="1.0"?>
+<RunInfo Version="5">
+  <Run Id="231116_A01234_0123_AHXXXXXDSX" Number="123">
+    <Flowcell>HXXXXXDSX</Flowcell>
+    <Instrument>A01234</Instrument>
+    <Date>11/16/2023 9:00:00 AM</Date>
+    <Reads>
+      <Read Number="1" NumCycles="151" IsIndexedRead="N" IsReverseComplement="N"/>
+      <Read Number="2" NumCycles="10" IsIndexedRead="Y" IsReverseComplement="N"/>
+      <Read Number="3" NumCycles="10" IsIndexedRead="Y" IsReverseComplement="Y"/>
+      <Read Number="4" NumCycles="151" IsIndexedRead="N" IsReverseComplement="N"/>
+    </Reads>
+    <FlowcellLayout LaneCount="2" SurfaceCount="2" SwathCount="6" TileCount="78">
+      <TileSet TileNamingConvention="FourDigit">
+        <Tiles>
+          <Tile>1_1101</Tile>
+          <Tile>1_2101</Tile>
+          <Tile>2_1101</Tile>
+        </Tiles>
+      </TileSet>
+    </FlowcellLayout>
+  </Run>
+</RunInfo>"#;
+
+    #[test]
+    fn parses_novaseq_run_info() {
+        let run_info = RunInfo::parse(NOVASEQ).unwrap();
+        assert_eq!(run_info.run_id(), "231116_A01234_0123_AHXXXXXDSX");
+        assert_eq!(run_info.run_number(), 123);
+        assert_eq!(run_info.flowcell(), "HXXXXXDSX");
+        assert_eq!(run_info.instrument(), "A01234");
+        assert_eq!(run_info.lane_count(), 2);
+        assert_eq!(
+            run_info.reads(),
+            &[
+                RunRead {
+                    cycles: 151,
+                    is_index: false
+                },
+                RunRead {
+                    cycles: 10,
+                    is_index: true
+                },
+                RunRead {
+                    cycles: 10,
+                    is_index: true
+                },
+                RunRead {
+                    cycles: 151,
+                    is_index: false
+                },
+            ]
+        );
+        assert_eq!(run_info.tiles()[&1], vec![1101, 2101]);
+        assert_eq!(run_info.tiles()[&2], vec![1101]);
+    }
+
+    #[test]
+    fn missing_tiles_are_empty() {
+        let miseq = NOVASEQ.replace(
+            &NOVASEQ[NOVASEQ.find("<TileSet").unwrap()..NOVASEQ.find("</TileSet>").unwrap() + 10],
+            "",
+        );
+        let run_info = RunInfo::parse(&miseq).unwrap();
+        assert!(run_info.tiles().is_empty());
+        assert_eq!(run_info.reads().len(), 4);
+    }
+
+    #[test]
+    fn bad_cycle_count_is_an_error() {
+        let bad = NOVASEQ.replace("NumCycles=\"151\"", "NumCycles=\"many\"");
+        assert!(matches!(
+            RunInfo::parse(&bad),
+            Err(RunInfoError::InvalidAttribute {
+                attribute: "NumCycles",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn instrument_prefers_run_info() {
+        let run_info = RunInfo::parse(NOVASEQ).unwrap();
+        let header = SampleSheetHeader {
+            instrument_type: Some("NovaSeq 6000".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_instrument(Some(&run_info), &header),
+            Instrument {
+                name: "A01234".to_string(),
+                source: InstrumentSource::RunInfo,
+            }
+        );
+        assert_eq!(
+            resolve_instrument(None, &header).source,
+            InstrumentSource::SampleSheet
+        );
+    }
+
+    #[test]
+    fn instrument_falls_back_to_the_sample_sheet() {
+        let run_info = RunInfo::parse(&NOVASEQ.replace("A01234", "")).unwrap();
+        let header = SampleSheetHeader {
+            instrument_platform: Some("NovaSeq".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(resolve_instrument(Some(&run_info), &header).name, "NovaSeq");
+        assert_eq!(
+            resolve_instrument(None, &SampleSheetHeader::default()).source,
+            InstrumentSource::Unknown
+        );
+    }
+}
@@ -0,0 +1,168 @@
+// RunParameters.xml records the run as it was planned on the instrument. Only
+// the planned cycles of each read are parsed, to cross-check RunInfo.xml.
+
+use std::{fs, path::Path};
+
+use thiserror::Error;
+
+use super::parser;
+
+pub const RUN_PARAMETERS: &str = "RunParameters.xml";
+
+#[derive(Error, Debug)]
+pub enum RunParametersError {
+    #[error("Unable to read RunParameters.xml")]
+    IoError(#[from] std::io::Error),
+    #[error("RunParameters.xml <{element}> has invalid cycles: {value:?}")]
+    InvalidCycles { element: String, value: String },
+    #[error("RunParameters.xml lists no planned reads")]
+    MissingPlannedReads,
+}
+
+/// Planned cycles per read, in run order: R1, I1, I2, R2
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunParameters {
+    planned_cycles: Vec<u16>,
+}
+
+/// Element or `ReadName` of each read, as the instruments name them, in run order
+const PLANNED_READS: [(&str, &str); 4] = [
+    ("Read1", "Read1NumberOfCycles"),
+    ("Index1", "IndexRead1NumberOfCycles"),
+    ("Index2", "IndexRead2NumberOfCycles"),
+    ("Read2", "Read2NumberOfCycles"),
+];
+
+impl RunParameters {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, RunParametersError> {
+        RunParameters::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Planned reads are found in whichever form the instrument writes them:
+    /// `<PlannedReads>` (NovaSeq X, NextSeq 2000), `<Read1NumberOfCycles>` and
+    /// friends (NovaSeq 6000), or `<RunInfoRead>` (MiSeq). Reads planned with
+    /// zero cycles were not sequenced, and are left out.
+    pub fn parse(input: &str) -> Result<Self, RunParametersError> {
+        let mut planned_cycles = Vec::new();
+        let planned_reads = parser::elements(input, "Read")
+            .into_iter()
+            .filter_map(|read| Some((read.attr("ReadName")?, read.attr("Cycles")?)))
+            .collect::<Vec<(&str, &str)>>();
+        if !planned_reads.is_empty() {
+            for (name, _) in PLANNED_READS.iter() {
+                if let Some((element, value)) = planned_reads.iter().find(|(n, _)| n == name) {
+                    planned_cycles.push(parse_cycles(element, value)?);
+                }
+            }
+        } else if parser::elements(input, "RunInfoRead").is_empty() {
+            for (_, element) in PLANNED_READS.iter() {
+                if let Some(value) = parser::elements(input, element)
+                    .into_iter()
+                    .find_map(|e| e.text)
+                {
+                    planned_cycles.push(parse_cycles(element, value)?);
+                }
+            }
+        } else {
+            let mut reads = parser::elements(input, "RunInfoRead")
+                .iter()
+                .map(|read| {
+                    let number = read.attr("Number").unwrap_or_default();
+                    let number =
+                        number
+                            .parse::<usize>()
+                            .map_err(|_| RunParametersError::InvalidCycles {
+                                element: "RunInfoRead".to_string(),
+                                value: number.to_string(),
+                            })?;
+                    let cycles = read.attr("NumCycles").unwrap_or_default();
+                    Ok((number, parse_cycles("RunInfoRead", cycles)?))
+                })
+                .collect::<Result<Vec<(usize, u16)>, RunParametersError>>()?;
+            reads.sort_by_key(|(number, _)| *number);
+            planned_cycles.extend(reads.into_iter().map(|(_, cycles)| cycles));
+        }
+        planned_cycles.retain(|cycles| *cycles > 0);
+        if planned_cycles.is_empty() {
+            return Err(RunParametersError::MissingPlannedReads);
+        }
+        Ok(RunParameters { planned_cycles })
+    }
+
+    /// Cycles planned for each read, ready for
+    /// [verify_read_structure_consistency](crate::assemble::layout::verify_read_structure_consistency)
+    pub fn planned_cycles(&self) -> &[u16] {
+        &self.planned_cycles
+    }
+}
+
+fn parse_cycles(element: &str, value: &str) -> Result<u16, RunParametersError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| RunParametersError::InvalidCycles {
+            element: element.to_string(),
+            value: value.to_string(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_planned_reads() {
+        let novaseq_x = r#"<RunParameters>
+  <PlannedReads>
+    <Read ReadName="Read1" Cycles="151" />
+    <Read ReadName="Read2" Cycles="151" />
+    <Read ReadName="Index1" Cycles="10" />
+    <Read ReadName="Index2" Cycles="10" />
+  </PlannedReads>
+</RunParameters>"#;
+        assert_eq!(
+            RunParameters::parse(novaseq_x).unwrap().planned_cycles(),
+            &[151, 10, 10, 151]
+        );
+    }
+
+    #[test]
+    fn parses_novaseq_6000_cycle_elements() {
+        let novaseq = "<RunParameters>\n\
+            <Read1NumberOfCycles>151</Read1NumberOfCycles>\n\
+            <Read2NumberOfCycles>151</Read2NumberOfCycles>\n\
+            <IndexRead1NumberOfCycles>8</IndexRead1NumberOfCycles>\n\
+            <IndexRead2NumberOfCycles>0</IndexRead2NumberOfCycles>\n\
+            </RunParameters>";
+        assert_eq!(
+            RunParameters::parse(novaseq).unwrap().planned_cycles(),
+            &[151, 8, 151]
+        );
+    }
+
+    #[test]
+    fn parses_miseq_run_info_reads() {
+        let miseq = r#"<RunParameters>
+  <Reads>
+    <RunInfoRead Number="2" NumCycles="8" IsIndexedRead="Y" />
+    <RunInfoRead Number="1" NumCycles="251" IsIndexedRead="N" />
+  </Reads>
+</RunParameters>"#;
+        assert_eq!(
+            RunParameters::parse(miseq).unwrap().planned_cycles(),
+            &[251, 8]
+        );
+    }
+
+    #[test]
+    fn bad_or_missing_cycles_are_errors() {
+        assert!(matches!(
+            RunParameters::parse("<Read1NumberOfCycles>lots</Read1NumberOfCycles>"),
+            Err(RunParametersError::InvalidCycles { .. })
+        ));
+        assert!(matches!(
+            RunParameters::parse("<RunParameters></RunParameters>"),
+            Err(RunParametersError::MissingPlannedReads)
+        ));
+    }
+}
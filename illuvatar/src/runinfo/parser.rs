@@ -0,0 +1,80 @@
+use nom::{
+    bytes::complete::{tag, take_till, take_until, take_while1},
+    character::complete::{multispace0, multispace1},
+    multi::many0,
+    sequence::{delimited, pair, preceded, separated_pair},
+    IResult,
+};
+
+/// A single XML element, with its attributes and any text before its first child
+///
+/// RunInfo.xml is shallow and regular enough that nothing needs the tree, so
+/// elements are found by name wherever they occur.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Element<'a> {
+    pub attrs: Vec<(&'a str, &'a str)>,
+    /// `None` for self-closing elements
+    pub text: Option<&'a str>,
+}
+
+impl<'a> Element<'a> {
+    pub fn attr(&self, name: &str) -> Option<&'a str> {
+        self.attrs.iter().find(|(n, _)| *n == name).map(|(_, v)| *v)
+    }
+}
+
+fn name(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == ':')(input)
+}
+
+/// `Name="value"`
+fn attribute(input: &str) -> IResult<&str, (&str, &str)> {
+    separated_pair(
+        name,
+        delimited(multispace0, tag("="), multispace0),
+        delimited(tag("\""), take_until("\""), tag("\"")),
+    )(input)
+}
+
+/// Everything after `<Name` up to and including the text content, if any
+fn element_rest(input: &str) -> IResult<&str, Element> {
+    let (i, attrs) = many0(preceded(multispace1, attribute))(input)?;
+    let (i, _) = multispace0(i)?;
+    if let Ok((i, _)) = tag::<&str, &str, nom::error::Error<&str>>("/>")(i) {
+        return Ok((i, Element { attrs, text: None }));
+    }
+    let (i, (_, text)) = pair(tag(">"), take_till(|c| c == '<'))(i)?;
+    Ok((
+        i,
+        Element {
+            attrs,
+            text: Some(text.trim()),
+        },
+    ))
+}
+
+/// Every `<element_name ...>` in `input`, in document order
+///
+/// Elements that fail to parse, e.g. a tag cut off by a truncated file, are skipped.
+pub(crate) fn elements<'a>(input: &'a str, element_name: &str) -> Vec<Element<'a>> {
+    let open = format!("<{element_name}");
+    let mut found = Vec::new();
+    let mut rest = input;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        // `<Read` must not match `<Reads>`
+        if rest.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/') {
+            if let Ok((i, element)) = element_rest(rest) {
+                found.push(element);
+                rest = i;
+            }
+        }
+    }
+    found
+}
+
+/// `1_1101` into lane 1, tile 1101
+pub(crate) fn lane_tile(input: &str) -> Option<(u8, u32)> {
+    let (lane, tile) = input.trim().split_once('_')?;
+    Some((lane.parse().ok()?, tile.parse().ok()?))
+}
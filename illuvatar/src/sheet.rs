@@ -0,0 +1,246 @@
+// Parts of the sample sheet the samplesheet crate doesn't keep, read straight
+// from the file: the [Reads] section, OverrideCycles, and extra data columns.
+
+use std::{fs, path::Path};
+
+use fxhash::FxHashMap;
+use samplesheet::OverrideCycle;
+use thiserror::Error;
+
+use crate::assemble::layout::RunRead;
+
+#[derive(Error, Debug)]
+pub enum SheetError {
+    #[error("Unable to read the sample sheet")]
+    IoError(#[from] std::io::Error),
+    #[error("[Reads] has an invalid {key}: {value:?}")]
+    InvalidReads { key: String, value: String },
+    #[error("Invalid OverrideCycles {0:?}, expected e.g. Y151;I8;I8;Y151")]
+    InvalidOverrideCycles(String),
+}
+
+/// A sample sheet as plain text, sections looked up by name
+#[derive(Debug, Clone, Default)]
+pub struct RawSheet {
+    contents: String,
+}
+
+impl RawSheet {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, SheetError> {
+        Ok(RawSheet::parse(&fs::read_to_string(path)?))
+    }
+
+    pub fn parse(contents: &str) -> Self {
+        RawSheet {
+            contents: contents.to_string(),
+        }
+    }
+
+    /// Lines of the first section whose name ends with `suffix`, e.g. `Data` for `[BCLConvert_Data]`
+    fn section(&self, suffix: &str) -> Option<impl Iterator<Item = &str>> {
+        let mut lines = self.contents.lines().map(str::trim);
+        lines.by_ref().find(|line| {
+            line.starts_with('[')
+                && line
+                    .trim_end_matches(',')
+                    .strip_suffix(']')
+                    .is_some_and(|name| name.ends_with(suffix))
+        })?;
+        Some(lines.take_while(|line| !line.starts_with('[')))
+    }
+
+    /// `key,value` rows of the first section whose name ends with `suffix`
+    fn settings(&self, suffix: &str) -> Vec<(&str, &str)> {
+        self.section(suffix).map_or_else(Vec::new, |lines| {
+            lines
+                .filter_map(|line| {
+                    let mut fields = line.split(',').map(str::trim);
+                    let key = fields.next().filter(|k| !k.is_empty())?;
+                    Some((key, fields.next().unwrap_or_default()))
+                })
+                .collect()
+        })
+    }
+
+    /// Reads the [Reads] section asks for, in run order: R1, I1, I2, R2
+    ///
+    /// Only v2 sheets name their reads, so `None` for v1 sheets and sheets without the section.
+    pub fn reads(&self) -> Result<Option<Vec<RunRead>>, SheetError> {
+        let mut cycles = [None; 4];
+        for (key, value) in self.settings("Reads") {
+            let slot = match key {
+                "Read1Cycles" => 0,
+                "Index1Cycles" => 1,
+                "Index2Cycles" => 2,
+                "Read2Cycles" => 3,
+                _ => continue,
+            };
+            cycles[slot] = Some(value.parse::<u16>().map_err(|_| SheetError::InvalidReads {
+                key: key.to_string(),
+                value: value.to_string(),
+            })?);
+        }
+        if cycles[0].is_none() {
+            return Ok(None);
+        }
+        Ok(Some(
+            cycles
+                .iter()
+                .enumerate()
+                .filter_map(|(slot, cycles)| {
+                    cycles.map(|cycles| RunRead {
+                        cycles,
+                        is_index: matches!(slot, 1 | 2),
+                    })
+                })
+                .collect(),
+        ))
+    }
+
+    /// OverrideCycles from the settings section, one list of segments per read
+    pub fn override_cycles(&self) -> Result<Option<Vec<Vec<OverrideCycle>>>, SheetError> {
+        let Some((_, value)) = self
+            .settings("Settings")
+            .into_iter()
+            .find(|(key, _)| *key == "OverrideCycles")
+        else {
+            return Ok(None);
+        };
+        value
+            .split(';')
+            .map(|read| parse_override_read(read.trim()))
+            .collect::<Option<Vec<Vec<OverrideCycle>>>>()
+            .map(Some)
+            .ok_or_else(|| SheetError::InvalidOverrideCycles(value.to_string()))
+    }
+
+    /// Map each sample to its value of `column` in the data section (`[Data]` or `[BCLConvert_Data]`)
+    ///
+    /// Sheets without the column, and samples with an empty value, are left out.
+    pub fn data_column(&self, column: &str) -> FxHashMap<String, String> {
+        let mut values = FxHashMap::default();
+        let Some(mut lines) = self.section("Data") else {
+            return values;
+        };
+        let Some(columns) = lines.next() else {
+            return values;
+        };
+        let columns = columns.split(',').map(str::trim).collect::<Vec<&str>>();
+        let sample = columns.iter().position(|c| *c == "Sample_ID");
+        let wanted = columns.iter().position(|c| *c == column);
+        let (Some(sample), Some(wanted)) = (sample, wanted) else {
+            return values;
+        };
+        for row in lines {
+            let fields = row.split(',').map(str::trim).collect::<Vec<&str>>();
+            if let (Some(sample_id), Some(value)) = (fields.get(sample), fields.get(wanted)) {
+                if !sample_id.is_empty() && !value.is_empty() {
+                    values.insert(sample_id.to_string(), value.to_string());
+                }
+            }
+        }
+        values
+    }
+}
+
+/// `N1Y150` is one skipped cycle then 150 template cycles
+fn parse_override_read(read: &str) -> Option<Vec<OverrideCycle>> {
+    let mut segments = Vec::new();
+    let mut rest = read;
+    while let Some(kind) = rest.chars().next() {
+        if !kind.is_ascii_alphabetic() {
+            return None;
+        }
+        let digits = rest[1..]
+            .find(|c: char| !c.is_ascii_digit())
+            .map_or(rest.len(), |end| end + 1);
+        let n = &rest[1..digits];
+        segments.push(
+            match kind.to_ascii_uppercase() {
+                'Y' => n.parse().map(OverrideCycle::Y),
+                'I' => n.parse().map(OverrideCycle::I),
+                'U' => n.parse().map(OverrideCycle::U),
+                'N' => n.parse().map(OverrideCycle::N),
+                _ => return None,
+            }
+            .ok()?,
+        );
+        rest = &rest[digits..];
+    }
+    (!segments.is_empty()).then_some(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = "[Header],,\n\
+        FileFormatVersion,2,\n\
+        [Reads],,\n\
+        Read1Cycles,151,\n\
+        Read2Cycles,151,\n\
+        Index1Cycles,10,\n\
+        [BCLConvert_Settings],,\n\
+        OverrideCycles,U8Y143;I10;Y151,\n\
+        [BCLConvert_Data],,\n\
+        Lane,Sample_ID,Index\n\
+        1,S1,ACGTACGTAC\n";
+
+    #[test]
+    fn reads_are_in_run_order() {
+        let reads = RawSheet::parse(SHEET).reads().unwrap().unwrap();
+        assert_eq!(
+            reads,
+            vec![
+                RunRead {
+                    cycles: 151,
+                    is_index: false
+                },
+                RunRead {
+                    cycles: 10,
+                    is_index: true
+                },
+                RunRead {
+                    cycles: 151,
+                    is_index: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn override_cycles_come_from_the_settings() {
+        let overrides = RawSheet::parse(SHEET).override_cycles().unwrap().unwrap();
+        assert_eq!(
+            overrides,
+            vec![
+                vec![OverrideCycle::U(8), OverrideCycle::Y(143)],
+                vec![OverrideCycle::I(10)],
+                vec![OverrideCycle::Y(151)],
+            ]
+        );
+        assert!(RawSheet::parse("[Settings]\nOverrideCycles,Y151;X8\n")
+            .override_cycles()
+            .is_err());
+    }
+
+    #[test]
+    fn data_columns_come_from_the_data_section() {
+        let sheet = RawSheet::parse(
+            "[Header],,\n\
+             FileFormatVersion,2,\n\
+             [BCLConvert_Data],,\n\
+             Lane,Sample_ID,Library_ID\n\
+             1,S1,LIB1\n\
+             1,S2,\n\
+             [Cloud_Data],,\n\
+             Sample_ID,ProjectName,LibraryName\n\
+             S1,P1,L1\n",
+        );
+
+        let libraries = sheet.data_column("Library_ID");
+        assert_eq!(libraries.len(), 1);
+        assert_eq!(libraries["S1"], "LIB1");
+        assert!(sheet.data_column("LibraryName").is_empty());
+    }
+}